@@ -0,0 +1,243 @@
+//! An in-process cache of page data shared by all requests. Pages are kept for their TTL and then served stale
+//! for a while longer, while a fresh copy is fetched in the background.
+//!
+//! The cache holds `HtmlData` rather than the rendered output, so the same entry is used for HTML and JSON.
+//! Lambda freezes the process between invocations, so a background refresh may not complete until the next request arrives
+//! or may never run at all if the container is dropped. A refresh that takes longer than `REFRESH_TIMEOUT` is assumed
+//! to be lost and the page is handed out as `Stale` again for another caller to refresh.
+
+use crate::html::html_data::HtmlData;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Max number of pages in the cache. The oldest page is dropped to make room for a new one.
+const MAX_ENTRIES: usize = 500;
+/// For how long a page can be served after its TTL expired while it's being refreshed
+const MAX_STALE: Duration = Duration::from_secs(600);
+/// For how long other callers wait for a background refresh of a stale page before trying to refresh it themselves
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the cache lookup went. It goes into the logs and `X-Cache` response header.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum CacheStatus {
+    /// The page was in the cache and within its TTL
+    Hit,
+    /// The page was in the cache past its TTL and should be refreshed by the caller
+    Stale,
+    /// The page is either not in the cache or too old to be served
+    Miss,
+}
+
+impl CacheStatus {
+    /// Returns the value for `X-Cache` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Miss => "MISS",
+        }
+    }
+}
+
+/// A cached page with the time it was fetched at
+struct CacheEntry {
+    html_data: Arc<HtmlData>,
+    fetched: Instant,
+    /// Set while a background refresh is running to avoid refreshing the same page more than once
+    refresh_started: Option<Instant>,
+}
+
+impl CacheEntry {
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.html_data.ttl as u64)
+    }
+}
+
+/// A bounded cache of page data keyed by the route and the query. See the module docs for details.
+#[derive(Default)]
+pub(crate) struct PageCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl PageCache {
+    /// Returns the cache key for the request with a trailing `/` removed from the path and
+    /// leading and trailing spaces removed from the query, e.g. `/rimutaka/` and `/rimutaka` are the same page.
+    pub fn key(url_path: &str, url_query: &str) -> String {
+        let url_path = url_path.trim().trim_end_matches("/");
+        [url_path, "?", url_query.trim()].concat()
+    }
+
+    /// Returns the page, if any, and how fresh it is. A `Stale` page is marked as being refreshed, so only the first
+    /// caller gets `Stale` and the others get `Hit` until it is refreshed or `REFRESH_TIMEOUT` runs out.
+    pub fn get(&self, key: &str) -> (Option<Arc<HtmlData>>, CacheStatus) {
+        let mut entries = self.entries.lock().expect("Poisoned page cache lock");
+
+        let entry = match entries.get_mut(key) {
+            Some(v) => v,
+            None => return (None, CacheStatus::Miss),
+        };
+
+        let age = entry.fetched.elapsed();
+        let status = if age < entry.ttl() {
+            CacheStatus::Hit
+        } else if age >= entry.ttl() + MAX_STALE {
+            CacheStatus::Miss
+        } else if matches!(entry.refresh_started, Some(v) if v.elapsed() < REFRESH_TIMEOUT) {
+            CacheStatus::Hit
+        } else {
+            entry.refresh_started = Some(Instant::now());
+            CacheStatus::Stale
+        };
+
+        if status == CacheStatus::Miss {
+            entries.remove(key);
+            return (None, status);
+        }
+
+        (Some(entry.html_data.clone()), status)
+    }
+
+    /// Adds or replaces the page if it can be cached. Only successful pages with a TTL are cached.
+    /// A cached copy of a page that can no longer be cached is dropped, e.g. if the dev was deleted from ES.
+    pub fn insert(&self, key: String, html_data: Arc<HtmlData>) {
+        let mut entries = self.entries.lock().expect("Poisoned page cache lock");
+
+        if html_data.http_resp_code != 200 || html_data.ttl == 0 {
+            entries.remove(&key);
+            return;
+        }
+
+        // drop the oldest page if there is no more room
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.fetched)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                info!("Cache full, dropping {}", oldest);
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            key,
+            CacheEntry {
+                html_data,
+                fetched: Instant::now(),
+                refresh_started: None,
+            },
+        );
+    }
+
+    /// Lets the next caller try to refresh the stale page again, e.g. after an ES error.
+    pub fn refresh_failed(&self, key: &str) {
+        let mut entries = self.entries.lock().expect("Poisoned page cache lock");
+        if let Some(entry) = entries.get_mut(key) {
+            entry.refresh_started = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A page that can be cached for the TTL
+    fn page(ttl: u32) -> Arc<HtmlData> {
+        Arc::new(HtmlData {
+            ttl,
+            http_resp_code: 200,
+            ..HtmlData::new(String::new())
+        })
+    }
+
+    /// Moves the fetch and refresh times of the page back as if the time has passed.
+    fn wait(cache: &PageCache, key: &str, duration: Duration) {
+        let mut entries = cache.entries.lock().unwrap();
+        let entry = entries.get_mut(key).unwrap();
+        entry.fetched = entry.fetched.checked_sub(duration).unwrap();
+        entry.refresh_started = entry
+            .refresh_started
+            .map(|v| v.checked_sub(duration).unwrap());
+    }
+
+    #[test]
+    fn fresh() {
+        let cache = PageCache::default();
+        assert_eq!(PageCache::key("/rimutaka/", " rust "), "/rimutaka?rust");
+        assert_eq!(cache.get("/rimutaka?").1, CacheStatus::Miss);
+
+        let html_data = page(600);
+        cache.insert("/rimutaka?".to_owned(), html_data.clone());
+        let (cached, status) = cache.get("/rimutaka?");
+        assert_eq!(status, CacheStatus::Hit);
+        assert!(Arc::ptr_eq(&cached.unwrap(), &html_data));
+
+        // error pages are not cached and replace the cached copy
+        let not_found = Arc::new(HtmlData {
+            http_resp_code: 404,
+            ..HtmlData::new(String::new())
+        });
+        cache.insert("/rimutaka?".to_owned(), not_found);
+        assert_eq!(cache.get("/rimutaka?").1, CacheStatus::Miss);
+    }
+
+    #[test]
+    fn stale_refresh() {
+        let cache = PageCache::default();
+        cache.insert("/?rust".to_owned(), page(60));
+        wait(&cache, "/?rust", Duration::from_secs(61));
+
+        // only the first caller refreshes the page
+        assert_eq!(cache.get("/?rust").1, CacheStatus::Stale);
+        assert_eq!(cache.get("/?rust").1, CacheStatus::Hit);
+
+        // a failed refresh can be retried straight away
+        cache.refresh_failed("/?rust");
+        assert_eq!(cache.get("/?rust").1, CacheStatus::Stale);
+
+        // a refresh that never finished, e.g. in a frozen Lambda container, is retried after the timeout
+        wait(&cache, "/?rust", REFRESH_TIMEOUT);
+        assert_eq!(cache.get("/?rust").1, CacheStatus::Stale);
+        assert_eq!(cache.get("/?rust").1, CacheStatus::Hit);
+
+        // a fresh copy replaces the stale one
+        cache.insert("/?rust".to_owned(), page(60));
+        assert_eq!(cache.get("/?rust").1, CacheStatus::Hit);
+        wait(&cache, "/?rust", Duration::from_secs(61));
+        assert_eq!(cache.get("/?rust").1, CacheStatus::Stale);
+    }
+
+    #[test]
+    fn expiry() {
+        let cache = PageCache::default();
+        cache.insert("/?rust".to_owned(), page(60));
+        wait(&cache, "/?rust", Duration::from_secs(60) + MAX_STALE);
+
+        let (cached, status) = cache.get("/?rust");
+        assert!(cached.is_none());
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn eviction() {
+        let cache = PageCache::default();
+        cache.insert("/?oldest".to_owned(), page(600));
+        wait(&cache, "/?oldest", Duration::from_secs(1));
+        for i in 1..MAX_ENTRIES {
+            cache.insert(["/?", &i.to_string()].concat(), page(600));
+        }
+
+        // replacing a page does not drop any other pages
+        cache.insert("/?1".to_owned(), page(600));
+        assert_eq!(cache.get("/?oldest").1, CacheStatus::Hit);
+
+        cache.insert("/?newest".to_owned(), page(600));
+        assert_eq!(cache.entries.lock().unwrap().len(), MAX_ENTRIES);
+        assert_eq!(cache.get("/?oldest").1, CacheStatus::Miss);
+        assert_eq!(cache.get("/?newest").1, CacheStatus::Hit);
+    }
+}
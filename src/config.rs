@@ -1,3 +1,6 @@
+use crate::elastic::backend::{self, SearchBackend};
+use crate::error::StmError;
+use crate::rate_limit::{self, RateLimitStore};
 use regex::Regex;
 
 /// Add the name of the ElasticSearch index to that env var
@@ -8,18 +11,109 @@ pub const ES_REPO_IDX_ENV: &str = "STM_HTML_ES_REPO_IDX";
 pub const ES_STATS_IDX_ENV: &str = "STM_HTML_ES_STATS_IDX";
 /// Add the absolute ElasticSearch URL to that env var
 pub const ES_URL_ENV: &str = "STM_HTML_ES_URL";
+/// ES auth mode: `aws`, `basic`, `api_key` or `none`. Defaults to `aws` for AWS hosts and `none` for anything else.
+pub const ES_AUTH_ENV: &str = "STM_HTML_ES_AUTH";
+/// ES user name for `basic` auth
+pub const ES_USER_ENV: &str = "STM_HTML_ES_USER";
+/// ES password for `basic` auth
+pub const ES_PASSWORD_ENV: &str = "STM_HTML_ES_PASSWORD";
+/// Base64-encoded `id:api_key` for `api_key` auth, same as `encoded` value returned by ES when the key is created
+pub const ES_API_KEY_ENV: &str = "STM_HTML_ES_API_KEY";
+
+/// The public URL of the site for absolute URLs in sitemaps and robots.txt. Defaults to `https://stackmuncher.com`.
+pub const SITE_URL_ENV: &str = "STM_HTML_SITE_URL";
+
+/// Set to `true` to send CSP as `Content-Security-Policy-Report-Only` header, e.g. while testing a new policy
+pub const CSP_REPORT_ONLY_ENV: &str = "STM_HTML_CSP_REPORT_ONLY";
+/// An optional URL for browsers to send CSP violation reports to
+pub const CSP_REPORT_URI_ENV: &str = "STM_HTML_CSP_REPORT_URI";
+/// HSTS max-age in seconds. Defaults to 1 year. Set to 0 to omit the header, e.g. for plain HTTP on localhost.
+pub const HSTS_MAX_AGE_ENV: &str = "STM_HTML_HSTS_MAX_AGE";
+
+/// Max requests per minute per client for search, stats and related keywords pages. Defaults to 30, 0 for no limit.
+pub const RATE_LIMIT_SEARCH_ENV: &str = "STM_HTML_RATE_LIMIT_SEARCH";
+/// Max requests per minute per client for dev profile and repo pages. Defaults to 60, 0 for no limit.
+pub const RATE_LIMIT_PROFILE_ENV: &str = "STM_HTML_RATE_LIMIT_PROFILE";
+/// Comma-separated parts of user agent names of crawlers that are never throttled, e.g. `googlebot,bingbot`
+pub const RATE_LIMIT_ALLOWED_BOTS_ENV: &str = "STM_HTML_RATE_LIMIT_ALLOWED_BOTS";
+/// Set to `true` to identify clients by `x-forwarded-for` header behind CloudFront or another proxy that overwrites it.
+/// Defaults to `false` because clients can send any XFF value if they can reach the app directly.
+pub const RATE_LIMIT_TRUST_XFF_ENV: &str = "STM_HTML_RATE_LIMIT_TRUST_XFF";
+/// Where the rate limiter keeps its state: `memory` (default) or `file:///path/to/dir`
+pub const RATE_LIMIT_STORE_ENV: &str = "STM_HTML_RATE_LIMIT_STORE";
+
+/// The default for `SITE_URL_ENV`
+const DEFAULT_SITE_URL: &str = "https://stackmuncher.com";
+/// The default for `HSTS_MAX_AGE_ENV`
+const DEFAULT_HSTS_MAX_AGE: u32 = 31536000;
+/// The default for `RATE_LIMIT_SEARCH_ENV`
+const DEFAULT_RATE_LIMIT_SEARCH: u32 = 30;
+/// The default for `RATE_LIMIT_PROFILE_ENV`
+const DEFAULT_RATE_LIMIT_PROFILE: u32 = 60;
+/// The default for `RATE_LIMIT_ALLOWED_BOTS_ENV`
+const DEFAULT_RATE_LIMIT_ALLOWED_BOTS: &str = "googlebot,bingbot,duckduckbot,applebot,yandexbot";
+
+/// How requests to ES are authenticated
+pub enum EsAuth {
+    /// AWS SigV4 with the default AWS credentials. The region is taken from the host name.
+    AwsSigV4,
+    /// HTTP basic auth
+    Basic { user: String, password: String },
+    /// `Authorization: ApiKey ...` header with a base64-encoded `id:api_key`
+    ApiKey(String),
+    /// No auth at all, e.g. a local docker container
+    None,
+}
+
+/// ES cluster URL with the backend for sending requests to it with the configured auth
+pub struct EsConnection {
+    /// Absolute ElasticSearch URL, e.g. `http://localhost:9200` or `file:///path/to/fixtures`
+    pub url: String,
+    /// A pooled HTTP client or fixtures, shared by all requests
+    pub(crate) backend: Box<dyn SearchBackend>,
+}
 
 pub struct Config {
-    /// Absolute ElasticSearch URL
-    pub es_url: String,
+    /// ElasticSearch or OpenSearch cluster
+    pub es: EsConnection,
     /// Name of `dev` index
     pub dev_idx: String,
     /// Name of `repo` index
     pub repo_idx: String,
     /// Name of `stats` index
     pub stats_idx: String,
+    /// The public URL of the site without a trailing `/`, e.g. `https://stackmuncher.com`
+    pub site_url: String,
     /// No-SQL field value validation regex - the value would be invalid if it's a match
     pub no_sql_string_invalidation_regex: Regex,
+    /// Security headers added to every response
+    pub security_headers: SecurityHeadersConfig,
+    /// Per-client request limits
+    pub rate_limit: RateLimitConfig,
+}
+
+/// Settings for security headers that may differ between environments. See `security` module for the headers.
+pub struct SecurityHeadersConfig {
+    /// CSP violations are reported, but not blocked
+    pub csp_report_only: bool,
+    /// Where to send CSP violation reports, if anywhere
+    pub csp_report_uri: Option<String>,
+    /// HSTS max-age in seconds, 0 for no HSTS header
+    pub hsts_max_age: u32,
+}
+
+/// Request budgets per client with the store for keeping track of them. See `rate_limit` module for details.
+pub struct RateLimitConfig {
+    /// Max requests per minute for search, stats and related keywords pages, 0 for no limit
+    pub search_per_minute: u32,
+    /// Max requests per minute for dev profile and repo pages, 0 for no limit
+    pub profile_per_minute: u32,
+    /// Lower-case parts of user agent names that are never throttled
+    pub allowed_bots: Vec<String>,
+    /// Take the client IP from `x-forwarded-for` header added by CloudFront rather than the source IP
+    pub trust_forwarded_for: bool,
+    /// In-memory or a local stand-in for a shared store, shared by all requests
+    pub(crate) store: Box<dyn RateLimitStore>,
 }
 
 /// A regex formula to check for unsafe values to insert into another regex string.
@@ -27,39 +121,193 @@ pub struct Config {
 pub(crate) const SAFE_REGEX_SUBSTRING: &str = r#"[^#\-\._0-9a-zA-Z]"#;
 
 impl Config {
-    pub fn new() -> Self {
-        Config {
-            es_url: std::env::var(ES_URL_ENV)
-                .expect(&format!(
-                    "Missing {} env var with ElasticSearch URL",
-                    ES_URL_ENV
-                ))
-                .trim()
+    /// Reads the config from env vars. Returns an error if any of them is missing.
+    pub fn new() -> Result<Self, StmError> {
+        Ok(Config {
+            es: es_connection()?,
+            dev_idx: get_env_var(ES_DEV_IDX_ENV, "ES DEV index name")?,
+            repo_idx: get_env_var(ES_REPO_IDX_ENV, "ES REPO index name")?,
+            stats_idx: get_env_var(ES_STATS_IDX_ENV, "ES STATS index name")?,
+            site_url: get_optional_env_var(SITE_URL_ENV)
+                .unwrap_or_else(|| DEFAULT_SITE_URL.to_owned())
                 .trim_end_matches("/")
                 .to_string(),
-            dev_idx: std::env::var(ES_DEV_IDX_ENV)
-                .expect(&format!(
-                    "Missing {} env var with ES DEV index name",
-                    ES_DEV_IDX_ENV
-                ))
-                .trim()
-                .to_string(),
-            repo_idx: std::env::var(ES_REPO_IDX_ENV)
-                .expect(&format!(
-                    "Missing {} env var with ES REPO index name",
-                    ES_REPO_IDX_ENV
-                ))
-                .trim()
-                .to_string(),
-            stats_idx: std::env::var(ES_STATS_IDX_ENV)
-                .expect(&format!(
-                    "Missing {} env var with ES STATS index name",
-                    ES_STATS_IDX_ENV
-                ))
-                .trim()
-                .to_string(),
             no_sql_string_invalidation_regex: Regex::new(r#"[^#\-\._0-9a-zA-Z]"#)
                 .expect("Failed to compile no_sql_string_value_regex"),
+            security_headers: security_headers()?,
+            rate_limit: rate_limit()?,
+        })
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// A config for route tests with `dev` and `stats` docs from `samples/` and the default settings for everything else.
+    pub(crate) fn with_samples() -> Self {
+        Config {
+            es: EsConnection {
+                url: [backend::FIXTURE_URL_SCHEME, "samples"].concat(),
+                backend: backend::samples(),
+            },
+            dev_idx: "dev".to_owned(),
+            repo_idx: "repo".to_owned(),
+            stats_idx: "stats".to_owned(),
+            site_url: DEFAULT_SITE_URL.to_owned(),
+            no_sql_string_invalidation_regex: Regex::new(r#"[^#\-\._0-9a-zA-Z]"#)
+                .expect("Failed to compile no_sql_string_value_regex"),
+            security_headers: SecurityHeadersConfig {
+                csp_report_only: false,
+                csp_report_uri: None,
+                hsts_max_age: DEFAULT_HSTS_MAX_AGE,
+            },
+            rate_limit: RateLimitConfig {
+                search_per_minute: 0,
+                profile_per_minute: 0,
+                allowed_bots: Vec::new(),
+                trust_forwarded_for: false,
+                store: rate_limit::store_for_url(rate_limit::MEMORY_STORE)
+                    .expect("Failed to create the memory store"),
+            },
         }
     }
 }
+
+/// Reads ES URL and auth mode with the credentials for the mode and creates the backend for them.
+fn es_connection() -> Result<EsConnection, StmError> {
+    let url = get_env_var(ES_URL_ENV, "ElasticSearch URL")?
+        .trim_end_matches("/")
+        .to_string();
+
+    // AWS is the default for AWS-hosted clusters to keep the config for existing deployments unchanged
+    let auth = match std::env::var(ES_AUTH_ENV) {
+        Ok(v) => v.trim().to_lowercase(),
+        Err(_) if url.contains(".amazonaws.com") => "aws".to_owned(),
+        Err(_) => "none".to_owned(),
+    };
+
+    let auth = match auth.as_str() {
+        "aws" => EsAuth::AwsSigV4,
+        "basic" => EsAuth::Basic {
+            user: get_env_var(ES_USER_ENV, "ES user name")?,
+            password: get_env_var(ES_PASSWORD_ENV, "ES password")?,
+        },
+        "api_key" => EsAuth::ApiKey(get_env_var(ES_API_KEY_ENV, "ES API key")?),
+        "none" => EsAuth::None,
+        _ => {
+            return Err(StmError::Config(format!(
+                "Invalid {} env var value: {}. Expected aws, basic, api_key or none",
+                ES_AUTH_ENV, auth
+            )));
+        }
+    };
+
+    let backend = backend::for_url(&url, auth)?;
+
+    Ok(EsConnection { url, backend })
+}
+
+/// Reads optional security header settings.
+fn security_headers() -> Result<SecurityHeadersConfig, StmError> {
+    let csp_report_only = match get_optional_env_var(CSP_REPORT_ONLY_ENV).as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(v) => {
+            return Err(StmError::Config(format!(
+                "Invalid {} env var value: {}. Expected true or false",
+                CSP_REPORT_ONLY_ENV, v
+            )));
+        }
+    };
+
+    let hsts_max_age = match get_optional_env_var(HSTS_MAX_AGE_ENV) {
+        None => DEFAULT_HSTS_MAX_AGE,
+        Some(v) => match v.parse::<u32>() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(StmError::Config(format!(
+                    "Invalid {} env var value: {}. Expected a number of seconds",
+                    HSTS_MAX_AGE_ENV, v
+                )));
+            }
+        },
+    };
+
+    Ok(SecurityHeadersConfig {
+        csp_report_only,
+        csp_report_uri: get_optional_env_var(CSP_REPORT_URI_ENV),
+        hsts_max_age,
+    })
+}
+
+/// Reads optional rate limiter settings and creates the store for them.
+fn rate_limit() -> Result<RateLimitConfig, StmError> {
+    let search_per_minute =
+        get_optional_u32_env_var(RATE_LIMIT_SEARCH_ENV)?.unwrap_or(DEFAULT_RATE_LIMIT_SEARCH);
+    let profile_per_minute =
+        get_optional_u32_env_var(RATE_LIMIT_PROFILE_ENV)?.unwrap_or(DEFAULT_RATE_LIMIT_PROFILE);
+
+    let allowed_bots = get_optional_env_var(RATE_LIMIT_ALLOWED_BOTS_ENV)
+        .unwrap_or_else(|| DEFAULT_RATE_LIMIT_ALLOWED_BOTS.to_owned())
+        .split(',')
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<String>>();
+
+    let trust_forwarded_for = match get_optional_env_var(RATE_LIMIT_TRUST_XFF_ENV).as_deref() {
+        Some("true") => true,
+        None | Some("false") => false,
+        Some(v) => {
+            return Err(StmError::Config(format!(
+                "Invalid {} env var value: {}. Expected true or false",
+                RATE_LIMIT_TRUST_XFF_ENV, v
+            )));
+        }
+    };
+
+    let store = rate_limit::store_for_url(
+        get_optional_env_var(RATE_LIMIT_STORE_ENV)
+            .as_deref()
+            .unwrap_or(rate_limit::MEMORY_STORE),
+    )?;
+
+    Ok(RateLimitConfig {
+        search_per_minute,
+        profile_per_minute,
+        allowed_bots,
+        trust_forwarded_for,
+        store,
+    })
+}
+
+/// Returns the value of the env var as a number or `None` if it is missing or empty.
+fn get_optional_u32_env_var(name: &str) -> Result<Option<u32>, StmError> {
+    match get_optional_env_var(name) {
+        None => Ok(None),
+        Some(v) => match v.parse::<u32>() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(StmError::Config(format!(
+                "Invalid {} env var value: {}. Expected a whole number",
+                name, v
+            ))),
+        },
+    }
+}
+
+/// Returns a trimmed value of the env var or `None` if it is missing or empty.
+fn get_optional_env_var(name: &str) -> Option<String> {
+    match std::env::var(name) {
+        Ok(v) if !v.trim().is_empty() => Some(v.trim().to_string()),
+        _ => None,
+    }
+}
+
+/// Returns a trimmed value of the env var or an error with the description of what is missing.
+fn get_env_var(name: &str, description: &str) -> Result<String, StmError> {
+    match std::env::var(name) {
+        Ok(v) => Ok(v.trim().to_string()),
+        Err(_) => Err(StmError::Config(format!(
+            "Missing {} env var with {}",
+            name, description
+        ))),
+    }
+}
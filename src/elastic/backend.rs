@@ -0,0 +1,41 @@
+//! ES API calls are made via a backend picked by the ES URL:
+//! * `file:///path/to/fixtures` - JSON fixtures loaded from disk, see `fixture` module
+//! * anything else - HTTP calls to ES or OpenSearch with the configured auth, see `http` module
+//!
+//! All backends take the same fully qualified endpoint URLs and return the same JSON as ES would.
+
+use super::fixture::FixtureBackend;
+use super::http::HttpBackend;
+use crate::config::EsAuth;
+use crate::error::StmError;
+use futures::future::BoxFuture;
+use serde_json::Value;
+
+/// ES URLs starting with this scheme are served from fixtures, e.g. `file:///home/me/stm-fixtures`
+pub(crate) const FIXTURE_URL_SCHEME: &str = "file://";
+
+/// An implementation of the subset of ES API used by this app: `_search`, `_msearch`, `_count` and `_doc`.
+pub(crate) trait SearchBackend: Send + Sync {
+    /// Sends the payload, if any, to the endpoint and returns the response as JSON.
+    /// `es_api_endpoint` is a fully qualified URL, e.g. `https://x.ap-southeast-2.es.amazonaws.com/my_index/_search`.
+    fn call(
+        &self,
+        es_api_endpoint: String,
+        payload: Option<String>,
+    ) -> BoxFuture<'_, Result<Value, StmError>>;
+}
+
+/// Returns the backend for the ES URL. It should be created once per process and shared by all requests.
+pub(crate) fn for_url(url: &str, auth: EsAuth) -> Result<Box<dyn SearchBackend>, StmError> {
+    if url.starts_with(FIXTURE_URL_SCHEME) {
+        Ok(Box::new(FixtureBackend::new(url)?))
+    } else {
+        Ok(Box::new(HttpBackend::new(auth)?))
+    }
+}
+
+/// Fixtures from `samples/` for route tests, see `FixtureBackend::from_samples`.
+#[cfg(test)]
+pub(crate) fn samples() -> Box<dyn SearchBackend> {
+    Box::new(FixtureBackend::from_samples())
+}
@@ -0,0 +1,640 @@
+//! An in-memory stand-in for ES that answers `_search`, `_msearch`, `_count` and `_doc` calls from JSON files.
+//! It is meant for local development and tests without an AWS account.
+//!
+//! Set `STM_HTML_ES_URL` to `file:///path/to/fixtures` and put the docs for every index into a sub-folder
+//! named after the index, e.g. `fixtures/dev/report-rimutaka.json` for `STM_HTML_ES_DEV_IDX=dev`. Every file is either
+//! * an ES search response with `hits.hits`, e.g. `samples/report-rimutaka.json`, or
+//! * a single doc, which gets the file name as its `_id`, e.g. `fixtures/stats/latest_stats.json`
+//!
+//! Only the queries, aggregations and suggesters built by this app are supported. Values are compared as
+//! case-insensitive exact matches, which is close enough to how `.keyword` fields are searched in the real index.
+//! `filter_path` and `_source` filtering are ignored, so the responses may have more members than ES would return.
+//!
+//! The files are loaded once when the backend is created. Restart the app to pick up any changes.
+
+use super::backend::{SearchBackend, FIXTURE_URL_SCHEME};
+use crate::error::StmError;
+use chrono::DateTime;
+use futures::future::BoxFuture;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tracing::{error, info, warn};
+
+/// ES default for `size`
+const DEFAULT_SIZE: usize = 10;
+/// ES default for `max_edits` in term suggester
+const MAX_SUGGESTION_EDITS: usize = 2;
+
+/// Answers ES API calls from JSON files loaded into memory.
+pub(crate) struct FixtureBackend {
+    /// Docs of every index as hits with `_id` and `_source`, keyed by the index name
+    indices: HashMap<String, Vec<Value>>,
+}
+
+impl SearchBackend for FixtureBackend {
+    fn call(
+        &self,
+        es_api_endpoint: String,
+        payload: Option<String>,
+    ) -> BoxFuture<'_, Result<Value, StmError>> {
+        Box::pin(async move { self.call_es_api(&es_api_endpoint, payload.as_deref()) })
+    }
+}
+
+impl FixtureBackend {
+    /// Loads the docs from every sub-folder of the fixtures folder, e.g. `/fixtures` for `file:///fixtures`.
+    /// Returns an error if any of the files cannot be read or parsed.
+    pub fn new(url: &str) -> Result<Self, StmError> {
+        let root = url.trim_start_matches(FIXTURE_URL_SCHEME);
+        let entries = match std::fs::read_dir(root) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Cannot read fixtures dir {}: {}", root, e);
+                return Err(StmError::Config(
+                    ["Cannot read fixtures dir ", root].concat(),
+                ));
+            }
+        };
+
+        let mut indices: HashMap<String, Vec<Value>> = HashMap::new();
+        for path in entries.filter_map(|entry| entry.ok().map(|v| v.path())) {
+            if !path.is_dir() {
+                continue;
+            }
+            let idx = path
+                .file_name()
+                .map(|v| v.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let docs = load_docs(&path)?;
+            info!("Loaded {} fixture docs for {}", docs.len(), idx);
+            indices.insert(idx, docs);
+        }
+
+        Ok(FixtureBackend { indices })
+    }
+
+    /// Serves `samples/report-rimutaka.json` as the only doc in `dev` index and `samples/latest_stats.json`
+    /// in `stats` index. All other indices are missing.
+    #[cfg(test)]
+    pub fn from_samples() -> Self {
+        let load = |file: &str| {
+            load_file(&std::path::Path::new("samples").join(file)).expect("Cannot load the sample")
+        };
+        FixtureBackend {
+            indices: vec![
+                ("dev".to_owned(), load("report-rimutaka.json")),
+                ("stats".to_owned(), load("latest_stats.json")),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    /// Returns the docs of the index. A missing index is an error, same as in ES.
+    fn docs(&self, idx: &str) -> Result<&[Value], StmError> {
+        match self.indices.get(idx) {
+            Some(v) => Ok(v),
+            None => {
+                error!("Missing fixture index: {}", idx);
+                Err(StmError::Es(["Status 404 for index ", idx].concat()))
+            }
+        }
+    }
+
+    /// Routes the call to `_doc`, `_count`, `_search` or `_msearch` handler.
+    fn call_es_api(&self, es_api_endpoint: &str, payload: Option<&str>) -> Result<Value, StmError> {
+        info!("Fixture query: {}", es_api_endpoint);
+
+        // e.g. file:///fixtures/dev/_search?filter_path=hits.total -> /fixtures/dev/_search
+        let path = es_api_endpoint.trim_start_matches(FIXTURE_URL_SCHEME);
+        let path = path.split('?').next().unwrap_or_default();
+
+        // msearch payload is NDJSON, which is not a valid JSON
+        if path.ends_with("/_msearch") {
+            return self.multi_search(payload.unwrap_or_default());
+        }
+
+        // the payload is always a query built by this app
+        let request = match payload {
+            Some(v) => serde_json::from_str::<Value>(v)?,
+            None => Value::Null,
+        };
+
+        if let Some((idx_dir, doc_id)) = path.split_once("/_doc/") {
+            let docs = self.docs(idx_name(idx_dir))?;
+            return match docs.iter().find(|doc| doc["_id"] == doc_id) {
+                Some(doc) => Ok(json!({"_id": doc_id, "found": true, "_source": doc["_source"]})),
+                None => {
+                    error!("Fixture doc not found: {}", doc_id);
+                    Err(StmError::Es(["Status 404 for doc ", doc_id].concat()))
+                }
+            };
+        }
+
+        if let Some(idx_dir) = path.strip_suffix("/_count") {
+            let docs = self.docs(idx_name(idx_dir))?;
+            let count = docs
+                .iter()
+                .filter(|doc| matches(&request["query"], &doc["_source"]))
+                .count();
+            return Ok(json!({ "count": count }));
+        }
+
+        if let Some(idx_dir) = path.strip_suffix("/_search") {
+            let docs = self.docs(idx_name(idx_dir))?;
+            return Ok(search(docs, &request));
+        }
+
+        error!("Unsupported fixture endpoint: {}", es_api_endpoint);
+        Err(StmError::Es(
+            ["Unsupported fixture endpoint: ", es_api_endpoint].concat(),
+        ))
+    }
+
+    /// Runs every search from `_msearch` NDJSON payload against its index and returns the responses in the same order.
+    /// A failed search gets an error response the same way as in ES.
+    fn multi_search(&self, payload: &str) -> Result<Value, StmError> {
+        let lines = payload
+            .lines()
+            .filter(|v| !v.trim().is_empty())
+            .collect::<Vec<&str>>();
+
+        let mut responses: Vec<Value> = Vec::new();
+        // every search is a pair of lines: {"index":"dev"} followed by the search itself
+        for pair in lines.chunks(2) {
+            let header = serde_json::from_str::<Value>(pair[0])?;
+            let request = match pair.get(1) {
+                Some(v) => serde_json::from_str::<Value>(v)?,
+                None => {
+                    error!("Missing search after msearch header: {}", pair[0]);
+                    return Err(StmError::Es("Invalid msearch payload".to_owned()));
+                }
+            };
+            let idx = header["index"].as_str().unwrap_or_default();
+
+            let response = match self.docs(idx) {
+                Ok(docs) => {
+                    let mut response = search(docs, &request);
+                    response["status"] = json!(200);
+                    response
+                }
+                Err(e) => json!({"error": {"reason": e.to_string()}, "status": 404}),
+            };
+            responses.push(response);
+        }
+
+        Ok(json!({ "responses": responses }))
+    }
+}
+
+/// The last part of the index folder path, e.g. `dev` for `/fixtures/dev`
+fn idx_name(idx_dir: &str) -> &str {
+    idx_dir.rsplit('/').next().unwrap_or_default()
+}
+
+/// Loads all docs from `*.json` files in the index folder as hits with `_id` and `_source`.
+fn load_docs(idx_dir: &std::path::Path) -> Result<Vec<Value>, StmError> {
+    let entries = match std::fs::read_dir(idx_dir) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Cannot read fixture dir {}: {}", idx_dir.display(), e);
+            return Err(StmError::Config(e.to_string()));
+        }
+    };
+
+    // sort the files by name to return the docs in the same order every time
+    let mut files = entries
+        .filter_map(|entry| entry.ok().map(|v| v.path()))
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("json"))
+        .collect::<Vec<std::path::PathBuf>>();
+    files.sort();
+
+    let mut docs: Vec<Value> = Vec::new();
+    for file in files {
+        docs.extend(load_file(&file)?);
+    }
+
+    Ok(docs)
+}
+
+/// Loads the docs from a single file: all hits if it is an ES search response or the file itself as a doc
+/// with the file name as its `_id`.
+fn load_file(file: &std::path::Path) -> Result<Vec<Value>, StmError> {
+    let contents = match std::fs::read_to_string(file) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Cannot read fixture {}: {}", file.display(), e);
+            return Err(StmError::Config(e.to_string()));
+        }
+    };
+    let contents = serde_json::from_str::<Value>(&contents)?;
+
+    match contents["hits"]["hits"].as_array() {
+        // a saved ES response
+        Some(hits) => Ok(hits.clone()),
+        // a single doc
+        None => {
+            let doc_id = file
+                .file_name()
+                .map(|v| v.to_string_lossy().to_string())
+                .unwrap_or_default();
+            Ok(vec![json!({"_id": doc_id, "_source": contents})])
+        }
+    }
+}
+
+/// Runs the search request against the docs and returns a response in the same format as ES.
+fn search(docs: &[Value], request: &Value) -> Value {
+    let mut hits = docs
+        .iter()
+        .filter(|doc| matches(&request["query"], &doc["_source"]))
+        .collect::<Vec<&Value>>();
+
+    // e.g. [{"hireable":{"order":"desc"}},{"report.timestamp":{"order":"desc"}}]
+    let sort = request["sort"]
+        .as_array()
+        .map(|sort| {
+            sort.iter()
+                .filter_map(|v| v.as_object())
+                .flat_map(|v| v.iter())
+                .map(|(field, opts)| (field.as_str(), opts["order"] == "desc"))
+                .collect::<Vec<(&str, bool)>>()
+        })
+        .unwrap_or_default();
+    hits.sort_by(|a, b| compare_sort_values(&sort_values(a, &sort), &sort_values(b, &sort), &sort));
+
+    // e.g. ["rimutaka"] for the hits after the hit with login `rimutaka` in the sort order
+    if let Some(search_after) = request["search_after"].as_array() {
+        hits.retain(|hit| {
+            compare_sort_values(&sort_values(hit, &sort), search_after, &sort) == Ordering::Greater
+        });
+    }
+
+    let from = request["from"].as_u64().unwrap_or_default() as usize;
+    let size = request["size"]
+        .as_u64()
+        .map_or(DEFAULT_SIZE, |v| v as usize);
+    // sorted hits have their sort values for `search_after`, same as in ES
+    let page = hits
+        .iter()
+        .skip(from)
+        .take(size)
+        .map(|hit| {
+            let mut hit = (*hit).clone();
+            if !sort.is_empty() {
+                hit["sort"] = Value::Array(sort_values(&hit, &sort));
+            }
+            hit
+        })
+        .collect::<Vec<Value>>();
+
+    let mut response = json!({
+        "hits": {
+            "total": {"value": hits.len(), "relation": "eq"},
+            "hits": page
+        }
+    });
+
+    if let Some(aggs) = request["aggregations"].as_object() {
+        let mut aggregations = serde_json::Map::new();
+        for (name, agg) in aggs {
+            aggregations.insert(name.clone(), terms_agg(&hits, &agg["terms"]));
+        }
+        response["aggregations"] = Value::Object(aggregations);
+    }
+
+    if let Some(suggesters) = request["suggest"].as_object() {
+        let mut suggest = serde_json::Map::new();
+        for (name, suggester) in suggesters {
+            suggest.insert(name.clone(), term_suggester(docs, suggester));
+        }
+        response["suggest"] = Value::Object(suggest);
+    }
+
+    response
+}
+
+/// Returns the first value of every sort field in the hit as ES would return it or `null` if the field is missing.
+fn sort_values(hit: &Value, sort: &[(&str, bool)]) -> Vec<Value> {
+    sort.iter()
+        .map(|(field, _)| {
+            field_values(&hit["_source"], field)
+                .into_iter()
+                .next()
+                .cloned()
+                .map_or(Value::Null, es_sort_value)
+        })
+        .collect()
+}
+
+/// Converts a doc value into the sort value ES would return for it: `1`/`0` for a boolean and epoch millis for a date.
+fn es_sort_value(value: Value) -> Value {
+    match value {
+        Value::Bool(v) => Value::from(v as u8),
+        Value::String(v) => match DateTime::parse_from_rfc3339(&v) {
+            Ok(date) => Value::from(date.timestamp_millis()),
+            Err(_) => Value::String(v),
+        },
+        v => v,
+    }
+}
+
+/// Compares the sort values of two hits as per the sort fields with their order, `true` for desc.
+fn compare_sort_values(a: &[Value], b: &[Value], sort: &[(&str, bool)]) -> Ordering {
+    for (i, (_, desc)) in sort.iter().enumerate() {
+        let a = a.get(i).filter(|v| !v.is_null());
+        let b = b.get(i).filter(|v| !v.is_null());
+        // docs with missing values go last regardless of the order, same as in ES
+        let ordering = match (a, b) {
+            (Some(a), Some(b)) if *desc => compare(b, a),
+            (Some(a), Some(b)) => compare(a, b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Returns true if the doc matches the query. A missing query matches everything.
+fn matches(query: &Value, doc: &Value) -> bool {
+    let (query_type, params) = match query.as_object().and_then(|v| v.iter().next()) {
+        Some(v) => v,
+        None => return true,
+    };
+
+    match query_type.as_str() {
+        "match_all" => true,
+        "match" | "term" | "match_phrase" => {
+            // e.g. {"report.tech.language.keyword":{"query":"rust"}}
+            let (field, value) = match params.as_object().and_then(|v| v.iter().next()) {
+                Some((field, opts)) => (
+                    field,
+                    opts.get("query")
+                        .or_else(|| opts.get("value"))
+                        .unwrap_or(opts),
+                ),
+                None => return false,
+            };
+            let value = match as_text(value) {
+                Some(v) => v,
+                None => return false,
+            };
+            field_values(doc, field)
+                .into_iter()
+                .filter_map(as_text)
+                .any(|v| {
+                    if query_type == "match_phrase" {
+                        v.contains(&value)
+                    } else {
+                        v == value
+                    }
+                })
+        }
+        "terms" => {
+            // e.g. {"login.keyword":["rimutaka","dtolnay"]}
+            let (field, values) = match params.as_object().and_then(|v| v.iter().next()) {
+                Some((field, values)) => (
+                    field,
+                    values
+                        .as_array()
+                        .map(|v| v.iter().filter_map(as_text).collect::<Vec<String>>())
+                        .unwrap_or_default(),
+                ),
+                None => return false,
+            };
+            field_values(doc, field)
+                .into_iter()
+                .filter_map(as_text)
+                .any(|v| values.contains(&v))
+        }
+        "multi_match" => {
+            let value = match as_text(&params["query"]) {
+                Some(v) => v,
+                None => return false,
+            };
+            params["fields"]
+                .as_array()
+                .map(|v| v.iter().filter_map(|v| v.as_str()).collect::<Vec<&str>>())
+                .unwrap_or_default()
+                .into_iter()
+                .flat_map(|field| field_values(doc, field))
+                .filter_map(as_text)
+                .any(|v| v == value)
+        }
+        "range" => {
+            // e.g. {"report.date_head":{"gte":"2021-01"}}
+            let (field, gte) = match params.as_object().and_then(|v| v.iter().next()) {
+                Some((field, opts)) => (field, &opts["gte"]),
+                None => return false,
+            };
+            field_values(doc, field)
+                .into_iter()
+                .any(|v| compare(v, gte) != Ordering::Less)
+        }
+        "bool" => {
+            let clauses = |name: &str| {
+                params[name]
+                    .as_array()
+                    .map(|v| v.iter().collect::<Vec<&Value>>())
+                    .unwrap_or_default()
+            };
+            let must = clauses("must");
+            let filter = clauses("filter");
+            let should = clauses("should");
+            let must_not = clauses("must_not");
+
+            // should clauses are optional if there are must or filter clauses, unless minimum_should_match says otherwise
+            let should_required = params["minimum_should_match"].as_u64().unwrap_or_default() > 0
+                || (must.is_empty() && filter.is_empty());
+
+            must.iter().chain(filter.iter()).all(|q| matches(q, doc))
+                && !must_not.iter().any(|q| matches(q, doc))
+                && (should.is_empty() || !should_required || should.iter().any(|q| matches(q, doc)))
+        }
+        _ => {
+            warn!("Unsupported fixture query: {}", query_type);
+            false
+        }
+    }
+}
+
+/// Counts docs per value of the field, e.g. `{"field":"report.tech.refs.k.keyword","size":50,"include":"(.*\\.)?tokio.*"}`
+/// Returns the buckets sorted by doc count, the most popular first.
+fn terms_agg(hits: &[&Value], terms: &Value) -> Value {
+    let field = terms["field"].as_str().unwrap_or_default();
+    let size = terms["size"].as_u64().map_or(DEFAULT_SIZE, |v| v as usize);
+    // ES include patterns must match the entire value
+    let include = terms["include"]
+        .as_str()
+        .and_then(|v| Regex::new(&["^(?:", v, ")$"].concat()).ok());
+
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for hit in hits {
+        // every doc is counted once per value
+        let keys = field_values(&hit["_source"], field)
+            .into_iter()
+            .filter_map(|v| v.as_str())
+            .filter(|v| match &include {
+                Some(rgx) => rgx.is_match(v),
+                None => true,
+            })
+            .collect::<HashSet<&str>>();
+        for key in keys {
+            *counts.entry(key.to_owned()).or_default() += 1;
+        }
+    }
+
+    let mut buckets = counts.into_iter().collect::<Vec<(String, usize)>>();
+    buckets.sort_by_key(|v| Reverse(v.1));
+    buckets.truncate(size);
+
+    json!({
+        "buckets": buckets
+            .into_iter()
+            .map(|(key, doc_count)| json!({"key": key, "doc_count": doc_count}))
+            .collect::<Vec<Value>>()
+    })
+}
+
+/// Suggests values of the field within `MAX_SUGGESTION_EDITS` of the text, e.g.
+/// `{"text":"tokoi","term":{"field":"report.tech.refs_kw.k.keyword","suggest_mode":"always","size":3}}`.
+/// The score is calculated the same way as ES does it: `1 - edits / length`.
+fn term_suggester(docs: &[Value], suggester: &Value) -> Value {
+    let text = suggester["text"]
+        .as_str()
+        .unwrap_or_default()
+        .to_lowercase();
+    let field = suggester["term"]["field"].as_str().unwrap_or_default();
+    let size = suggester["term"]["size"].as_u64().map_or(5, |v| v as usize);
+
+    // number of docs per value
+    let mut freqs: BTreeMap<String, usize> = BTreeMap::new();
+    for doc in docs {
+        let values = field_values(&doc["_source"], field)
+            .into_iter()
+            .filter_map(as_text)
+            .collect::<HashSet<String>>();
+        for value in values {
+            *freqs.entry(value).or_default() += 1;
+        }
+    }
+
+    let text_chars = text.chars().collect::<Vec<char>>();
+    let mut options = freqs
+        .into_iter()
+        .filter(|(value, _)| value != &text)
+        .filter_map(|(value, freq)| {
+            let value_chars = value.chars().collect::<Vec<char>>();
+            // ES requires the first char to match by default
+            if value_chars.first() != text_chars.first() {
+                return None;
+            }
+            let edits = edit_distance(&text_chars, &value_chars);
+            if edits > MAX_SUGGESTION_EDITS {
+                return None;
+            }
+            let score = 1.0 - edits as f64 / text_chars.len().max(value_chars.len()) as f64;
+            Some((value, score, freq))
+        })
+        .collect::<Vec<(String, f64, usize)>>();
+    options.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(Ordering::Equal)
+            .then(b.2.cmp(&a.2))
+    });
+    options.truncate(size);
+
+    json!([{
+        "text": text,
+        "offset": 0,
+        "length": text_chars.len(),
+        "options": options
+            .into_iter()
+            .map(|(text, score, freq)| json!({"text": text, "score": score, "freq": freq}))
+            .collect::<Vec<Value>>()
+    }])
+}
+
+/// Returns all values at the path, e.g. `report.tech.language.keyword`, with arrays flattened along the way.
+/// `.keyword` suffix is ignored because the fixtures have no mapping.
+fn field_values<'a>(doc: &'a Value, field: &str) -> Vec<&'a Value> {
+    // arrays are transparent in ES, e.g. `report.tech.language` is a list of all languages from all tech records
+    let flatten = |values: Vec<&'a Value>| {
+        values
+            .into_iter()
+            .flat_map(|v| match v {
+                Value::Array(arr) => arr.iter().collect::<Vec<&Value>>(),
+                v => vec![v],
+            })
+            .collect::<Vec<&Value>>()
+    };
+
+    let mut values = vec![doc];
+    for part in field.trim_end_matches(".keyword").split('.') {
+        values = flatten(values)
+            .into_iter()
+            .filter_map(|v| v.get(part))
+            .collect();
+    }
+
+    flatten(values)
+        .into_iter()
+        .filter(|v| !v.is_null())
+        .collect()
+}
+
+/// Converts a scalar value into lower case text for comparison.
+fn as_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(v) => Some(v.to_lowercase()),
+        Value::Number(v) => Some(v.to_string()),
+        Value::Bool(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Compares two values as numbers if both are numeric, e.g. `176` and `"100"`, or as text otherwise.
+/// Text comparison works for ISO dates, e.g. `2021-01-07T08:57:45+00:00` is greater than `2021-01`.
+fn compare(a: &Value, b: &Value) -> Ordering {
+    let as_number = |v: &Value| match v {
+        Value::Number(v) => v.as_f64(),
+        Value::String(v) => v.parse::<f64>().ok(),
+        _ => None,
+    };
+
+    match (as_number(a), as_number(b)) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => as_text(a).cmp(&as_text(b)),
+    }
+}
+
+/// Number of insertions, deletions, substitutions and transpositions of adjacent chars to turn one word into another.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut d = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[a.len()][b.len()]
+}
@@ -0,0 +1,265 @@
+//! HTTP calls to ES or OpenSearch with AWS SigV4, basic, API key or no auth. This is the backend for any non-fixture ES URL.
+
+use super::backend::SearchBackend;
+use super::log_http_body;
+use crate::config::EsAuth;
+use crate::error::StmError;
+use futures::future::BoxFuture;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use rusoto_core::credential::{DefaultCredentialsProvider, ProvideAwsCredentials};
+use rusoto_signature::signature::SignedRequest;
+use serde_json::Value;
+use std::convert::TryInto;
+use std::str::FromStr;
+use std::time::Instant;
+use tracing::{debug, error, info};
+
+/// Sends requests over HTTP or HTTPS with the auth from the config. It is created once per process and
+/// reuses connections to ES between requests.
+pub(crate) struct HttpBackend {
+    auth: EsAuth,
+    /// Keeps connections to ES alive between requests
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// Caches AWS credentials and refreshes them before they expire. Only present for `AwsSigV4` auth.
+    credentials: Option<DefaultCredentialsProvider>,
+}
+
+impl HttpBackend {
+    /// Creates a pooled client and AWS credentials provider if the auth requires one.
+    pub(crate) fn new(auth: EsAuth) -> Result<Self, StmError> {
+        let credentials = match auth {
+            EsAuth::AwsSigV4 => match DefaultCredentialsProvider::new() {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    error!("Cannot get default creds provider: {}", e);
+                    return Err(StmError::Config(e.to_string()));
+                }
+            },
+            _ => None,
+        };
+
+        Ok(Self {
+            auth,
+            client: Client::builder().build::<_, Body>(HttpsConnector::with_native_roots()),
+            credentials,
+        })
+    }
+
+    /// Makes an API call to ES with the auth required by the cluster.
+    /// `es_api_endpoint` must be a fully qualified URL, e.g. https://x.ap-southeast-2.es.amazonaws.com/my_index/_search
+    /// or http://localhost:9200/my_index/_search
+    async fn call_es_api(
+        &self,
+        es_api_endpoint: String,
+        payload: Option<String>,
+    ) -> Result<Value, StmError> {
+        // prepare METHOD and the payload in one step
+        let (method, payload) = match payload {
+            None => ("GET", None),
+            Some(v) => ("POST", Some(v.as_bytes().to_owned())),
+        };
+        let payload_id = payload.as_ref().map(|v| v.len()).unwrap_or_default();
+        info!("ES query {} started", payload_id);
+        let started = Instant::now();
+
+        // The URL will need to be split into parts to extract region, host, etc. for signing
+        let uri = match Uri::from_maybe_shared(es_api_endpoint) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Invalid ES URL: {}", e);
+                return Err(StmError::Config(
+                    ["Invalid ES URL: ", &e.to_string()].concat(),
+                ));
+            }
+        };
+
+        // AWS requests are signed, the rest may have an auth header
+        let req = match &self.credentials {
+            Some(credentials) => signed_request(credentials, method, &uri, payload).await?,
+            None => plain_request(&self.auth, method, uri, payload)?,
+        };
+        debug!("Http rq: {:?}", req);
+
+        let res = match self.client.request(req).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("ES request failed: {}", e);
+                return Err(StmError::Es(e.to_string()));
+            }
+        };
+
+        info!(
+            "ES query {} response arrived in {}ms",
+            payload_id,
+            started.elapsed().as_millis()
+        );
+        let status = res.status();
+
+        // Concatenate the body stream into a single buffer...
+        let buf = match hyper::body::to_bytes(res).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Cannot convert response body to bytes: {}", e);
+                return Err(StmError::Es(e.to_string()));
+            }
+        };
+
+        // there should be at least some data returned
+        if buf.is_empty() {
+            error!("Empty body with status {}", status);
+            return Err(StmError::Es(
+                ["Empty body with status ", status.as_str()].concat(),
+            ));
+        }
+
+        // any status other than 200 is an error
+        if !status.is_success() {
+            error!("Status {}", status);
+            log_http_body(&buf);
+            return Err(StmError::Es(["Status ", status.as_str()].concat()));
+        }
+
+        // all responses should be JSON. If it's not JSON it's an error.
+        let output = match serde_json::from_slice::<Value>(&buf) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!("Failed to convert ES resp to JSON: {}", e);
+                log_http_body(&buf);
+                return Err(e.into());
+            }
+        };
+        info!(
+            "ES query {} finished in {}ms",
+            payload_id,
+            started.elapsed().as_millis()
+        );
+        //info!("{}", output.as_ref().unwrap()); // for debugging
+        output
+    }
+}
+
+impl SearchBackend for HttpBackend {
+    fn call(
+        &self,
+        es_api_endpoint: String,
+        payload: Option<String>,
+    ) -> BoxFuture<'_, Result<Value, StmError>> {
+        Box::pin(self.call_es_api(es_api_endpoint, payload))
+    }
+}
+
+/// Signs the request with SigV4 using the default AWS credentials. The region is taken from the host name,
+/// e.g. `x.ap-southeast-2.es.amazonaws.com`.
+async fn signed_request(
+    provider: &DefaultCredentialsProvider,
+    method: &str,
+    uri: &Uri,
+    payload: Option<Vec<u8>>,
+) -> Result<Request<Body>, StmError> {
+    let host = match uri.host() {
+        Some(v) => v.to_string(),
+        None => {
+            error!("Missing host in ES URL: {}", uri);
+            return Err(StmError::Config("Missing host in ES URL".to_owned()));
+        }
+    };
+
+    // get the region from teh URL
+    let region = host.trim_end_matches(".es.amazonaws.com");
+    let region = match region.rfind(".") {
+        Some(v) => region.split_at(v + 1).1,
+        None => {
+            error!("Invalid ES URL: {}", uri);
+            return Err(StmError::Config(["Invalid ES URL: ", &host].concat()));
+        }
+    };
+    let region = match rusoto_core::Region::from_str(region) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid region in the ES URL: {}", e);
+            return Err(StmError::Config(
+                ["Invalid region in the ES URL: ", region].concat(),
+            ));
+        }
+    };
+
+    // prepare the request
+    let mut req = SignedRequest::new(method, "es", &region, uri.path());
+    req.set_payload(payload);
+    req.set_hostname(Some(host));
+
+    // these headers are required by ES
+    req.add_header("Content-Type", content_type(uri));
+
+    // get AWS creds, which are cached by the provider until they are about to expire
+    let credentials = match provider.credentials().await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Cannot find creds: {}", e);
+            return Err(StmError::Config(e.to_string()));
+        }
+    };
+
+    // sign the request
+    req.sign(&credentials);
+
+    // convert the signed request into an HTTP request we can send out
+    match req.try_into() {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            error!("Cannot convert signed request into hyper request: {}", e);
+            Err(StmError::Es(e.to_string()))
+        }
+    }
+}
+
+/// Builds a request with basic auth, API key or no auth at all.
+fn plain_request(
+    auth: &EsAuth,
+    method: &str,
+    uri: Uri,
+    payload: Option<Vec<u8>>,
+) -> Result<Request<Body>, StmError> {
+    // these headers are required by ES
+    let content_type = content_type(&uri);
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Content-Type", content_type);
+
+    let req = match auth {
+        EsAuth::Basic { user, password } => req.header(
+            "Authorization",
+            ["Basic ", &base64::encode([user, ":", password].concat())].concat(),
+        ),
+        EsAuth::ApiKey(api_key) => req.header("Authorization", ["ApiKey ", api_key].concat()),
+        _ => req,
+    };
+
+    let body = match payload {
+        Some(v) => Body::from(v),
+        None => Body::empty(),
+    };
+
+    match req.body(body) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            // most likely an invalid char in the credentials
+            error!("Cannot build ES request: {}", e);
+            Err(StmError::Config(
+                ["Cannot build ES request: ", &e.to_string()].concat(),
+            ))
+        }
+    }
+}
+
+/// `_msearch` payload is NDJSON, the rest is plain JSON.
+fn content_type(uri: &Uri) -> &'static str {
+    if uri.path().ends_with("/_msearch") {
+        "application/x-ndjson"
+    } else {
+        "application/json"
+    }
+}
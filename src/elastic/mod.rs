@@ -0,0 +1,969 @@
+//use elasticsearch::{http::transport::Transport, CountParts, Elasticsearch, SearchParts};
+use crate::config::EsConnection;
+use crate::error::StmError;
+use futures::future::join3;
+use query::{Aggregation, BoolQuery, Query, Search, SortOrder, Suggester};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use tracing::{error, info};
+
+pub(crate) mod backend;
+mod fixture;
+mod http;
+pub(crate) mod query;
+
+/// Max number of devs returned by search queries, which is also the page size for paginated searches
+pub(crate) const MAX_DEVS_PER_SEARCH: usize = 24;
+/// ES refuses to return hits past this number (`index.max_result_window`) with `from` + `size` pagination
+pub(crate) const MAX_RESULT_WINDOW: usize = 10000;
+/// Max number of devs per page when walking the dev index by login, which is also the number of devs per sitemap.
+/// Sitemaps can have up to 50,000 URLs, but ES returns no more than 10,000 hits at a time.
+pub(crate) const DEV_LOGINS_PAGE_SIZE: usize = MAX_RESULT_WINDOW;
+/// Max number of languages in the list of all languages, which is more than there are in the index
+const MAX_DEV_LANGS: usize = 1000;
+
+/// Latest additions and updates to dev idx, hireable first, narrowed down by the filters, if any
+/// ```json
+/// {"size":24,"query":{"bool":{"must":[{"match":{"hireable":{"query":"true"}}}],"filter":[{"match_phrase":{"location":{"query":"new zealand"}}}]}},"sort":[{"report.timestamp":{"order":"desc"}}]}
+/// ```
+pub(crate) fn search_top_users(filters: &DevFilters) -> Search {
+    Search::new(Query::Bool(BoolQuery {
+        must: vec![Query::match_field("hireable", "true")],
+        filter: filters.to_queries(),
+        ..Default::default()
+    }))
+    .size(MAX_DEVS_PER_SEARCH)
+    .sort("report.timestamp", SortOrder::Desc)
+}
+
+/// A single dev by their login
+/// ```json
+/// {"query":{"term":{"login.keyword":{"value":"rimutaka"}}}}
+/// ```
+pub(crate) fn search_engineer_by_login(login: &str) -> Search {
+    Search::new(Query::term("login.keyword", login))
+}
+
+/// Several devs by their logins in a single request, e.g. for comparing them side by side
+/// ```json
+/// {"size":2,"query":{"terms":{"login.keyword":["rimutaka","dtolnay"]}}}
+/// ```
+pub(crate) fn search_engineers_by_logins(logins: &[String]) -> Search {
+    Search::new(Query::terms("login.keyword", logins)).size(logins.len())
+}
+
+/// A page of devs sorted by login, starting after the login, if any, for walking the entire dev index
+/// for sitemaps and pre-rendering. Only the login and the date of the latest report are returned.
+/// ```json
+/// {"size":10000,"query":{"match_all":{}},"sort":[{"login.keyword":{"order":"asc"}}],"search_after":["rimutaka"],"_source":["login","report.timestamp"]}
+/// ```
+pub(crate) fn search_dev_logins(after: Option<&str>) -> Search {
+    Search::new(Query::MatchAll)
+        .size(DEV_LOGINS_PAGE_SIZE)
+        .sort("login.keyword", SortOrder::Asc)
+        .search_after(after.map(|v| vec![v.to_owned()]).unwrap_or_default())
+        .source(&["login", "report.timestamp"])
+}
+
+/// All languages from dev reports
+/// ```json
+/// {"size":0,"query":{"match_all":{}},"aggregations":{"langs":{"terms":{"field":"report.tech.language.keyword","size":1000}}}}
+/// ```
+pub(crate) fn search_dev_langs() -> Search {
+    Search::new(Query::MatchAll).size(0).agg(
+        "langs",
+        Aggregation::terms("report.tech.language.keyword", MAX_DEV_LANGS, None),
+    )
+}
+
+/// A single repo by its GitHub owner and repo name. Repo idx docs have the same `report` section as dev idx docs,
+/// but for a single project.
+/// ```json
+/// {"query":{"bool":{"must":[{"term":{"owner_id.keyword":{"value":"rimutaka"}}},{"term":{"repo_name.keyword":{"value":"stm"}}}]}}}
+/// ```
+pub(crate) fn search_repo_by_name(owner: &str, repo: &str) -> Search {
+    Search::new(Query::Bool(BoolQuery {
+        must: vec![
+            Query::term("owner_id.keyword", owner),
+            Query::term("repo_name.keyword", repo),
+        ],
+        ..Default::default()
+    }))
+}
+
+/// Member of ESHitsCount
+#[derive(Deserialize)]
+struct ESHitsCountTotals {
+    value: usize,
+}
+
+/// Member of ESHitsCount
+#[derive(Deserialize)]
+struct ESHitsCountHits {
+    total: ESHitsCountTotals,
+}
+
+/// Corresponds to ES response metadata
+/// ```json
+/// {
+///     "took" : 652,
+///     "timed_out" : false,
+///     "_shards" : {
+///         "total" : 5,
+///         "successful" : 5,
+///         "skipped" : 0,
+///         "failed" : 0
+///     },
+///     "hits" : {
+///         "total" : {
+///         "value" : 0,
+///         "relation" : "eq"
+///         },
+///         "max_score" : null,
+///         "hits" : [ ]
+///     }
+/// }
+/// ```
+#[derive(Deserialize)]
+struct ESHitsCount {
+    hits: ESHitsCountHits,
+}
+
+/// Part of ESAggs
+#[derive(Deserialize)]
+struct ESAggsBucket {
+    pub key: String,
+    pub doc_count: usize,
+}
+
+/// Part of ESAggs
+#[derive(Deserialize)]
+struct ESAggsBuckets {
+    pub buckets: Vec<ESAggsBucket>,
+}
+
+/// Part of ESAggs
+#[derive(Deserialize)]
+struct ESAggsAgg {
+    pub agg: ESAggsBuckets,
+}
+
+/// A generic structure for ES aggregations result. Make sure the aggregation name is `aggs`.
+/// ```json
+///   {
+///     "aggregations" : {
+///       "agg" : {
+///         "buckets" : [
+///           {
+///             "key" : "twilio",
+///             "doc_count" : 597
+///           }
+///         ]
+///       }
+///     }
+///   }
+/// ```
+#[derive(Deserialize)]
+struct ESAggs {
+    pub aggregations: ESAggsAgg,
+}
+
+/// A single suggested term from ES term suggester, part of ESSuggest
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct TermSuggestion {
+    /// The suggested term as it is stored in ES
+    pub text: String,
+    /// 0..1, where 1 is an exact match
+    pub score: f64,
+    /// Number of docs with the term
+    pub freq: usize,
+}
+
+/// Part of ESSuggest
+#[derive(Deserialize)]
+struct ESSuggestEntry {
+    options: Vec<TermSuggestion>,
+}
+
+/// A generic structure for ES term suggester results. There is one entry per suggester, which has one entry
+/// per token in the text. A keyword field produces a single token.
+/// ```json
+///   {
+///     "suggest" : {
+///       "s0" : [
+///         {
+///           "text" : "tokoi",
+///           "offset" : 0,
+///           "length" : 5,
+///           "options" : [
+///             {
+///               "text" : "tokio",
+///               "score" : 0.8,
+///               "freq" : 597
+///             }
+///           ]
+///         }
+///       ]
+///     }
+///   }
+/// ```
+#[derive(Deserialize)]
+struct ESSuggest {
+    #[serde(default)]
+    suggest: HashMap<String, Vec<ESSuggestEntry>>,
+}
+
+/// Returns the names of all languages from dev reports, most used first, e.g. `["Rust", "C#"]`.
+pub(crate) async fn dev_langs(es: &EsConnection, dev_idx: &str) -> Result<Vec<String>, StmError> {
+    let es_response = search(es, dev_idx, Some(&search_dev_langs())).await?;
+
+    Ok(es_response["aggregations"]["langs"]["buckets"]
+        .as_array()
+        .map(|buckets| {
+            buckets
+                .iter()
+                .filter_map(|bucket| bucket["key"].as_str())
+                .map(|v| v.to_owned())
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default())
+}
+
+/// Run a search with the provided query.
+/// * es: elastucsearch url and auth
+/// * idx: ES index name
+/// * query: the query, if any for *_search* or `None` for *_count*
+pub(crate) async fn search(
+    es: &EsConnection,
+    idx: &str,
+    query: Option<&Search>,
+) -> Result<Value, StmError> {
+    if let Some(query) = query {
+        let es_api_endpoint = [es.url.as_str(), "/", idx, "/_search"].concat();
+        return call_es_api(es, es_api_endpoint, Some(query.to_string())).await;
+    } else {
+        let es_api_endpoint = [es.url.as_str(), "/", idx, "/_count"].concat();
+        return call_es_api(es, es_api_endpoint, None).await;
+    }
+}
+
+/// A generic function for making ES API calls via the backend matching the connection, which is either
+/// HTTP calls with the configured auth or JSON fixtures for `file://` URLs. See `backend` module for details.
+/// `es_api_endpoint` must be a fully qualified URL, e.g. https://x.ap-southeast-2.es.amazonaws.com/my_index/_search
+pub(crate) async fn call_es_api(
+    es: &EsConnection,
+    es_api_endpoint: String,
+    payload: Option<String>,
+) -> Result<Value, StmError> {
+    es.backend.call(es_api_endpoint, payload).await
+}
+
+/// A batch of searches sent to ES as a single `_msearch` request to save on round trips.
+/// The responses come back in the same order as the searches were added.
+#[derive(Default)]
+pub(crate) struct MultiSearch {
+    /// Index name and the search for that index
+    searches: Vec<(String, Search)>,
+}
+
+impl MultiSearch {
+    /// Adds the search to the batch and returns the position of its response in the output of `run`.
+    pub fn add(&mut self, idx: &str, search: Search) -> usize {
+        self.searches.push((idx.to_owned(), search));
+        self.searches.len() - 1
+    }
+
+    /// Adds a search for the number of docs with the field matching the value and returns the position of its response.
+    /// Use `hits_total` to get the count from the response. The field name is not validated or sanitized.
+    /// The field value can be any string.
+    pub fn add_doc_count(&mut self, idx: &str, field: &str, field_value: &str) -> usize {
+        self.add(
+            idx,
+            Search::new(Query::match_field(field, field_value)).size(0),
+        )
+    }
+
+    /// Adds a search for terms similar to the term in any of the fields and returns the position of its response.
+    /// Use `similar_terms` to get the suggestions from the response.
+    pub fn add_similar_terms(
+        &mut self,
+        idx: &str,
+        fields: &[&str],
+        term: &str,
+        max_suggestions: usize,
+    ) -> usize {
+        self.add(idx, search_similar_terms(fields, term, max_suggestions))
+    }
+
+    /// Sends all searches to ES in a single request. Returns an error if the request or any of the searches fail.
+    /// ```json
+    /// {"index":"dev"}
+    /// {"size":0,"query":{"match":{"report.tech.language.keyword":{"query":"rust"}}}}
+    /// {"index":"dev"}
+    /// {"size":0,"query":{"match":{"report.tech.refs_kw.k.keyword":{"query":"rust"}}}}
+    /// ```
+    pub async fn run(&self, es: &EsConnection) -> Result<Vec<Value>, StmError> {
+        if self.searches.is_empty() {
+            return Ok(Vec::new());
+        }
+        info!("ES msearch of {} searches", self.searches.len());
+
+        // NDJSON with a header line before every search, the last line must end with \n as well
+        let mut payload = String::new();
+        for (idx, search) in &self.searches {
+            payload.push_str(&serde_json::json!({ "index": idx }).to_string());
+            payload.push('\n');
+            payload.push_str(&search.to_string());
+            payload.push('\n');
+        }
+
+        let es_api_endpoint = [es.url.as_str(), "/_msearch"].concat();
+        let es_response = call_es_api(es, es_api_endpoint, Some(payload)).await?;
+
+        // {"took":5,"responses":[{"took":3,"hits":{...},"status":200},{"error":{...},"status":404}]}
+        let responses = match es_response {
+            Value::Object(mut v) => match v.remove("responses") {
+                Some(Value::Array(responses)) if responses.len() == self.searches.len() => {
+                    responses
+                }
+                _ => {
+                    error!("Invalid msearch response");
+                    return Err(StmError::Es("Invalid msearch response".to_owned()));
+                }
+            },
+            _ => {
+                error!("Invalid msearch response");
+                return Err(StmError::Es("Invalid msearch response".to_owned()));
+            }
+        };
+
+        // a single failed search fails the entire batch, same as if they were sent separately
+        if let Some(failed) = responses.iter().find(|v| !v["error"].is_null()) {
+            error!("msearch failed with {}", failed["error"]);
+            return Err(StmError::Es(
+                ["Status ", &failed["status"].to_string()].concat(),
+            ));
+        }
+
+        Ok(responses)
+    }
+}
+
+/// Returns the total number of hits from a search response, e.g. the response for `MultiSearch::add_doc_count`.
+pub(crate) fn hits_total(es_response: &Value) -> Result<usize, StmError> {
+    // extract the actual value from a struct like this
+    // {
+    //     "took" : 652,
+    //     "timed_out" : false,
+    //     "_shards" : {
+    //       "total" : 5,
+    //       "successful" : 5,
+    //       "skipped" : 0,
+    //       "failed" : 0
+    //     },
+    //     "hits" : {
+    //       "total" : {
+    //         "value" : 0,
+    //         "relation" : "eq"
+    //       },
+    //       "max_score" : null,
+    //       "hits" : [ ]
+    //     }
+    // }
+    match ESHitsCount::deserialize(es_response) {
+        Ok(v) => Ok(v.hits.total.value),
+        Err(e) => {
+            error!("Failed to get hits total with {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Logs the body as error!(), if possible.
+pub(crate) fn log_http_body(body_bytes: &hyper::body::Bytes) {
+    // log the body as-is if it's not too long
+    if body_bytes.len() < 5000 {
+        let s = match std::str::from_utf8(body_bytes).to_owned() {
+            Err(_e) => "The body is not UTF-8".to_string(),
+            Ok(v) => v.to_string(),
+        };
+        error!("Response body: {}", s);
+    } else {
+        error!("Response is too long to log: {}B", body_bytes.len());
+    }
+}
+
+/// A search term after it was matched to a field in dev idx.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum DevSearchTerm {
+    /// Matches `report.tech.language.keyword`
+    Lang(String),
+    /// Matches packages and refs, either fully qualified or as keywords depending on the presence of `.`
+    Keyword(String),
+}
+
+impl DevSearchTerm {
+    /// Converts the term into an ES query clause.
+    fn to_query(&self) -> Query {
+        match self {
+            // language clause is different from keywords clause
+            DevSearchTerm::Lang(lang) => Query::match_field("report.tech.language.keyword", lang),
+            DevSearchTerm::Keyword(keyword) => {
+                // query  pkgs and refs if the name is qualified or pkgs_kw and refs_kw if it's not
+                let fields = if keyword.contains(".") {
+                    ["report.tech.pkgs.k.keyword", "report.tech.refs.k.keyword"]
+                } else {
+                    [
+                        "report.tech.pkgs_kw.k.keyword",
+                        "report.tech.refs_kw.k.keyword",
+                    ]
+                };
+
+                // using multimatch because different techs have keywords in different places
+                Query::multi_match(&fields, keyword)
+            }
+        }
+    }
+}
+
+/// All search terms for `matching_devs`.
+#[derive(Default, Debug, PartialEq)]
+pub(crate) struct DevSearchTerms {
+    /// At least one term from every group must match. A single required term is a group of one.
+    pub must: Vec<Vec<DevSearchTerm>>,
+    /// None of these terms can match
+    pub must_not: Vec<DevSearchTerm>,
+}
+
+/// Filters that narrow down dev search results. They go into `filter` clauses and do not affect the score.
+#[derive(Default, Clone, Debug)]
+pub(crate) struct DevFilters {
+    /// Words from the free-form location in the GitHub profile, e.g. `new zealand`
+    pub location: Option<String>,
+    pub hireable: Option<bool>,
+    /// Min date of the latest commit in ES date format, e.g. `2021`, `2021-01` or `2021-01-31`
+    pub active_since: Option<String>,
+    /// Min number of lines of code in any of the languages. It cannot be tied to a particular language
+    /// because `report.tech` is not a nested field.
+    pub min_loc: Option<usize>,
+}
+
+impl DevFilters {
+    /// Returns true if no filters are set.
+    pub fn is_empty(&self) -> bool {
+        self.to_queries().is_empty()
+    }
+
+    /// Converts the filters into ES query clauses.
+    fn to_queries(&self) -> Vec<Query> {
+        let mut queries: Vec<Query> = Vec::new();
+
+        if let Some(location) = &self.location {
+            queries.push(Query::match_phrase("location", location));
+        }
+        if let Some(hireable) = self.hireable {
+            queries.push(Query::term("hireable", &hireable.to_string()));
+        }
+        if let Some(active_since) = &self.active_since {
+            queries.push(Query::range_gte("report.date_head", active_since));
+        }
+        if let Some(min_loc) = self.min_loc {
+            queries.push(Query::range_gte(
+                "report.tech.code_lines",
+                &min_loc.to_string(),
+            ));
+        }
+
+        queries
+    }
+}
+
+/// Returns up to 24 matching docs from DEV idx depending on the params. The query is built to match the list of params.
+/// Lang and KW params can be any strings.
+/// `page` is 1-based. The caller must make sure it does not go past `MAX_RESULT_WINDOW`.
+pub(crate) async fn matching_devs(
+    es: &EsConnection,
+    dev_idx: &str,
+    terms: &DevSearchTerms,
+    filters: &DevFilters,
+    page: usize,
+) -> Result<Value, StmError> {
+    let query = search_devs(terms, filters, page);
+
+    // call the query
+    let es_api_endpoint = [es.url.as_str(), "/", dev_idx, "/_search"].concat();
+    let es_response = call_es_api(es, es_api_endpoint, Some(query.to_string())).await?;
+
+    Ok(es_response)
+}
+
+/// Builds the query for `matching_devs`. It can also be sent as part of `MultiSearch`.
+pub(crate) fn search_devs(terms: &DevSearchTerms, filters: &DevFilters, page: usize) -> Search {
+    // sample query for `rust (tokio OR async-std) -actix`
+    // {"size":24,"from":0,"track_scores":true,"query":{"bool":{"must":[{"match":{"report.tech.language.keyword":{"query":"rust"}}},{"bool":{"should":[{"multi_match":{"query":"tokio","fields":["report.tech.pkgs_kw.k.keyword","report.tech.refs_kw.k.keyword"]}},{"multi_match":{"query":"async-std","fields":["report.tech.pkgs_kw.k.keyword","report.tech.refs_kw.k.keyword"]}}],"minimum_should_match":1}}],"must_not":[{"multi_match":{"query":"actix","fields":["report.tech.pkgs_kw.k.keyword","report.tech.refs_kw.k.keyword"]}}]}},"sort":[{"hireable":{"order":"desc"}},{"report.timestamp":{"order":"desc"}}]}
+    Search::new(dev_search_query(terms, filters))
+        .size(MAX_DEVS_PER_SEARCH)
+        .from((page.max(1) - 1) * MAX_DEVS_PER_SEARCH)
+        .track_scores(true)
+        .sort("hireable", SortOrder::Desc)
+        .sort("report.timestamp", SortOrder::Desc)
+}
+
+/// The same devs as `search_devs`, but with the latest reports first regardless of `hireable`, e.g. for feeds.
+/// ```json
+/// {"size":24,"from":0,"query":{"bool":{"must":[{"match":{"report.tech.language.keyword":{"query":"rust"}}}]}},"sort":[{"report.timestamp":{"order":"desc"}}]}
+/// ```
+pub(crate) fn search_latest_devs(
+    terms: &DevSearchTerms,
+    filters: &DevFilters,
+    page: usize,
+) -> Search {
+    Search::new(dev_search_query(terms, filters))
+        .size(MAX_DEVS_PER_SEARCH)
+        .from((page.max(1) - 1) * MAX_DEVS_PER_SEARCH)
+        .sort("report.timestamp", SortOrder::Desc)
+}
+
+/// Combines the terms and the filters into a single bool query.
+fn dev_search_query(terms: &DevSearchTerms, filters: &DevFilters) -> Query {
+    // a collector of must clauses
+    let mut must_clauses: Vec<Query> = Vec::new();
+
+    for group in &terms.must {
+        if group.len() == 1 {
+            must_clauses.push(group[0].to_query());
+        } else if !group.is_empty() {
+            // alternatives go into a nested bool query
+            must_clauses.push(Query::Bool(BoolQuery {
+                should: group.iter().map(|v| v.to_query()).collect(),
+                minimum_should_match: Some(1),
+                ..Default::default()
+            }));
+        }
+    }
+
+    Query::Bool(BoolQuery {
+        must: must_clauses,
+        must_not: terms.must_not.iter().map(|v| v.to_query()).collect(),
+        filter: filters.to_queries(),
+        ..Default::default()
+    })
+}
+
+/// Reads a single document by ID.
+/// Returns `_source` as the root tag with `hits` and meta sections stripped off.
+/// ```json
+///   {
+///     "_source" : {
+///       "repo" : [
+///         {
+///           "ts" : 1615195803,
+///           "iso" : "2021-03-08T09:30:03.966075280+00:00",
+///           "c" : 1725617
+///         }
+///       ]
+///     }
+///   }
+/// ```
+pub(crate) async fn get_doc_by_id(
+    es: &EsConnection,
+    idx: &str,
+    doc_id: &str,
+    no_sql_string_invalidation_regex: &Regex,
+) -> Result<Value, StmError> {
+    // validate field_value for possible no-sql injection
+    if no_sql_string_invalidation_regex.is_match(doc_id) {
+        error!("Invalid doc_id: {}", doc_id);
+        return Err(StmError::Validation(["Invalid doc_id: ", doc_id].concat()));
+    }
+
+    let es_api_endpoint = [
+        es.url.as_str(),
+        "/",
+        idx,
+        "/_doc/",
+        doc_id,
+        "?filter_path=_source",
+    ]
+    .concat();
+
+    let es_response = call_es_api(es, es_api_endpoint, None).await?;
+
+    Ok(es_response)
+}
+
+/// Search related keywords and packages by a partial keyword, up to 100 of each.
+/// Returns a combined list of keyword/populary count for refs_kw and pkgs_kw sorted alphabetically.
+/// The keyword is checked for validity ([^\-_0-9a-zA-Z]) before inserting into the regex query.
+/// Returns an error if the keyword has any extra characters or the queries fail.
+pub(crate) async fn related_keywords(
+    es: &EsConnection,
+    idx: &str,
+    keyword: &str,
+) -> Result<Vec<(String, usize)>, StmError> {
+    // the keyword goes into a regex, which is not escaped by serde
+    let rgx = Regex::new(crate::config::SAFE_REGEX_SUBSTRING)
+        .expect("Failed to compile SAFE_REGEX_SUBSTRING");
+    if rgx.is_match(keyword) {
+        error!("Invalid keyword: {}", keyword);
+        return Err(StmError::Validation(
+            ["Invalid keyword: ", keyword].concat(),
+        ));
+    }
+
+    // send a joined query to ES
+    let refs = search_related("report.tech.refs.k.keyword", keyword);
+    let pkgs = search_related("report.tech.pkgs.k.keyword", keyword);
+    let langs = search_related("report.tech.language.keyword", keyword);
+    let (refs, pkgs, langs) = join3(
+        search(es, idx, Some(&refs)),
+        search(es, idx, Some(&pkgs)),
+        search(es, idx, Some(&langs)),
+    )
+    .await;
+
+    // extract the data from JSON
+    let refs = match serde_json::from_value::<ESAggs>(refs?) {
+        Err(e) => {
+            error!("Cannot deser refs with {}", e);
+            return Err(e.into());
+        }
+        Ok(v) => v,
+    };
+    let pkgs = match serde_json::from_value::<ESAggs>(pkgs?) {
+        Err(e) => {
+            error!("Cannot pkgs refs with {}", e);
+            return Err(e.into());
+        }
+        Ok(v) => v,
+    };
+    let langs = match serde_json::from_value::<ESAggs>(langs?) {
+        Err(e) => {
+            error!("Cannot deser langs with {}", e);
+            return Err(e.into());
+        }
+        Ok(v) => v,
+    };
+
+    // extract refs into a hashmap
+    let mut related = refs
+        .aggregations
+        .agg
+        .buckets
+        .into_iter()
+        .map(|v| (v.key.to_lowercase(), v.doc_count))
+        .collect::<HashMap<String, usize>>();
+
+    // combine the refs counts with pkgs counts
+    for bucket in pkgs.aggregations.agg.buckets {
+        if let Some(doc_count) = related.get_mut(&bucket.key) {
+            *doc_count += bucket.doc_count;
+        } else {
+            related.insert(bucket.key, bucket.doc_count);
+        }
+    }
+
+    // repeat the same for languages
+    for bucket in langs.aggregations.agg.buckets {
+        if let Some(doc_count) = related.get_mut(&bucket.key) {
+            *doc_count += bucket.doc_count;
+        } else {
+            related.insert(bucket.key, bucket.doc_count);
+        }
+    }
+
+    // convert the combined hashmap into an array
+    let mut related = related
+        .into_iter()
+        .map(|v| (v.0, v.1))
+        .collect::<Vec<(String, usize)>>();
+
+    // sort the result alphabetically
+    related.sort_by_key(|v| Reverse(v.1));
+
+    Ok(related)
+}
+
+/// Values of the field that contain the keyword as a whole or after a `.`, for `related_keywords`.
+/// The keyword is inserted into a regex and must be validated by the caller.
+/// ```json
+/// {"size":0,"aggregations":{"agg":{"terms":{"field":"report.tech.refs.k.keyword","size":50,"include":"(.*\\.)?tokio.*"}}}}
+/// ```
+pub(crate) fn search_related(field: &str, keyword: &str) -> Search {
+    // some keywords may contain #,. or -, which should be escaped in regex
+    let keyword_escaped = keyword
+        .replace("#", r#"\#"#)
+        .replace(".", r#"\."#)
+        .replace("-", r#"\-"#);
+    let include = [r#"(.*\.)?"#, &keyword_escaped, ".*"].concat();
+
+    Search::default()
+        .size(0)
+        .agg("agg", Aggregation::terms(field, 50, Some(include)))
+}
+
+/// Returns terms that are similar to the term, e.g. `tokio` for `tokoi`, from the response for `MultiSearch::add_similar_terms`.
+/// The suggestions from all fields are merged, sorted by score and popularity, best first, and capped at `max_suggestions`.
+/// The term itself is never included.
+pub(crate) fn similar_terms(
+    es_response: &Value,
+    term: &str,
+    max_suggestions: usize,
+) -> Result<Vec<TermSuggestion>, StmError> {
+    let es_response = match ESSuggest::deserialize(es_response) {
+        Err(e) => {
+            error!("Cannot deser suggestions with {}", e);
+            return Err(e.into());
+        }
+        Ok(v) => v,
+    };
+
+    // the same term can come from different fields, e.g. refs and pkgs
+    let mut suggestions: Vec<TermSuggestion> = Vec::new();
+    for suggestion in es_response
+        .suggest
+        .into_values()
+        .flatten()
+        .flat_map(|entry| entry.options)
+    {
+        let text = suggestion.text.to_lowercase();
+        if text == term {
+            continue;
+        }
+        match suggestions.iter_mut().find(|v| v.text == text) {
+            Some(existing) => {
+                existing.score = existing.score.max(suggestion.score);
+                existing.freq = existing.freq.max(suggestion.freq);
+            }
+            None => suggestions.push(TermSuggestion { text, ..suggestion }),
+        }
+    }
+
+    suggestions.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.freq.cmp(&a.freq))
+    });
+    suggestions.truncate(max_suggestions);
+
+    Ok(suggestions)
+}
+
+/// One term suggester per field within a single query, named `s0`, `s1`, etc. in the order of the fields.
+/// ```json
+/// {"size":0,"suggest":{"s0":{"text":"tokoi","term":{"field":"report.tech.refs_kw.k.keyword","suggest_mode":"always","size":3}}}}
+/// ```
+pub(crate) fn search_similar_terms(fields: &[&str], term: &str, max_suggestions: usize) -> Search {
+    let mut query = Search::default().size(0);
+    for (field_idx, field) in fields.iter().enumerate() {
+        query = query.suggest(
+            &["s", &field_idx.to_string()].concat(),
+            Suggester::term(term, field, max_suggestions),
+        );
+    }
+
+    query
+}
+
+/// The latest N entries from the stats index. The index name is also the name of the top element in its docs.
+/// ```json
+/// {"size":10,"query":{"match_all":{}},"sort":[{"stm_stats_dev_job_counts.ts":{"order":"desc"}}]}
+/// ```
+pub(crate) fn search_stm_stats(idx: &str, count: usize) -> Search {
+    Search::new(Query::MatchAll)
+        .size(count)
+        .sort(&[idx, ".ts"].concat(), SortOrder::Desc)
+}
+
+/// Reads the latest N entries from the specified stats index, e.g. stm_stats_dev_job_counts.
+/// Returns the entire response as JSON Value. The index must follow a certain pattern
+/// with the top element the same as the name of the query. Any other format will fail
+/// at Tera transform.
+/// ```json
+/// {
+/// "stm_stats_dev_job_counts" : {
+///     "iso" : "2021-04-29T10:32:17.660423+00:00",
+///     "ts" : 1619692338,
+///     ...
+///   }
+/// }
+/// ```
+/// The name of the IDX is included as a field in the query, but is NOT SANITIZED.
+pub(crate) async fn get_stm_stats(
+    es: &EsConnection,
+    idx: &str,
+    count: usize,
+) -> Result<Value, StmError> {
+    // e.g. GET stm_stats_dev_job_counts/_search
+    let es_api_endpoint = [es.url.as_str(), "/", idx, "/_search"].concat();
+
+    let query = search_stm_stats(idx, count);
+
+    let es_response = call_es_api(es, es_api_endpoint, Some(query.to_string())).await?;
+
+    Ok(es_response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compares the serialized search with the expected JSON regardless of the order of the members.
+    fn assert_json(search: Search, expected: &str) {
+        let actual: Value = serde_json::from_str(&search.to_string()).unwrap();
+        let expected: Value = serde_json::from_str(expected).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    // The hand-written queries used the short form of match, `{"match":{"field":"value"}}`, in some places.
+    // ES expands it into `{"match":{"field":{"query":"value"}}}`, which is what the builders output.
+
+    #[test]
+    fn baseline_queries() {
+        assert_json(
+            search_engineer_by_login("rimutaka"),
+            r#"{"query":{"term":{"login.keyword":{"value":"rimutaka"}}}}"#,
+        );
+
+        let mut msearch = MultiSearch::default();
+        msearch.add_doc_count("dev", "report.tech.refs_kw.k.keyword", "tokio");
+        assert_json(
+            msearch.searches.pop().unwrap().1,
+            r#"{"query":{"match":{"report.tech.refs_kw.k.keyword":{"query":"tokio"}}},"size":0}"#,
+        );
+
+        let terms = DevSearchTerms {
+            must: vec![
+                vec![DevSearchTerm::Lang("rust".to_owned())],
+                vec![DevSearchTerm::Keyword("logger".to_owned())],
+                vec![DevSearchTerm::Keyword("serde_json.Value".to_owned())],
+            ],
+            ..Default::default()
+        };
+        // `from` was added for pagination
+        assert_json(
+            search_devs(&terms, &DevFilters::default(), 1),
+            r#"{"size":24,"from":0,"track_scores":true,"query":{"bool":{"must":[{"match":{"report.tech.language.keyword":{"query":"rust"}}},{"multi_match":{"query":"logger","fields":["report.tech.pkgs_kw.k.keyword","report.tech.refs_kw.k.keyword"]}},{"multi_match":{"query":"serde_json.Value","fields":["report.tech.pkgs.k.keyword","report.tech.refs.k.keyword"]}}]}},"sort":[{"hireable":{"order":"desc"}},{"report.timestamp":{"order":"desc"}}]}"#,
+        );
+
+        for field in &[
+            "report.tech.refs.k.keyword",
+            "report.tech.pkgs.k.keyword",
+            "report.tech.language.keyword",
+        ] {
+            assert_json(
+                search_related(field, "tokio"),
+                &[
+                    r#"{"size":0,"aggregations":{"agg":{"terms":{"field":""#,
+                    field,
+                    r#"","size":50,"include":"(.*\\.)?tokio.*"}}}}"#,
+                ]
+                .concat(),
+            );
+        }
+
+        assert_json(
+            search_stm_stats("stm_stats_dev_job_counts", 10),
+            r#"{"size":10,"query":{"match_all":{}},"sort":[{"stm_stats_dev_job_counts.ts":{"order":"desc"}}]}"#,
+        );
+    }
+
+    #[test]
+    fn top_users() {
+        // the same as the baseline query without filters
+        assert_json(
+            search_top_users(&DevFilters::default()),
+            r#"{"size":24,"query":{"bool":{"must":[{"match":{"hireable":{"query":"true"}}}]}},"sort":[{"report.timestamp":{"order":"desc"}}]}"#,
+        );
+
+        let filters = DevFilters {
+            location: Some("new zealand".to_owned()),
+            hireable: Some(true),
+            active_since: Some("2021-01".to_owned()),
+            min_loc: Some(1000),
+        };
+        assert_json(
+            search_top_users(&filters),
+            r#"{"size":24,"query":{"bool":{"must":[{"match":{"hireable":{"query":"true"}}}],"filter":[{"match_phrase":{"location":{"query":"new zealand"}}},{"term":{"hireable":{"value":"true"}}},{"range":{"report.date_head":{"gte":"2021-01"}}},{"range":{"report.tech.code_lines":{"gte":"1000"}}}]}},"sort":[{"report.timestamp":{"order":"desc"}}]}"#,
+        );
+    }
+
+    #[test]
+    fn dev_queries() {
+        assert_json(
+            search_engineers_by_logins(&["rimutaka".to_owned(), "dtolnay".to_owned()]),
+            r#"{"size":2,"query":{"terms":{"login.keyword":["rimutaka","dtolnay"]}}}"#,
+        );
+        assert_json(
+            search_dev_logins(Some("rimutaka")),
+            r#"{"size":10000,"query":{"match_all":{}},"sort":[{"login.keyword":{"order":"asc"}}],"search_after":["rimutaka"],"_source":["login","report.timestamp"]}"#,
+        );
+        assert_json(
+            search_dev_logins(None),
+            r#"{"size":10000,"query":{"match_all":{}},"sort":[{"login.keyword":{"order":"asc"}}],"_source":["login","report.timestamp"]}"#,
+        );
+        assert_json(
+            search_dev_langs(),
+            r#"{"size":0,"query":{"match_all":{}},"aggregations":{"langs":{"terms":{"field":"report.tech.language.keyword","size":1000}}}}"#,
+        );
+        assert_json(
+            search_repo_by_name("rimutaka", "stm"),
+            r#"{"query":{"bool":{"must":[{"term":{"owner_id.keyword":{"value":"rimutaka"}}},{"term":{"repo_name.keyword":{"value":"stm"}}}]}}}"#,
+        );
+    }
+
+    #[test]
+    fn search_syntax_queries() {
+        // rust (tokio OR async-std) -actix
+        let keyword = |v: &str| DevSearchTerm::Keyword(v.to_owned());
+        let terms = DevSearchTerms {
+            must: vec![
+                vec![DevSearchTerm::Lang("rust".to_owned())],
+                vec![keyword("tokio"), keyword("async-std")],
+            ],
+            must_not: vec![keyword("actix")],
+        };
+        assert_json(
+            search_devs(&terms, &DevFilters::default(), 3),
+            r#"{"size":24,"from":48,"track_scores":true,"query":{"bool":{"must":[{"match":{"report.tech.language.keyword":{"query":"rust"}}},{"bool":{"should":[{"multi_match":{"query":"tokio","fields":["report.tech.pkgs_kw.k.keyword","report.tech.refs_kw.k.keyword"]}},{"multi_match":{"query":"async-std","fields":["report.tech.pkgs_kw.k.keyword","report.tech.refs_kw.k.keyword"]}}],"minimum_should_match":1}}],"must_not":[{"multi_match":{"query":"actix","fields":["report.tech.pkgs_kw.k.keyword","report.tech.refs_kw.k.keyword"]}}]}},"sort":[{"hireable":{"order":"desc"}},{"report.timestamp":{"order":"desc"}}]}"#,
+        );
+
+        let terms = DevSearchTerms {
+            must: vec![vec![DevSearchTerm::Lang("rust".to_owned())]],
+            ..Default::default()
+        };
+        assert_json(
+            search_latest_devs(&terms, &DevFilters::default(), 1),
+            r#"{"size":24,"from":0,"query":{"bool":{"must":[{"match":{"report.tech.language.keyword":{"query":"rust"}}}]}},"sort":[{"report.timestamp":{"order":"desc"}}]}"#,
+        );
+    }
+
+    #[test]
+    fn user_input_is_escaped() {
+        // values are JSON-escaped by serde
+        assert_json(
+            search_engineer_by_login(r#"a"}},{"b"#),
+            r#"{"query":{"term":{"login.keyword":{"value":"a\"}},{\"b"}}}}"#,
+        );
+        // regex special chars allowed in keywords are escaped in the regex
+        assert_json(
+            search_related("report.tech.refs.k.keyword", "c#.net-core"),
+            r#"{"size":0,"aggregations":{"agg":{"terms":{"field":"report.tech.refs.k.keyword","size":50,"include":"(.*\\.)?c\\#\\.net\\-core.*"}}}}"#,
+        );
+        assert_json(
+            search_similar_terms(
+                &[
+                    "report.tech.refs_kw.k.keyword",
+                    "report.tech.pkgs_kw.k.keyword",
+                ],
+                "tokoi",
+                3,
+            ),
+            r#"{"size":0,"suggest":{"s0":{"text":"tokoi","term":{"field":"report.tech.refs_kw.k.keyword","suggest_mode":"always","size":3}},"s1":{"text":"tokoi","term":{"field":"report.tech.pkgs_kw.k.keyword","suggest_mode":"always","size":3}}}}"#,
+        );
+    }
+}
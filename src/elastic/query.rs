@@ -0,0 +1,350 @@
+//! A minimal typed subset of ElasticSearch Query DSL.
+//!
+//! All user input is passed around as values and serialized by serde_json, so there is
+//! no need to validate or escape it before inserting into the query.
+//! ```rust
+//! let query = Search::new(Query::match_field("report.tech.language.keyword", "rust"))
+//!     .size(24)
+//!     .sort("report.timestamp", SortOrder::Desc);
+//! ```
+//! serializes as
+//! ```json
+//! {"size":24,"query":{"match":{"report.tech.language.keyword":{"query":"rust"}}},"sort":[{"report.timestamp":{"order":"desc"}}]}
+//! ```
+
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The root of a `_search` request body.
+#[derive(Serialize, Default)]
+pub(crate) struct Search {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track_scores: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query: Option<Query>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sort: Vec<Sort>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    search_after: Vec<String>,
+    #[serde(rename = "_source", skip_serializing_if = "Option::is_none")]
+    source: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    aggregations: BTreeMap<String, Aggregation>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    suggest: BTreeMap<String, Suggester>,
+}
+
+impl Search {
+    /// A search with the query and ES defaults for everything else.
+    pub fn new(query: Query) -> Self {
+        Search {
+            query: Some(query),
+            ..Default::default()
+        }
+    }
+
+    /// Max number of hits to return.
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Number of hits to skip.
+    pub fn from(mut self, from: usize) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    /// Calculate scores even if the results are sorted by a field.
+    pub fn track_scores(mut self, track_scores: bool) -> Self {
+        self.track_scores = Some(track_scores);
+        self
+    }
+
+    /// Adds a sort clause. The order of the calls is the order of the sort.
+    pub fn sort(mut self, field: &str, order: SortOrder) -> Self {
+        self.sort.push(Sort {
+            field: field.to_owned(),
+            order,
+        });
+        self
+    }
+
+    /// Returns hits after the hit with these sort values, one value per sort clause, e.g. the login of the last dev on the previous page.
+    pub fn search_after(mut self, values: Vec<String>) -> Self {
+        self.search_after = values;
+        self
+    }
+
+    /// Returns only these fields in `_source` of every hit.
+    pub fn source(mut self, fields: &[&str]) -> Self {
+        self.source = Some(fields.iter().map(|v| v.to_string()).collect());
+        self
+    }
+
+    /// Adds a named aggregation.
+    pub fn agg(mut self, name: &str, agg: Aggregation) -> Self {
+        self.aggregations.insert(name.to_owned(), agg);
+        self
+    }
+
+    /// Adds a named suggester.
+    pub fn suggest(mut self, name: &str, suggester: Suggester) -> Self {
+        self.suggest.insert(name.to_owned(), suggester);
+        self
+    }
+}
+
+impl std::fmt::Display for Search {
+    /// Outputs the query as JSON, ready to be sent to ES.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+/// Leaf and compound queries supported by this module.
+pub(crate) enum Query {
+    /// `{"match_all":{}}`
+    MatchAll,
+    /// `{"match":{"field":{"query":"value"}}}`
+    Match { field: String, query: String },
+    /// `{"multi_match":{"query":"value","fields":["f1","f2"]}}`
+    MultiMatch { query: String, fields: Vec<String> },
+    /// `{"match_phrase":{"field":{"query":"value"}}}`
+    MatchPhrase { field: String, query: String },
+    /// `{"term":{"field":{"value":"value"}}}`
+    Term { field: String, value: String },
+    /// `{"terms":{"field":["value1","value2"]}}`
+    Terms { field: String, values: Vec<String> },
+    /// `{"range":{"field":{"gte":"value"}}}`, where the value can be a number or a date in ES format
+    Range { field: String, gte: String },
+    /// `{"bool":{"must":[...],"should":[...],"filter":[...],"must_not":[...]}}`
+    Bool(BoolQuery),
+}
+
+impl Query {
+    pub fn match_field(field: &str, query: &str) -> Self {
+        Query::Match {
+            field: field.to_owned(),
+            query: query.to_owned(),
+        }
+    }
+
+    pub fn multi_match(fields: &[&str], query: &str) -> Self {
+        Query::MultiMatch {
+            query: query.to_owned(),
+            fields: fields.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    pub fn match_phrase(field: &str, query: &str) -> Self {
+        Query::MatchPhrase {
+            field: field.to_owned(),
+            query: query.to_owned(),
+        }
+    }
+
+    pub fn range_gte(field: &str, gte: &str) -> Self {
+        Query::Range {
+            field: field.to_owned(),
+            gte: gte.to_owned(),
+        }
+    }
+
+    pub fn term(field: &str, value: &str) -> Self {
+        Query::Term {
+            field: field.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    pub fn terms(field: &str, values: &[String]) -> Self {
+        Query::Terms {
+            field: field.to_owned(),
+            values: values.to_vec(),
+        }
+    }
+}
+
+impl Serialize for Query {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        match self {
+            Query::MatchAll => {
+                map.serialize_entry("match_all", &BTreeMap::<String, String>::new())?
+            }
+            Query::Match { field, query } => {
+                map.serialize_entry("match", &Single(field, &Single("query", query)))?
+            }
+            Query::MultiMatch { query, fields } => {
+                #[derive(Serialize)]
+                struct MultiMatch<'a> {
+                    query: &'a String,
+                    fields: &'a Vec<String>,
+                }
+                map.serialize_entry("multi_match", &MultiMatch { query, fields })?
+            }
+            Query::MatchPhrase { field, query } => {
+                map.serialize_entry("match_phrase", &Single(field, &Single("query", query)))?
+            }
+            Query::Term { field, value } => {
+                map.serialize_entry("term", &Single(field, &Single("value", value)))?
+            }
+            Query::Terms { field, values } => {
+                map.serialize_entry("terms", &Single(field, values))?
+            }
+            Query::Range { field, gte } => {
+                map.serialize_entry("range", &Single(field, &Single("gte", gte)))?
+            }
+            Query::Bool(bool_query) => map.serialize_entry("bool", bool_query)?,
+        }
+        map.end()
+    }
+}
+
+/// Compound query clauses. Empty lists are omitted from the output.
+#[derive(Serialize, Default)]
+pub(crate) struct BoolQuery {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub must: Vec<Query>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub should: Vec<Query>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filter: Vec<Query>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub must_not: Vec<Query>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimum_should_match: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Serializes as `{"field":{"order":"desc"}}`
+pub(crate) struct Sort {
+    field: String,
+    order: SortOrder,
+}
+
+impl Serialize for Sort {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Single(&self.field, &Single("order", &self.order)).serialize(serializer)
+    }
+}
+
+/// Supported aggregation types.
+pub(crate) enum Aggregation {
+    /// `{"terms":{"field":"f","size":50,"include":"regex"}}`
+    Terms {
+        field: String,
+        size: usize,
+        include: Option<String>,
+    },
+}
+
+impl Aggregation {
+    pub fn terms(field: &str, size: usize, include: Option<String>) -> Self {
+        Aggregation::Terms {
+            field: field.to_owned(),
+            size,
+            include,
+        }
+    }
+}
+
+impl Serialize for Aggregation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Aggregation::Terms {
+                field,
+                size,
+                include,
+            } => {
+                #[derive(Serialize)]
+                struct Terms<'a> {
+                    field: &'a String,
+                    size: &'a usize,
+                    #[serde(skip_serializing_if = "Option::is_none")]
+                    include: Option<&'a String>,
+                }
+                Single(
+                    "terms",
+                    &Terms {
+                        field,
+                        size,
+                        include: include.as_ref(),
+                    },
+                )
+                .serialize(serializer)
+            }
+        }
+    }
+}
+
+/// Supported suggester types.
+pub(crate) enum Suggester {
+    /// `{"text":"tokoi","term":{"field":"f","suggest_mode":"always","size":3}}`
+    Term {
+        text: String,
+        field: String,
+        size: usize,
+    },
+}
+
+impl Suggester {
+    /// Suggests terms from the field that are within the edit distance of the text, even if the text is in the field.
+    pub fn term(text: &str, field: &str, size: usize) -> Self {
+        Suggester::Term {
+            text: text.to_owned(),
+            field: field.to_owned(),
+            size,
+        }
+    }
+}
+
+impl Serialize for Suggester {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Suggester::Term { text, field, size } => {
+                #[derive(Serialize)]
+                struct Term<'a> {
+                    field: &'a String,
+                    suggest_mode: &'a str,
+                    size: &'a usize,
+                }
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("text", text)?;
+                map.serialize_entry(
+                    "term",
+                    &Term {
+                        field,
+                        suggest_mode: "always",
+                        size,
+                    },
+                )?;
+                map.end()
+            }
+        }
+    }
+}
+
+/// A JSON object with a single member, e.g. `{"key":value}`. ES DSL is full of these.
+struct Single<'a, K: ?Sized, V: ?Sized>(&'a K, &'a V);
+
+impl<'a, K: Serialize + ?Sized, V: Serialize + ?Sized> Serialize for Single<'a, K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(self.0, self.1)?;
+        map.end()
+    }
+}
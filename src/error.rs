@@ -0,0 +1,81 @@
+use std::fmt;
+
+/// All errors that can happen while processing a request.
+/// Each variant maps to its own HTTP status and caching policy so that the user gets an error page
+/// instead of APIGW 502 when something goes wrong.
+#[derive(Debug)]
+pub(crate) enum StmError {
+    /// The request or one of its params is invalid, e.g. an unsafe doc ID.
+    Validation(String),
+    /// The search cannot be understood. The message tells the user how to fix it.
+    InvalidSearch(&'static str),
+    /// ElasticSearch could not be reached or returned an error.
+    Es(String),
+    /// A response from ES or a request payload could not be (de)serialized.
+    Deserialization(String),
+    /// Tera failed to load or render a template.
+    Template(String),
+    /// Missing or invalid config, e.g. env vars or AWS credentials.
+    Config(String),
+}
+
+impl StmError {
+    /// HTTP response code for the error page
+    pub fn http_resp_code(&self) -> u32 {
+        match self {
+            StmError::Validation(_) | StmError::InvalidSearch(_) => 400,
+            StmError::Es(_) => 503,
+            StmError::Deserialization(_) => 502,
+            StmError::Template(_) | StmError::Config(_) => 500,
+        }
+    }
+
+    /// Time to live for the error page. ES outages should be retried by CloudFront soon after,
+    /// but invalid requests will not get any better.
+    pub fn ttl(&self) -> u32 {
+        match self {
+            StmError::Validation(_) | StmError::InvalidSearch(_) => 3600,
+            StmError::Es(_) | StmError::Deserialization(_) => 10,
+            StmError::Template(_) | StmError::Config(_) => 60,
+        }
+    }
+
+    /// A short message for the user. The details are logged, but not shown, except for search errors.
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            StmError::Validation(_) => "Invalid request",
+            StmError::InvalidSearch(e) => e,
+            StmError::Es(_) | StmError::Deserialization(_) => {
+                "Our search engine is temporarily unavailable"
+            }
+            StmError::Template(_) | StmError::Config(_) => "Something went wrong on our side",
+        }
+    }
+}
+
+impl fmt::Display for StmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StmError::Validation(e) => write!(f, "Validation error: {}", e),
+            StmError::InvalidSearch(e) => write!(f, "Invalid search: {}", e),
+            StmError::Es(e) => write!(f, "ES error: {}", e),
+            StmError::Deserialization(e) => write!(f, "Deserialization error: {}", e),
+            StmError::Template(e) => write!(f, "Template error: {}", e),
+            StmError::Config(e) => write!(f, "Config error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StmError {}
+
+impl From<serde_json::Error> for StmError {
+    fn from(e: serde_json::Error) -> Self {
+        StmError::Deserialization(e.to_string())
+    }
+}
+
+impl From<tera::Error> for StmError {
+    fn from(e: tera::Error) -> Self {
+        StmError::Template(e.to_string())
+    }
+}
@@ -1,43 +1,153 @@
-use crate::{config::Config, html, Error};
+use crate::cache::{CacheStatus, PageCache};
+use crate::config::{Config, SecurityHeadersConfig};
+use crate::html::html_data::HtmlData;
+use crate::{error::StmError, html, rate_limit, security, Error};
+use chrono::{DateTime, Utc};
 use lambda_runtime::Context;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
 use tera::Tera;
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 use urlencoding::decode;
 
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct ApiGatewayResponse {
+pub(crate) struct ApiGatewayResponse {
     // #[serde(skip_serializing_if = "Option::is_none")]
     // cookies: Option<Vec<String>>,
-    is_base64_encoded: bool,
-    status_code: u32,
-    headers: HashMap<String, String>,
-    body: String,
+    pub is_base64_encoded: bool,
+    pub status_code: u32,
+    pub headers: HashMap<String, String>,
+    pub body: String,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct ApiGatewayRequest {
-    raw_path: String,
-    raw_query_string: String,
-    headers: HashMap<String, String>,
+pub(crate) struct ApiGatewayRequest {
+    pub raw_path: String,
+    pub raw_query_string: String,
+    pub headers: HashMap<String, String>,
+    /// Requests from older proxy messages may have no context and are treated as GET
+    #[serde(default)]
+    pub request_context: RequestContext,
+}
+
+/// A subset of APIGW `requestContext` member
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct RequestContext {
+    #[serde(default)]
+    pub http: HttpContext,
+}
+
+/// A subset of APIGW `requestContext.http` member
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HttpContext {
+    /// The HTTP method in upper case, e.g. `GET`
+    pub method: String,
+    /// The IP of the caller, which is CloudFront if the request came via CloudFront
+    #[serde(default)]
+    pub source_ip: String,
+}
+
+impl Default for HttpContext {
+    fn default() -> Self {
+        Self {
+            method: "GET".to_owned(),
+            source_ip: String::new(),
+        }
+    }
 }
 
 #[derive(RustEmbed)]
 #[folder = "templates"]
 struct Asset;
 
-//pub(crate) async fn my_handler(event: Value, _ctx: Context) -> Result<Value, Error> {
-pub(crate) async fn my_handler(event: Value, _ctx: Context) -> Result<Value, Error> {
+/// Process-wide state initialised once per container or server and shared by all requests
+pub(crate) struct SharedState {
+    /// Validated config with the ES backend that keeps connections and credentials between requests
+    pub config: Config,
+    /// All templates compiled once on start-up
+    pub tera: Tera,
+    /// Page data shared by all visitors
+    pub cache: PageCache,
+}
+
+impl SharedState {
+    /// Reads the config and compiles the templates. Returns an error if either of them is invalid.
+    pub(crate) fn new() -> Result<Self, StmError> {
+        let config = Config::new()?;
+        let tera = tera_init()?;
+        info!("Shared state initialised");
+
+        Ok(Self {
+            config,
+            tera,
+            cache: PageCache::default(),
+        })
+    }
+}
+
+const CONTENT_TYPE_HTML: &str = "text/html";
+const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_XML: &str = "application/xml";
+const CONTENT_TYPE_TEXT: &str = "text/plain";
+const CONTENT_TYPE_ATOM: &str = "application/atom+xml";
+/// Tells if the page came from the cache: `HIT`, `STALE` or `MISS`
+const CACHE_STATUS_HEADER: &str = "X-Cache";
+/// Smaller responses are not compressed because the saving is too small to be worth the CPU time
+const MIN_COMPRESSED_BODY_SIZE: usize = 1024;
+/// Brotli quality from 0 to 11. Higher levels are too slow for compressing pages on every request.
+const BROTLI_QUALITY: u32 = 5;
+/// Brotli window size as a power of 2, the default recommended by the brotli crate
+const BROTLI_LG_WINDOW_SIZE: u32 = 22;
+/// HTTP-date format for `Last-Modified` and `If-Modified-Since` headers, e.g. `Sun, 21 Mar 2021 03:12:49 GMT`
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+/// The value of `Allow` header for OPTIONS and 405 responses
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+/// Any page can be requested as JSON by adding this suffix to the path, e.g. `/rimutaka.json` or `/index.json?rust`
+const JSON_PATH_SUFFIX: &str = ".json";
+
+pub(crate) async fn my_handler(
+    event: Value,
+    _ctx: Context,
+    state: &Arc<SharedState>,
+) -> Result<Value, Error> {
     //info!("Event: {}", event);
     //info!("Context: {:?}", ctx);
 
-    let api_request =
-        serde_json::from_value::<ApiGatewayRequest>(event).expect("Failed to deser APIGW request");
+    let resp = match serde_json::from_value::<ApiGatewayRequest>(event) {
+        Ok(v) => handle_request(v, state).await?,
+        Err(e) => {
+            let e = StmError::from(e);
+            error!("Failed to deser APIGW request: {}", e);
+            gw_response(
+                "Bad Request".to_owned(),
+                400,
+                0,
+                CONTENT_TYPE_HTML,
+                &state.config.security_headers,
+                None,
+            )?
+        }
+    };
+
+    Ok(serde_json::to_value(resp).expect("Failed to serialize response"))
+}
+
+/// Processes a single request in APIGW format regardless of where it came from:
+/// Lambda, the SQS proxy or the standalone HTTP server.
+pub(crate) async fn handle_request(
+    api_request: ApiGatewayRequest,
+    state: &Arc<SharedState>,
+) -> Result<ApiGatewayResponse, Error> {
+    let started = Instant::now();
 
     // if Authorization env var is present check if it matches Authorization header
     // this is done for basic protection against direct calls to the api bypassing CloudFront
@@ -49,55 +159,332 @@ pub(crate) async fn my_handler(event: Value, _ctx: Context) -> Result<Value, Err
 
         if auth_var != auth_header {
             warn!("Unauthorized. Header: {}", auth_header);
-            return gw_response("Unauthorized".to_owned(), 403, 3600);
+            return gw_response(
+                "Unauthorized".to_owned(),
+                403,
+                3600,
+                CONTENT_TYPE_HTML,
+                &state.config.security_headers,
+                None,
+            );
         }
     } else {
         #[cfg(debug_assertions)]
         warn!("No Authorization env var - all requests are allowed");
     };
 
-    // get ElasticSearch URL and index names from env vars
-    let config = Config::new();
-
-    let tera = tera_init()?;
+    // only GET and HEAD get a page, anything else is answered here without going to ES
+    let method = api_request.request_context.http.method.to_uppercase();
+    info!("Method: {}", method);
+    match method.as_str() {
+        "GET" | "HEAD" => {}
+        "OPTIONS" => return allow_response(String::new(), 204, state),
+        _ => {
+            warn!("Method not allowed: {}", method);
+            return allow_response("Method Not Allowed".to_owned(), 405, state);
+        }
+    }
+    let head_requested = method == "HEAD";
 
-    // decode possible URL path and query string
+    // decode possible URL path
+    // the query string is decoded after it is split into params, so that an encoded `&` or `=` stays inside the value
     info!("Path: {}", &api_request.raw_path);
     info!("Query: {}", &api_request.raw_query_string);
     let url_path = decode(&api_request.raw_path).unwrap_or_default();
-    let url_query = decode(&api_request.raw_query_string).unwrap_or_default();
+    let url_query = api_request.raw_query_string.clone();
     info!("Path: {}", url_path);
-    info!("Query: {}", url_query);
-
-    // send the user request downstream for processing
-    let html_data = html::html(&config, url_path, url_query)
-        .await
-        .expect("html() failed");
-
-    // render the prepared data as HTML
-    let html = tera
-        .render(
-            &html_data.template_name,
-            &tera::Context::from_value(
-                serde_json::to_value(&html_data).expect("Failed to serialize html_data"),
-            )
-            .expect("Cannot serialize: tera::Context::from_value"),
-        )
-        .expect("Cannot render");
-    info!("Rendered");
+
+    // API clients get the same data as JSON instead of HTML
+    let (url_path, json_requested) = json_requested(url_path, &api_request.headers);
+
+    // a single client should not be able to use up all Lambda instances
+    if let Some(retry_after) = rate_limit::check(
+        &state.config.rate_limit,
+        &url_path,
+        &api_request.headers,
+        &api_request.request_context.http.source_ip,
+    )
+    .await
+    {
+        let html_data = html::too_many_requests_html(url_query);
+        let mut resp = to_gw_response(state, &html_data, json_requested)?;
+        resp.headers
+            .insert("Retry-After".to_owned(), retry_after.to_string());
+        return Ok(if head_requested {
+            without_body(resp)
+        } else {
+            resp
+        });
+    }
+
+    // the same pages are served to all visitors from the cache, see `cache` module for details
+    let cache_key = PageCache::key(&url_path, &url_query);
+    let (html_data, cache_status) = match state.cache.get(&cache_key) {
+        (Some(html_data), CacheStatus::Stale) => {
+            // the stale copy is returned straight away and the next request gets a fresh one
+            let state = state.clone();
+            let cache_key = cache_key.clone();
+            tokio::spawn(async move {
+                fetch_page(&state, cache_key.clone(), url_path, url_query).await;
+                info!("Refreshed {}", cache_key);
+            });
+            (html_data, CacheStatus::Stale)
+        }
+        (Some(html_data), cache_status) => (html_data, cache_status),
+        (None, cache_status) => (
+            fetch_page(state, cache_key.clone(), url_path, url_query).await,
+            cache_status,
+        ),
+    };
+    info!(
+        "Cache {} for {}, data ready in {}ms",
+        cache_status.as_str(),
+        cache_key,
+        started.elapsed().as_millis()
+    );
+
+    let mut resp = to_gw_response(state, &html_data, json_requested)?;
+    info!("Rendered in {}ms", started.elapsed().as_millis());
+
+    resp.headers.insert(
+        CACHE_STATUS_HEADER.to_owned(),
+        cache_status.as_str().to_owned(),
+    );
+    if let Some(last_modified) = html_data.last_modified {
+        resp.headers.insert(
+            "Last-Modified".to_owned(),
+            last_modified.format(HTTP_DATE_FORMAT).to_string(),
+        );
+    }
+
+    // compression changes the ETag, so it has to be done before checking if the client has the same copy
+    let mut resp = compress(resp, &api_request.headers);
+
+    // the client already has the same page
+    if is_not_modified(&api_request.headers, &resp, html_data.last_modified) {
+        info!("Not modified");
+        resp.headers.remove("Content-Encoding");
+        // a new CSP nonce would not match the nonce in the cached copy of the page
+        resp.headers.remove(security::CSP_HEADER);
+        resp.headers.remove(security::CSP_REPORT_ONLY_HEADER);
+        return Ok(ApiGatewayResponse {
+            status_code: 304,
+            is_base64_encoded: false,
+            body: String::new(),
+            ..resp
+        });
+    }
+
+    // HEAD gets the same headers as GET would, but no body
+    if head_requested {
+        return Ok(without_body(resp));
+    }
+
+    Ok(resp)
+}
+
+/// Returns an empty or a plain text response with `Allow` header listing the supported methods,
+/// e.g. for OPTIONS (204) or an unsupported method (405).
+fn allow_response(
+    body: String,
+    status_code: u32,
+    state: &SharedState,
+) -> Result<ApiGatewayResponse, Error> {
+    let mut resp = gw_response(
+        body,
+        status_code,
+        3600,
+        CONTENT_TYPE_HTML,
+        &state.config.security_headers,
+        None,
+    )?;
+    resp.headers
+        .insert("Allow".to_owned(), ALLOWED_METHODS.to_owned());
+
+    Ok(resp)
+}
+
+/// Drops the body for a HEAD request. `Content-Length` is set to the length of the body GET would return,
+/// which is the decoded length for compressed bodies.
+fn without_body(resp: ApiGatewayResponse) -> ApiGatewayResponse {
+    let body_len = if resp.is_base64_encoded {
+        match base64::decode(&resp.body) {
+            Ok(v) => v.len(),
+            Err(e) => {
+                error!("Invalid base64 body: {}", e);
+                0
+            }
+        }
+    } else {
+        resp.body.len()
+    };
+
+    let mut headers = resp.headers;
+    headers.insert("Content-Length".to_owned(), body_len.to_string());
+
+    ApiGatewayResponse {
+        is_base64_encoded: false,
+        headers,
+        body: String::new(),
+        ..resp
+    }
+}
+
+/// Sends the user request downstream for processing and caches the result if it can be cached.
+/// Any error is returned as an error page with its own status code, which is not cached.
+async fn fetch_page(
+    state: &SharedState,
+    cache_key: String,
+    url_path: String,
+    url_query: String,
+) -> Arc<HtmlData> {
+    match html::html(&state.config, url_path, url_query.clone()).await {
+        Ok(v) => {
+            let html_data = Arc::new(v);
+            state.cache.insert(cache_key, html_data.clone());
+            html_data
+        }
+        Err(e) => {
+            state.cache.refresh_failed(&cache_key);
+            Arc::new(html::error_html(&e, url_query))
+        }
+    }
+}
+
+/// Converts the data into JSON or renders it as HTML.
+fn to_gw_response(
+    state: &SharedState,
+    html_data: &HtmlData,
+    json_requested: bool,
+) -> Result<ApiGatewayResponse, Error> {
+    let security_headers = &state.config.security_headers;
+    let content_type = content_type(&html_data.template_name);
+
+    // JSON needs no templates, but sitemaps and robots.txt are never converted into JSON
+    if json_requested && content_type == CONTENT_TYPE_HTML {
+        return match html::json_doc::to_json(html_data) {
+            Ok(v) => gw_response(
+                v,
+                html_data.http_resp_code,
+                html_data.ttl,
+                CONTENT_TYPE_JSON,
+                security_headers,
+                None,
+            ),
+            Err(e) => {
+                error!("Cannot convert {} to JSON: {}", html_data.template_name, e);
+                gw_response(
+                    e.user_message().to_owned(),
+                    e.http_resp_code(),
+                    e.ttl(),
+                    CONTENT_TYPE_HTML,
+                    security_headers,
+                    None,
+                )
+            }
+        };
+    }
+
+    // render the prepared data as HTML, XML or plain text
+    // inline scripts are allowed by CSP only if they have this nonce
+    let csp_nonce = security::new_nonce();
+    let html = match render(&state.tera, html_data, &csp_nonce) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Cannot render {}: {}", html_data.template_name, e);
+            return gw_response(
+                e.user_message().to_owned(),
+                e.http_resp_code(),
+                e.ttl(),
+                CONTENT_TYPE_HTML,
+                security_headers,
+                None,
+            );
+        }
+    };
+
+    // only HTML pages have inline scripts
+    let csp_nonce = if content_type == CONTENT_TYPE_HTML {
+        Some(csp_nonce.as_str())
+    } else {
+        None
+    };
 
     // return back the result
-    gw_response(html, html_data.http_resp_code, html_data.ttl)
+    gw_response(
+        html,
+        html_data.http_resp_code,
+        html_data.ttl,
+        content_type,
+        security_headers,
+        csp_nonce,
+    )
+}
+
+/// Returns the content type for the template by its extension, e.g. `sitemap.xml` is XML.
+fn content_type(template_name: &str) -> &'static str {
+    if template_name.ends_with(".xml") {
+        CONTENT_TYPE_XML
+    } else if template_name.ends_with(".atom") {
+        CONTENT_TYPE_ATOM
+    } else if template_name.ends_with(".txt") {
+        CONTENT_TYPE_TEXT
+    } else {
+        CONTENT_TYPE_HTML
+    }
+}
+
+/// Returns the path without `.json` suffix and `true` if the client asked for JSON with the suffix
+/// or via `Accept: application/json` header. `/index.json` is the same as `/`.
+fn json_requested(url_path: String, headers: &HashMap<String, String>) -> (String, bool) {
+    if let Some(path) = url_path.strip_suffix(JSON_PATH_SUFFIX) {
+        let path = path.trim_end_matches("/index");
+        let path = if path.is_empty() { "/" } else { path };
+        return (path.to_owned(), true);
+    }
+
+    let accepts_json = match headers.get("accept") {
+        Some(v) => v.contains(CONTENT_TYPE_JSON),
+        None => false,
+    };
+
+    (url_path, accepts_json)
 }
 
-/// Prepares the response with the status and HTML body. May fail and return an error.
-fn gw_response(body: String, status_code: u32, ttl: u32) -> Result<Value, Error> {
+/// Prepares the response with the status and the body of the specified type. May fail and return an error.
+/// `csp_nonce` is the nonce of inline scripts in the body, if any.
+fn gw_response(
+    body: String,
+    status_code: u32,
+    ttl: u32,
+    content_type: &str,
+    security_headers: &SecurityHeadersConfig,
+    csp_nonce: Option<&str>,
+) -> Result<ApiGatewayResponse, Error> {
     let mut headers: HashMap<String, String> = HashMap::new();
-    headers.insert("Content-Type".to_owned(), "text/html".to_owned());
+    headers.insert("Content-Type".to_owned(), content_type.to_owned());
     headers.insert(
         "Cache-Control".to_owned(),
         ["max-age=".to_owned(), ttl.to_string()].concat(),
     );
+    // the same URL may return HTML or JSON depending on Accept header, compressed or not
+    headers.insert("Vary".to_owned(), "Accept, Accept-Encoding".to_owned());
+    // a strong ETag for conditional requests, which changes with any change to the body
+    // the nonce is different every time, so it's excluded to get the same ETag for the same page
+    let etag_body = match csp_nonce {
+        Some(v) => body.replace(v, ""),
+        None => body.clone(),
+    };
+    headers.insert(
+        "ETag".to_owned(),
+        [
+            "\"",
+            &format!("{:x}", Sha256::digest(etag_body.as_bytes())),
+            "\"",
+        ]
+        .concat(),
+    );
+    security::add_headers(&mut headers, security_headers, csp_nonce);
 
     let resp = ApiGatewayResponse {
         is_base64_encoded: false,
@@ -106,23 +493,194 @@ fn gw_response(body: String, status_code: u32, ttl: u32) -> Result<Value, Error>
         body,
     };
 
-    Ok(serde_json::to_value(resp).expect("Failed to serialize response"))
+    Ok(resp)
+}
+
+/// Content encodings for compressing responses
+#[derive(Clone, Copy, Debug)]
+enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentEncoding {
+    /// Returns the name of the encoding for `Content-Encoding` header.
+    fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+
+    /// Compresses the bytes with this encoding.
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            ContentEncoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(
+                    Vec::new(),
+                    4096,
+                    BROTLI_QUALITY,
+                    BROTLI_LG_WINDOW_SIZE,
+                );
+                writer.write_all(bytes)?;
+                Ok(writer.into_inner())
+            }
+            ContentEncoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Returns the encoding the client prefers as per `Accept-Encoding` header, e.g. `gzip, deflate, br`.
+/// Brotli is preferred over gzip if both are accepted. Encodings with `q=0` are not accepted.
+fn accepted_encoding(req_headers: &HashMap<String, String>) -> Option<ContentEncoding> {
+    let accepted = req_headers
+        .get("accept-encoding")?
+        .split(',')
+        .filter_map(|v| {
+            let mut parts = v.split(';');
+            let name = parts.next()?.trim().to_lowercase();
+            let rejected = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            if rejected {
+                None
+            } else {
+                Some(name)
+            }
+        })
+        .collect::<Vec<String>>();
+
+    if accepted.iter().any(|v| v == "br") {
+        Some(ContentEncoding::Brotli)
+    } else if accepted.iter().any(|v| v == "gzip") {
+        Some(ContentEncoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Compresses the body if the client accepts compressed responses and the body is large enough.
+/// The compressed body is base64-encoded for APIGW. The response is returned as-is if it cannot be compressed.
+fn compress(resp: ApiGatewayResponse, req_headers: &HashMap<String, String>) -> ApiGatewayResponse {
+    if resp.is_base64_encoded || resp.body.len() < MIN_COMPRESSED_BODY_SIZE {
+        return resp;
+    }
+    let encoding = match accepted_encoding(req_headers) {
+        Some(v) => v,
+        None => return resp,
+    };
+
+    let compressed = match encoding.encode(resp.body.as_bytes()) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Cannot compress the body with {:?}: {}", encoding, e);
+            return resp;
+        }
+    };
+    info!(
+        "Compressed {}B to {}B with {}",
+        resp.body.len(),
+        compressed.len(),
+        encoding.as_str()
+    );
+
+    let mut headers = resp.headers;
+    headers.insert("Content-Encoding".to_owned(), encoding.as_str().to_owned());
+    // a strong ETag must be different for every encoding of the same page, e.g. `"abc...-br"`
+    if let Some(etag) = headers.get_mut("ETag") {
+        *etag = [etag.trim_end_matches('"'), "-", encoding.as_str(), "\""].concat();
+    }
+
+    ApiGatewayResponse {
+        is_base64_encoded: true,
+        headers,
+        body: base64::encode(compressed),
+        ..resp
+    }
+}
+
+/// Returns true if the response is a 200 and the client has the same copy as per `If-None-Match`
+/// or `If-Modified-Since` headers. `If-Modified-Since` is ignored if `If-None-Match` is present.
+fn is_not_modified(
+    req_headers: &HashMap<String, String>,
+    resp: &ApiGatewayResponse,
+    last_modified: Option<DateTime<Utc>>,
+) -> bool {
+    if resp.status_code != 200 {
+        return false;
+    }
+
+    // e.g. `"abc..."`, `W/"abc...", "def..."` or `*`
+    if let Some(if_none_match) = req_headers.get("if-none-match") {
+        let etag = match resp.headers.get("ETag") {
+            Some(v) => v,
+            None => return false,
+        };
+        return if_none_match
+            .split(',')
+            .map(|v| v.trim().trim_start_matches("W/"))
+            .any(|v| v == "*" || v == etag);
+    }
+
+    // HTTP dates have no fractions of a second, so only whole seconds are compared
+    match (req_headers.get("if-modified-since"), last_modified) {
+        (Some(if_modified_since), Some(last_modified)) => {
+            match DateTime::parse_from_rfc2822(if_modified_since) {
+                Ok(v) => last_modified.timestamp() <= v.timestamp(),
+                Err(_) => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Renders the data with the template specified in it. The nonce goes into `nonce` attribute of inline scripts.
+/// An empty nonce leaves the attribute out, e.g. for pre-rendered pages with hash-based CSP.
+pub(crate) fn render(
+    tera: &Tera,
+    html_data: &HtmlData,
+    csp_nonce: &str,
+) -> Result<String, StmError> {
+    let mut context = tera::Context::from_value(serde_json::to_value(html_data)?)?;
+    context.insert("csp_nonce", csp_nonce);
+
+    Ok(tera.render(&html_data.template_name, &context)?)
 }
 
 /// Init Tera instance and load all HTML templates either from the file system
 /// (debug) or the binary (release).
-fn tera_init() -> Result<Tera, Error> {
+fn tera_init() -> Result<Tera, StmError> {
     let mut tera = Tera::default();
+    // Atom feeds are XML and need escaping same as the default .html and .xml
+    tera.autoescape_on(vec![".html", ".htm", ".xml", ".atom"]);
 
     // loads the files from the fs or embedded strings
     // see https://github.com/pyros2097/rust-embed
     for file in Asset::iter() {
         let file: &str = &file;
-        let content = Asset::get(file).expect("Cannot de-asset HTML");
-        let content = std::str::from_utf8(content.as_ref()).expect("Cannot convert HTML for str");
+        let content = match Asset::get(file) {
+            Some(v) => v,
+            None => {
+                return Err(StmError::Template(["Cannot de-asset ", file].concat()));
+            }
+        };
+        let content = match std::str::from_utf8(content.as_ref()) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(StmError::Template(e.to_string()));
+            }
+        };
 
-        tera.add_raw_template(file, content)
-            .expect("Cannot add raw template");
+        tera.add_raw_template(file, content)?;
     }
 
     Ok(tera)
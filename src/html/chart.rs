@@ -0,0 +1,328 @@
+//! Inline SVG charts for time series, e.g. job counts on the stats page. They are rendered on the server
+//! and need no JS. Every data point has a `<title>` that browsers show as a tooltip on hover.
+
+use chrono::{TimeZone, Utc};
+
+const WIDTH: f64 = 600.0;
+const HEIGHT: f64 = 260.0;
+/// The plot area leaves room for y-axis labels on the left and x-axis labels with the legend at the bottom
+const PLOT_LEFT: f64 = 56.0;
+const PLOT_RIGHT: f64 = WIDTH - 12.0;
+const PLOT_TOP: f64 = 12.0;
+const PLOT_BOTTOM: f64 = HEIGHT - 56.0;
+/// Number of intervals between horizontal grid lines
+const Y_TICKS: usize = 5;
+/// Max number of timestamps under the x-axis. More would overlap.
+const MAX_X_LABELS: usize = 5;
+const LEGEND_ITEM_WIDTH: f64 = 130.0;
+/// Bootstrap colors, so the charts match the rest of the page
+const COLORS: [&str; 6] = [
+    "#0d6efd", "#dc3545", "#198754", "#fd7e14", "#6f42c1", "#20c997",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ChartType {
+    /// Time-scaled x-axis, good for trends
+    Line,
+    /// Bars for every series grouped by timestamp
+    Bar,
+}
+
+/// A named list of values over time
+pub(crate) struct Series {
+    /// A short name for the legend, e.g. `In flight`
+    pub name: String,
+    /// Unix timestamps in seconds with their values in any order
+    pub points: Vec<(i64, f64)>,
+}
+
+/// Returns an SVG document for embedding into HTML. The title is for screen readers.
+/// The unit is appended to values in tooltips, e.g. ` sec`.
+pub(crate) fn svg(chart_type: ChartType, title: &str, unit: &str, series: &[Series]) -> String {
+    let mut svg = vec![format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {} {}" width="100%" role="img" aria-label="{}" font-family="sans-serif" font-size="11">"#,
+        WIDTH,
+        HEIGHT,
+        escape(title)
+    )];
+
+    // all distinct timestamps in order, which are also the groups of bars
+    let mut timestamps = series
+        .iter()
+        .flat_map(|s| s.points.iter().map(|(ts, _)| *ts))
+        .collect::<Vec<i64>>();
+    timestamps.sort_unstable();
+    timestamps.dedup();
+
+    if timestamps.is_empty() {
+        svg.push(format!(
+            r##"<text x="{}" y="{}" text-anchor="middle" fill="#6c757d">No data</text></svg>"##,
+            WIDTH / 2.0,
+            HEIGHT / 2.0
+        ));
+        return svg.concat();
+    }
+
+    let y_max = nice_max(
+        series
+            .iter()
+            .flat_map(|s| s.points.iter().map(|(_, v)| *v))
+            .fold(0.0, f64::max),
+    );
+    let y = |value: f64| PLOT_BOTTOM - value / y_max * (PLOT_BOTTOM - PLOT_TOP);
+
+    // lines are placed by time, bars are in equal bands in the order of their timestamps
+    let band = (PLOT_RIGHT - PLOT_LEFT) / timestamps.len() as f64;
+    let (first, last) = (timestamps[0], timestamps[timestamps.len() - 1]);
+    let x = |ts: i64| match chart_type {
+        ChartType::Bar => {
+            PLOT_LEFT + band * (timestamps.binary_search(&ts).unwrap_or_default() as f64 + 0.5)
+        }
+        ChartType::Line if first == last => (PLOT_LEFT + PLOT_RIGHT) / 2.0,
+        ChartType::Line => {
+            PLOT_LEFT + (ts - first) as f64 / (last - first) as f64 * (PLOT_RIGHT - PLOT_LEFT)
+        }
+    };
+
+    // horizontal grid lines with the y-axis labels
+    for i in 0..=Y_TICKS {
+        let value = y_max * i as f64 / Y_TICKS as f64;
+        svg.push(format!(
+            r##"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="#dee2e6"/><text x="{:.1}" y="{:.1}" text-anchor="end" fill="#6c757d">{}</text>"##,
+            PLOT_LEFT,
+            y(value),
+            PLOT_RIGHT,
+            y(value),
+            PLOT_LEFT - 6.0,
+            y(value) + 4.0,
+            format_value(value)
+        ));
+    }
+
+    // x-axis with a few timestamps spread evenly
+    svg.push(format!(
+        r##"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="#6c757d"/>"##,
+        PLOT_LEFT, PLOT_BOTTOM, PLOT_RIGHT, PLOT_BOTTOM
+    ));
+    let label_step = timestamps.len().div_ceil(MAX_X_LABELS);
+    for ts in timestamps.iter().step_by(label_step) {
+        svg.push(format!(
+            r##"<text x="{:.1}" y="{:.1}" text-anchor="middle" fill="#6c757d">{}</text>"##,
+            x(*ts),
+            PLOT_BOTTOM + 16.0,
+            time_label(*ts)
+        ));
+    }
+
+    for (i, s) in series.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let mut points = s.points.clone();
+        points.sort_by_key(|(ts, _)| *ts);
+
+        // every point gets a tooltip, e.g. `In flight: 15 at 04/29 10:32`
+        let tooltip = |ts: i64, value: f64| {
+            [
+                "<title>",
+                &escape(&s.name),
+                ": ",
+                &format_value(value),
+                &escape(unit),
+                " at ",
+                &time_label(ts),
+                "</title>",
+            ]
+            .concat()
+        };
+
+        match chart_type {
+            ChartType::Line => {
+                svg.push(format!(
+                    r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="2"/>"#,
+                    points
+                        .iter()
+                        .map(|(ts, value)| format!("{:.1},{:.1}", x(*ts), y(*value)))
+                        .collect::<Vec<String>>()
+                        .join(" "),
+                    color
+                ));
+                for (ts, value) in &points {
+                    svg.push(format!(
+                        r#"<circle cx="{:.1}" cy="{:.1}" r="3" fill="{}">{}</circle>"#,
+                        x(*ts),
+                        y(*value),
+                        color,
+                        tooltip(*ts, *value)
+                    ));
+                }
+            }
+            ChartType::Bar => {
+                // bars take up 80% of the band, which leaves a gap between the groups
+                let bar_width = band * 0.8 / series.len() as f64;
+                for (ts, value) in &points {
+                    svg.push(format!(
+                        r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}">{}</rect>"#,
+                        x(*ts) - band * 0.4 + bar_width * i as f64,
+                        y(*value),
+                        bar_width,
+                        PLOT_BOTTOM - y(*value),
+                        color,
+                        tooltip(*ts, *value)
+                    ));
+                }
+            }
+        }
+
+        // legend
+        let legend_x = PLOT_LEFT + LEGEND_ITEM_WIDTH * i as f64;
+        svg.push(format!(
+            r#"<rect x="{:.1}" y="{:.1}" width="10" height="10" fill="{}"/><text x="{:.1}" y="{:.1}">{}</text>"#,
+            legend_x,
+            HEIGHT - 20.0,
+            color,
+            legend_x + 14.0,
+            HEIGHT - 11.0,
+            escape(&s.name)
+        ));
+    }
+
+    svg.push("</svg>".to_owned());
+    svg.concat()
+}
+
+/// Rounds the max value up to a round number on the same order of magnitude, e.g. 57 to 60, so that
+/// the grid lines are at round values too.
+fn nice_max(max: f64) -> f64 {
+    if max <= 0.0 {
+        return 1.0;
+    }
+
+    let magnitude = 10f64.powf(max.log10().floor());
+    let nice = [1.0, 2.0, 2.5, 3.0, 4.0, 5.0, 6.0, 8.0]
+        .iter()
+        .find(|v| max / magnitude <= **v)
+        .unwrap_or(&10.0);
+    nice * magnitude
+}
+
+/// Formats whole numbers without decimals and the rest with 1 decimal place, e.g. `15` or `0.4`.
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 {
+        format!("{:.0}", value)
+    } else {
+        format!("{:.1}", value)
+    }
+}
+
+/// Formats the timestamp the same way as in the stats tables, e.g. `04/29 10:32`.
+fn time_label(ts: i64) -> String {
+    match Utc.timestamp_opt(ts, 0).single() {
+        Some(v) => v.format("%m/%d %H:%M").to_string(),
+        None => ts.to_string(),
+    }
+}
+
+/// Escapes text for SVG elements and attributes.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2021-04-29 10:32 UTC
+    const TS: i64 = 1619692338;
+
+    fn series(name: &str, points: Vec<(i64, f64)>) -> Series {
+        Series {
+            name: name.to_owned(),
+            points,
+        }
+    }
+
+    /// Checks that the scaling did not produce any invalid coordinates.
+    fn assert_valid(svg: &str) {
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>"));
+        assert!(!svg.contains("NaN"));
+        assert!(!svg.contains("inf"));
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        // the SVG is inserted into the page as is
+        let chart = svg(
+            ChartType::Line,
+            r#"Jobs "<script>""#,
+            " <b>",
+            &[series("a<b>&c", vec![(TS, 1.0)])],
+        );
+        assert_valid(&chart);
+        assert!(!chart.contains("<script>"));
+        assert!(!chart.contains("<b>"));
+        assert!(chart.contains(r#"aria-label="Jobs &quot;&lt;script&gt;&quot;""#));
+        assert!(chart.contains("<title>a&lt;b&gt;&amp;c: 1 &lt;b&gt; at 04/29 10:32</title>"));
+        assert!(chart.contains(">a&lt;b&gt;&amp;c</text>"));
+    }
+
+    #[test]
+    fn empty_series() {
+        for chart_type in [ChartType::Line, ChartType::Bar] {
+            let chart = svg(chart_type, "Jobs", "", &[]);
+            assert_valid(&chart);
+            assert!(chart.contains("No data"));
+
+            let chart = svg(chart_type, "Jobs", "", &[series("Done", Vec::new())]);
+            assert_valid(&chart);
+            assert!(chart.contains("No data"));
+        }
+    }
+
+    #[test]
+    fn single_point() {
+        // in the middle of the plot area at the top of the scale
+        let chart = svg(
+            ChartType::Line,
+            "Jobs",
+            "",
+            &[series("Done", vec![(TS, 5.0)])],
+        );
+        assert_valid(&chart);
+        assert!(chart.contains(r##"<circle cx="322.0" cy="12.0" r="3" fill="#0d6efd">"##));
+
+        // a single bar across 80% of the plot area
+        let chart = svg(
+            ChartType::Bar,
+            "Jobs",
+            "",
+            &[series("Done", vec![(TS, 5.0)])],
+        );
+        assert_valid(&chart);
+        assert!(chart.contains(
+            r##"<rect x="109.2" y="12.0" width="425.6" height="192.0" fill="#0d6efd">"##
+        ));
+
+        // all zeros still have a scale
+        let chart = svg(
+            ChartType::Line,
+            "Jobs",
+            "",
+            &[series("Done", vec![(TS, 0.0)])],
+        );
+        assert_valid(&chart);
+        assert!(chart.contains(r##"<circle cx="322.0" cy="204.0" r="3""##));
+    }
+
+    #[test]
+    fn axis_scale() {
+        assert_eq!(nice_max(0.0), 1.0);
+        assert_eq!(nice_max(57.0), 60.0);
+        assert_eq!(nice_max(100.0), 100.0);
+        assert_eq!(nice_max(101.0), 200.0);
+        assert_eq!(format_value(12.0), "12");
+        assert_eq!(format_value(0.4), "0.4");
+    }
+}
@@ -0,0 +1,356 @@
+//! Several devs side by side, e.g. `/_compare?devs=rimutaka,dtolnay`, with their languages, shared and unique
+//! packages/refs, project counts and the dates of their first and last commits.
+
+use super::dev_doc::{Dev, DevSearchResponse, Tech};
+use super::html_data::{ComparedDev, ComparedKeywords, ComparedLang, Comparison, HtmlData};
+use super::{decode_query, split_query_string};
+use crate::config::Config;
+use crate::elastic;
+use crate::error::StmError;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+pub(crate) const COMPARE_PATH: &str = "/_compare";
+/// The query string param with a comma-separated list of logins, e.g. `devs=rimutaka,dtolnay`
+const DEVS_PARAM: &str = "devs";
+const MIN_DEVS: usize = 2;
+/// More than 4 devs do not fit on the screen side by side
+const MAX_DEVS: usize = 4;
+/// GitHub logins are up to 39 chars long
+const MAX_LOGIN_LEN: usize = 39;
+/// `devs=` with `MAX_DEVS` logins of `MAX_LOGIN_LEN` separated by a comma and an optional space
+const MAX_QUERY_LEN: usize = DEVS_PARAM.len() + 1 + MAX_DEVS * MAX_LOGIN_LEN + (MAX_DEVS - 1) * 2;
+/// Max number of keywords in a list of shared or unique packages/refs. The rest are only counted.
+const MAX_KEYWORDS_PER_LIST: usize = 50;
+
+/// Returns the compare page for 2 - 4 logins from the query string. A duplicate login is ignored.
+/// Returns 404 if any of the logins is invalid or fewer than 2 devs were found. The query is not subject to
+/// the search length limits because 4 long logins are longer than a search.
+pub(crate) async fn html(
+    config: &Config,
+    url_query: &str,
+    html_data: HtmlData,
+) -> Result<HtmlData, StmError> {
+    info!("Generating html-compare");
+
+    if decode_query(url_query).len() > MAX_QUERY_LEN {
+        warn!("Compare query is too long: {}", url_query);
+        return Ok(html_data);
+    }
+
+    let (_, query_params) = split_query_string(url_query);
+    let mut logins: Vec<String> = Vec::new();
+    for login in query_params
+        .get(DEVS_PARAM)
+        .map(|v| v.split(',').map(|v| v.trim()).collect::<Vec<&str>>())
+        .unwrap_or_default()
+    {
+        if login.is_empty() {
+            continue;
+        }
+        if login.len() > MAX_LOGIN_LEN || config.no_sql_string_invalidation_regex.is_match(login) {
+            warn!("Invalid dev login: {}", login);
+            return Ok(html_data);
+        }
+        // GitHub logins are case-insensitive
+        if !logins.iter().any(|v| v.eq_ignore_ascii_case(login)) {
+            logins.push(login.to_owned());
+        }
+    }
+    if logins.len() < MIN_DEVS || logins.len() > MAX_DEVS {
+        warn!("Invalid number of devs to compare: {}", logins.len());
+        return Ok(html_data);
+    }
+
+    let query = elastic::search_engineers_by_logins(&logins);
+    let devs = DevSearchResponse::from_es(
+        elastic::search(&config.es, &config.dev_idx, Some(&query)).await?,
+    )?;
+
+    let (found, missing) = in_url_order(&devs, &logins);
+    if found.len() < MIN_DEVS {
+        warn!("Not enough devs to compare, missing: {:?}", missing);
+        return Ok(html_data);
+    }
+
+    // the page changes only when one of the reports is regenerated
+    let last_modified = found.iter().filter_map(|dev| dev.report_timestamp()).max();
+
+    let comparison = compare(&found, missing);
+
+    Ok(HtmlData {
+        comparison: Some(comparison),
+        devs: Some(devs),
+        // the list of logins is not a search
+        raw_search: String::new(),
+        template_name: "compare.html".to_owned(),
+        ttl: 3600,
+        http_resp_code: 200,
+        // there is a separate page for every combination of devs, which is a lot of duplicate content
+        meta_robots: Some("noindex".to_owned()),
+        last_modified,
+        ..html_data
+    })
+}
+
+/// Returns the devs in the same order as their logins in the URL and the logins that were not found.
+/// ES returns the devs in no particular order and `login.keyword` matches may differ in case from the URL.
+fn in_url_order<'a>(devs: &'a DevSearchResponse, logins: &[String]) -> (Vec<&'a Dev>, Vec<String>) {
+    let mut found: Vec<&Dev> = Vec::new();
+    let mut missing: Vec<String> = Vec::new();
+    for login in logins {
+        match devs
+            .devs()
+            .find(|dev| dev.login.eq_ignore_ascii_case(login))
+        {
+            Some(dev) => found.push(dev),
+            None => missing.push(login.clone()),
+        }
+    }
+
+    (found, missing)
+}
+
+/// Builds the side-by-side view of the devs.
+fn compare(devs: &[&Dev], missing: Vec<String>) -> Comparison {
+    let keywords = devs
+        .iter()
+        .map(|dev| dev_keywords(dev))
+        .collect::<Vec<HashMap<String, u64>>>();
+
+    // lines of code per dev for every language any of them uses
+    let mut langs: HashMap<String, Vec<u64>> = HashMap::new();
+    for (i, dev) in devs.iter().enumerate() {
+        for tech in techs(dev) {
+            langs
+                .entry(tech.language.clone())
+                .or_insert_with(|| vec![0; devs.len()])[i] += tech.code_lines;
+        }
+    }
+    let mut langs = langs
+        .into_iter()
+        .map(|(language, code_lines)| ComparedLang {
+            language,
+            code_lines,
+        })
+        .collect::<Vec<ComparedLang>>();
+    langs.sort_by(|a, b| {
+        b.code_lines
+            .iter()
+            .sum::<u64>()
+            .cmp(&a.code_lines.iter().sum::<u64>())
+            .then_with(|| a.language.cmp(&b.language))
+    });
+
+    // shared keywords are ranked by their total use by all devs
+    let shared_keywords = keywords[0]
+        .keys()
+        .filter(|k| keywords.iter().all(|v| v.contains_key(*k)))
+        .map(|k| (k.clone(), keywords.iter().map(|v| v[k]).sum()))
+        .collect::<Vec<(String, u64)>>();
+
+    let compared_devs = devs
+        .iter()
+        .enumerate()
+        .map(|(i, dev)| {
+            let unique_keywords = keywords[i]
+                .iter()
+                .filter(|(k, _)| {
+                    keywords
+                        .iter()
+                        .enumerate()
+                        .all(|(j, v)| j == i || !v.contains_key(*k))
+                })
+                .map(|(k, c)| (k.clone(), *c))
+                .collect::<Vec<(String, u64)>>();
+
+            ComparedDev {
+                login: dev.login.clone(),
+                name: dev.display_name().map(|v| v.to_owned()),
+                hireable: dev.hireable.unwrap_or_default(),
+                // old reports have no project details, only the list of included repo reports
+                projects: dev
+                    .report
+                    .as_ref()
+                    .map(|v| v.projects_included.len().max(v.reports_included.len()))
+                    .unwrap_or_default(),
+                code_lines: techs(dev).iter().map(|tech| tech.code_lines).sum(),
+                date_init: dev.report.as_ref().and_then(|v| v.date_init.clone()),
+                date_head: dev.report.as_ref().and_then(|v| v.date_head.clone()),
+                unique_keywords: top_keywords(unique_keywords),
+            }
+        })
+        .collect::<Vec<ComparedDev>>();
+
+    Comparison {
+        devs: compared_devs,
+        missing,
+        langs,
+        shared_keywords: top_keywords(shared_keywords),
+    }
+}
+
+/// Returns `report.tech` of the dev or an empty list if there is no report.
+fn techs(dev: &Dev) -> &[Tech] {
+    dev.report
+        .as_ref()
+        .map(|v| v.tech.as_slice())
+        .unwrap_or_default()
+}
+
+/// Returns all packages and refs used by the dev in any of the languages with their counts.
+fn dev_keywords(dev: &Dev) -> HashMap<String, u64> {
+    let mut keywords: HashMap<String, u64> = HashMap::new();
+
+    for tech in techs(dev) {
+        for kw in tech.refs_kw.iter().chain(tech.pkgs_kw.iter()) {
+            *keywords.entry(kw.k.clone()).or_default() += kw.c;
+        }
+    }
+
+    keywords
+}
+
+/// Returns up to `MAX_KEYWORDS_PER_LIST` keywords, most used first, with the total number of keywords.
+fn top_keywords(mut keywords: Vec<(String, u64)>) -> ComparedKeywords {
+    keywords.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    ComparedKeywords {
+        total: keywords.len(),
+        keywords: keywords
+            .into_iter()
+            .take(MAX_KEYWORDS_PER_LIST)
+            .map(|(k, _)| k)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// An ES response with the devs in the same order
+    fn es_response(devs: Vec<Value>) -> DevSearchResponse {
+        let hits = devs
+            .into_iter()
+            .map(|v| json!({ "_source": v }))
+            .collect::<Vec<Value>>();
+        DevSearchResponse::from_es(json!({"hits": {"total": {"value": hits.len()}, "hits": hits}}))
+            .unwrap()
+    }
+
+    /// A dev with the list of `report.tech` records
+    fn dev(login: &str, tech: Value) -> Value {
+        json!({"login": login, "report": {"tech": tech}})
+    }
+
+    #[test]
+    fn url_order() {
+        let devs = es_response(vec![dev("dtolnay", json!([])), dev("Rimutaka", json!([]))]);
+        let logins = ["rimutaka", "nobody", "dtolnay"]
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<String>>();
+
+        let (found, missing) = in_url_order(&devs, &logins);
+        let found = found
+            .iter()
+            .map(|v| v.login.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(found, vec!["Rimutaka", "dtolnay"]);
+        assert_eq!(missing, vec!["nobody"]);
+
+        let comparison = compare(&in_url_order(&devs, &logins).0, missing);
+        assert_eq!(comparison.missing, vec!["nobody"]);
+        assert_eq!(comparison.devs[0].login, "Rimutaka");
+    }
+
+    #[test]
+    fn language_matrix() {
+        let devs = es_response(vec![
+            dev(
+                "a",
+                json!([
+                    {"language": "Rust", "code_lines": 100},
+                    {"language": "Go", "code_lines": 10},
+                    {"language": "Rust", "code_lines": 50}
+                ]),
+            ),
+            dev(
+                "b",
+                json!([
+                    {"language": "Python", "code_lines": 300},
+                    {"language": "Rust", "code_lines": 20}
+                ]),
+            ),
+        ]);
+        let comparison = compare(&devs.devs().collect::<Vec<&Dev>>(), Vec::new());
+
+        // the most used languages first, 0 for a dev who doesn't use it
+        let langs = comparison
+            .langs
+            .iter()
+            .map(|v| (v.language.as_str(), v.code_lines.clone()))
+            .collect::<Vec<(&str, Vec<u64>)>>();
+        assert_eq!(
+            langs,
+            vec![
+                ("Python", vec![0, 300]),
+                ("Rust", vec![150, 20]),
+                ("Go", vec![10, 0])
+            ]
+        );
+        assert_eq!(comparison.devs[0].code_lines, 160);
+        assert_eq!(comparison.devs[1].code_lines, 320);
+    }
+
+    #[test]
+    fn shared_and_unique_keywords() {
+        let devs = es_response(vec![
+            dev(
+                "a",
+                json!([{
+                    "language": "Rust",
+                    "refs_kw": [{"k": "tokio", "c": 5}, {"k": "serde", "c": 2}],
+                    "pkgs_kw": [{"k": "serde", "c": 1}, {"k": "clap", "c": 3}]
+                }]),
+            ),
+            dev(
+                "b",
+                json!([
+                    {"language": "Rust", "refs_kw": [{"k": "serde", "c": 10}, {"k": "tokio", "c": 1}]},
+                    {"language": "Python", "pkgs_kw": [{"k": "numpy", "c": 4}]}
+                ]),
+            ),
+            dev(
+                "c",
+                json!([{"language": "Rust", "pkgs_kw": [{"k": "serde", "c": 1}, {"k": "clap", "c": 1}]}]),
+            ),
+        ]);
+        let comparison = compare(&devs.devs().collect::<Vec<&Dev>>(), Vec::new());
+
+        // only the keywords used by all devs are shared
+        assert_eq!(comparison.shared_keywords.keywords, vec!["serde"]);
+        assert_eq!(comparison.shared_keywords.total, 1);
+
+        // a keyword shared by some devs is not unique to any of them
+        assert!(comparison.devs[0].unique_keywords.keywords.is_empty());
+        assert_eq!(comparison.devs[1].unique_keywords.keywords, vec!["numpy"]);
+        assert!(comparison.devs[2].unique_keywords.keywords.is_empty());
+    }
+
+    #[test]
+    fn keyword_list_cap() {
+        let keywords = (0..MAX_KEYWORDS_PER_LIST as u64 + 10)
+            .map(|v| (["kw", &v.to_string()].concat(), v % 3))
+            .collect::<Vec<(String, u64)>>();
+        let top = top_keywords(keywords);
+
+        assert_eq!(top.total, MAX_KEYWORDS_PER_LIST + 10);
+        assert_eq!(top.keywords.len(), MAX_KEYWORDS_PER_LIST);
+        // the most used first, then alphabetically
+        assert_eq!(top.keywords[0], "kw11");
+        assert_eq!(top.keywords[1], "kw14");
+    }
+}
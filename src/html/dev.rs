@@ -1,6 +1,8 @@
+use super::dev_doc::DevSearchResponse;
 use super::html_data::HtmlData;
 use crate::config::Config;
 use crate::elastic;
+use crate::error::StmError;
 use tracing::info;
 
 /// Returns the developer profile. Expects a valid login
@@ -8,20 +10,23 @@ pub(crate) async fn html(
     config: &Config,
     login: String,
     html_data: HtmlData,
-) -> Result<HtmlData, ()> {
+) -> Result<HtmlData, StmError> {
     info!("Generating html-dev");
-    let query = elastic::add_param(
-        elastic::SEARCH_ENGINEER_BY_LOGIN,
-        login.clone(),
-        &config.no_sql_string_invalidation_regex,
-    );
+    let query = elastic::search_engineer_by_login(&login);
+    let devs = DevSearchResponse::from_es(
+        elastic::search(&config.es, &config.dev_idx, Some(&query)).await?,
+    )?;
+
+    // the profile changes only when a new report is generated
+    let last_modified = devs.devs().next().and_then(|dev| dev.report_timestamp());
 
     let html_data = HtmlData {
-        devs: Some(elastic::search(&config.es_url, &config.dev_idx, Some(query.as_str())).await?),
+        devs: Some(devs),
         template_name: "dev.html".to_owned(),
         ttl: 3600,
         http_resp_code: 200,
         login_str: Some(login),
+        last_modified,
         ..html_data
     };
 
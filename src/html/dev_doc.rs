@@ -0,0 +1,457 @@
+//! A typed model of dev and repo idx docs. A search response serializes back into the same shape as the raw
+//! ES response, so the templates keep using paths like `devs.hits.hits[0]._source.report.tech`. Missing lists and
+//! counts default to empty or 0, but a field of the wrong type fails the deserialization instead of leaving a blank
+//! section in the page.
+//!
+//! Only the fields the pages and the JSON API need are modelled. Any other fields in ES docs are dropped.
+
+use super::parse_timestamp;
+use crate::config::Config;
+use crate::elastic;
+use crate::error::StmError;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, warn};
+
+/// ES search response with dev idx docs
+pub(crate) type DevSearchResponse = SearchResponse<Dev>;
+
+/// ES search response with repo idx docs
+pub(crate) type RepoSearchResponse = SearchResponse<Repo>;
+
+/// ES search response with docs of type `T`
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct SearchResponse<T> {
+    pub hits: Hits<T>,
+}
+
+impl<T: DeserializeOwned> SearchResponse<T> {
+    /// Converts a raw ES response into the typed model. Logs and returns an error if the docs
+    /// do not match the model.
+    pub fn from_es(es_response: Value) -> Result<Self, StmError> {
+        match serde_json::from_value::<SearchResponse<T>>(es_response) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!(
+                    "Cannot deser ES response into {}: {}",
+                    std::any::type_name::<T>(),
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Returns all docs in the order they were returned by ES.
+    pub fn docs(&self) -> impl Iterator<Item = &T> {
+        self.hits.hits.iter().map(|hit| &hit.source)
+    }
+}
+
+impl DevSearchResponse {
+    /// Returns all docs in the order they were returned by ES.
+    pub fn devs(&self) -> impl Iterator<Item = &Dev> {
+        self.docs()
+    }
+
+    /// Returns a page of devs sorted by login that come after the login, if any, for walking the entire dev index.
+    /// Use `next_page_after` for the login to fetch the next page after.
+    pub async fn dev_logins_page(config: &Config, after: Option<&str>) -> Result<Self, StmError> {
+        Self::from_es(
+            elastic::search(
+                &config.es,
+                &config.dev_idx,
+                Some(&elastic::search_dev_logins(after)),
+            )
+            .await?,
+        )
+    }
+
+    /// Returns the login of the last dev in a page from `dev_logins_page` or `None` if it is the last page.
+    pub fn next_page_after(&self) -> Option<&str> {
+        // a partial page is the last one
+        if self.hits.hits.len() < elastic::DEV_LOGINS_PAGE_SIZE {
+            return None;
+        }
+        match self.hits.hits.last().and_then(|hit| hit.sort.first()) {
+            Some(Value::String(v)) => Some(v.as_str()),
+            v => {
+                warn!("Unexpected sort value in dev logins: {:?}", v);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Hits<T> {
+    #[serde(default)]
+    pub total: HitsTotal,
+    /// A plain `default` would require `T: Default`
+    #[serde(default = "Vec::new")]
+    pub hits: Vec<Hit<T>>,
+}
+
+/// Total number of matching docs. ES stops counting at 10,000 by default.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct HitsTotal {
+    #[serde(default)]
+    pub value: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Hit<T> {
+    #[serde(rename = "_source")]
+    pub source: T,
+    /// Sort values of the hit as returned by ES for `search_after`, e.g. the login in sitemaps, or `1`/`0` for
+    /// a boolean and epoch millis for a date
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sort: Vec<Value>,
+}
+
+/// A dev idx doc: the GitHub profile of the dev with their combined report.
+/// Optional fields are `null` in GitHub profiles if the dev didn't fill them in.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Dev {
+    /// GitHub login, e.g. `rimutaka`
+    pub login: String,
+    /// GitHub user ID
+    #[serde(default)]
+    pub id: u64,
+    pub node_id: Option<String>,
+    pub name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub company: Option<String>,
+    /// Any URL from the profile, not necessarily a valid one
+    pub blog: Option<String>,
+    /// Free-form location, e.g. `New Zealand`
+    pub location: Option<String>,
+    pub email: Option<String>,
+    pub hireable: Option<bool>,
+    pub bio: Option<String>,
+    pub twitter_username: Option<String>,
+    #[serde(default)]
+    pub public_repos: u64,
+    #[serde(default)]
+    pub public_gists: u64,
+    #[serde(default)]
+    pub followers: u64,
+    #[serde(default)]
+    pub following: u64,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    /// The combined report for all projects of the dev, if it was generated
+    pub report: Option<Report>,
+}
+
+impl Dev {
+    /// Returns the date of the latest report, if there is a report with a valid date.
+    pub fn report_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.report
+            .as_ref()?
+            .timestamp
+            .as_deref()
+            .and_then(parse_timestamp)
+    }
+
+    /// Returns the name from the profile or `None` if it's blank.
+    pub fn display_name(&self) -> Option<&str> {
+        self.name.as_deref().filter(|v| !v.trim().is_empty())
+    }
+}
+
+/// A repo idx doc: the report for a single GitHub repo
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Repo {
+    /// GitHub login of the repo owner, e.g. `rimutaka`
+    pub owner_id: String,
+    /// The repo name, e.g. `stm`
+    pub repo_name: String,
+    pub report: Option<Report>,
+}
+
+/// A report combined from the reports of all projects of the dev in dev idx or the report for a single project
+/// in repo idx
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Report {
+    /// When the report was generated, e.g. `2021-03-21T03:12:49.943327166+00:00`
+    pub timestamp: Option<String>,
+    /// The date of the first commit in any of the projects
+    pub date_init: Option<String>,
+    /// The date of the last commit in any of the projects
+    pub date_head: Option<String>,
+    #[serde(default)]
+    pub is_single_commit: bool,
+    /// Stats per language
+    #[serde(default)]
+    pub tech: Vec<Tech>,
+    /// Project details. Older reports only have `reports_included`.
+    #[serde(default)]
+    pub projects_included: Vec<Project>,
+    /// Names of the project reports the report was combined from, e.g. `rimutaka/stm.report`
+    #[serde(default)]
+    pub reports_included: Vec<String>,
+    /// File extensions that are not processed, e.g. `apk`, with the number of files
+    #[serde(default)]
+    pub unknown_file_types: Vec<KeywordCount>,
+    /// Git identities of the dev in the commits
+    #[serde(default)]
+    pub contributor_git_ids: Vec<String>,
+    /// Contributors with their number of commits. Only repo idx reports have them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contributors: Vec<Contributor>,
+}
+
+/// A contributor to a repo
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Contributor {
+    /// Git identity from the commits, e.g. `max@onebro.me`
+    pub git_id: String,
+    #[serde(default)]
+    pub commit_count: u64,
+}
+
+/// Stats for a single language
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Tech {
+    /// E.g. `Rust` or `C#`
+    pub language: String,
+    #[serde(default)]
+    pub muncher_name: String,
+    #[serde(default)]
+    pub muncher_hash: u64,
+    #[serde(default)]
+    pub files: u64,
+    #[serde(default)]
+    pub total_lines: u64,
+    #[serde(default)]
+    pub code_lines: u64,
+    #[serde(default)]
+    pub line_comments: u64,
+    #[serde(default)]
+    pub block_comments: u64,
+    #[serde(default)]
+    pub docs_comments: u64,
+    #[serde(default)]
+    pub inline_comments: u64,
+    #[serde(default)]
+    pub blank_lines: u64,
+    #[serde(default)]
+    pub bracket_only_lines: u64,
+    /// Language keywords, e.g. `if` or `loop`
+    #[serde(default)]
+    pub keywords: Vec<KeywordCount>,
+    /// Fully qualified references from code files, e.g. `System.Text.Json` in .cs
+    #[serde(default)]
+    pub refs: Vec<KeywordCount>,
+    /// Fully qualified packages from project files, e.g. `tokio` in Cargo.toml
+    #[serde(default)]
+    pub pkgs: Vec<KeywordCount>,
+    /// Parts of `refs` split into keywords, e.g. `System`, `Text` and `Json`
+    #[serde(default)]
+    pub refs_kw: Vec<KeywordCount>,
+    /// Parts of `pkgs` split into keywords
+    #[serde(default)]
+    pub pkgs_kw: Vec<KeywordCount>,
+}
+
+/// A single project of the dev
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Project {
+    /// The repo name, e.g. `stm` for `rimutaka/stm`
+    pub project_name: String,
+    /// Lines of code
+    #[serde(default)]
+    pub loc: u64,
+    /// Number of external libraries
+    #[serde(default)]
+    pub libs: u64,
+    pub date_init: Option<String>,
+    pub date_head: Option<String>,
+    #[serde(default)]
+    pub tech: Vec<ProjectTech>,
+}
+
+/// Lines of code per language in a project
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct ProjectTech {
+    pub language: String,
+    #[serde(default)]
+    pub loc: u64,
+}
+
+/// A keyword, a package or a file type with the number of times it was found
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct KeywordCount {
+    pub k: String,
+    #[serde(default)]
+    pub c: u64,
+    /// Versions of a package, e.g. `["1.6", "1.6.0"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub t: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = include_str!("../../samples/report-rimutaka.json");
+
+    fn sample() -> DevSearchResponse {
+        DevSearchResponse::from_es(serde_json::from_str(SAMPLE).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn sample_profile() {
+        let resp = sample();
+        assert_eq!(resp.hits.total.value, 1);
+        assert_eq!(resp.hits.hits.len(), 1);
+
+        let dev = &resp.hits.hits[0].source;
+        assert_eq!(dev.login, "rimutaka");
+        assert_eq!(dev.id, 5926028);
+        assert_eq!(dev.display_name(), Some("mx"));
+        assert_eq!(dev.hireable, Some(true));
+        assert_eq!(dev.location.as_deref(), Some("New Zealand"));
+        assert_eq!(dev.company, None);
+        assert_eq!(dev.public_repos, 20);
+        assert_eq!(
+            dev.report_timestamp().map(|v| v.to_rfc3339()),
+            Some("2021-03-21T03:12:49.943327166+00:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn sample_report() {
+        let resp = sample();
+        let report = resp.hits.hits[0].source.report.as_ref().unwrap();
+
+        assert_eq!(
+            report.date_init.as_deref(),
+            Some("2016-04-04T16:56:38+12:00")
+        );
+        assert_eq!(report.reports_included.len(), 13);
+        assert_eq!(report.contributor_git_ids.len(), 3);
+        assert!(report.projects_included.is_empty());
+        assert_eq!(report.unknown_file_types[0].k, "apk");
+        assert_eq!(report.unknown_file_types[0].c, 12);
+
+        let langs = report
+            .tech
+            .iter()
+            .map(|v| v.language.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(langs, ["JavaScript", "Rust", "CSS", "C#", "Markdown"]);
+
+        let rust = &report.tech[1];
+        assert_eq!(rust.pkgs.len(), 23);
+        assert_eq!(rust.pkgs_kw.len(), 23);
+        assert!(rust.refs.is_empty());
+        assert_eq!(rust.pkgs[0].k, "simple_logger");
+        assert_eq!(rust.pkgs[0].t, ["1.6", "1.6.0"]);
+
+        let cs = &report.tech[3];
+        assert_eq!(cs.code_lines, 1424);
+        assert_eq!(cs.refs.len(), 17);
+        assert_eq!(cs.refs_kw.len(), 19);
+        assert_eq!(cs.pkgs_kw.len(), 14);
+        assert!(cs.keywords.iter().any(|v| v.k == "int" && v.c == 47));
+    }
+
+    #[test]
+    fn serializes_as_es_response() {
+        // the templates rely on the same paths as in the raw ES response
+        let json = serde_json::to_value(sample()).unwrap();
+        let raw: Value = serde_json::from_str(SAMPLE).unwrap();
+
+        for path in &[
+            "/hits/total/value",
+            "/hits/hits/0/_source/login",
+            "/hits/hits/0/_source/hireable",
+            "/hits/hits/0/_source/report/date_head",
+            "/hits/hits/0/_source/report/tech/1/pkgs/0/t",
+            "/hits/hits/0/_source/report/tech/3/refs_kw",
+            "/hits/hits/0/_source/report/unknown_file_types",
+        ] {
+            assert_eq!(json.pointer(path), raw.pointer(path), "{}", path);
+        }
+        // nulls stay nulls for `{% if user.company %}`
+        assert_eq!(
+            json.pointer("/hits/hits/0/_source/company"),
+            Some(&Value::Null)
+        );
+    }
+
+    #[test]
+    fn dev_without_report() {
+        let resp = DevSearchResponse::from_es(serde_json::json!({
+            "hits": {"hits": [{"_source": {"login": "octocat", "hireable": null, "name": " "}}]}
+        }))
+        .unwrap();
+        let dev = &resp.hits.hits[0].source;
+
+        assert!(dev.report.is_none());
+        assert_eq!(dev.hireable, None);
+        assert_eq!(dev.display_name(), None);
+        assert_eq!(dev.report_timestamp(), None);
+        assert_eq!(resp.hits.total.value, 0);
+    }
+
+    #[test]
+    fn sort_values() {
+        // e.g. sorted by `hireable` and `report.timestamp`
+        let resp = DevSearchResponse::from_es(serde_json::json!({
+            "hits": {"hits": [{"_source": {"login": "rimutaka"}, "sort": [1, 1616296369943i64]}]}
+        }))
+        .unwrap();
+        assert_eq!(resp.hits.hits[0].sort, [1, 1616296369943i64]);
+        assert_eq!(resp.next_page_after(), None);
+
+        // the sort values go back into the page as they were
+        let json = serde_json::to_value(&resp).unwrap();
+        assert_eq!(
+            json.pointer("/hits/hits/0/sort"),
+            Some(&serde_json::json!([1, 1616296369943i64]))
+        );
+    }
+
+    #[test]
+    fn repo_report() {
+        let resp = RepoSearchResponse::from_es(serde_json::json!({
+            "hits": {"total": {"value": 1}, "hits": [{"_source": {
+                "owner_id": "rimutaka",
+                "repo_name": "stm",
+                "report": {
+                    "timestamp": "2021-03-21T03:12:49.943327166+00:00",
+                    "tech": [{"language": "Rust", "code_lines": 176}],
+                    "contributors": [{"git_id": "max@onebro.me", "commit_count": 42, "last_commit_date": "2021-03-20"}]
+                }
+            }}]}
+        }))
+        .unwrap();
+        let repo = resp.docs().next().unwrap();
+        let report = repo.report.as_ref().unwrap();
+
+        assert_eq!(repo.repo_name, "stm");
+        assert_eq!(report.tech[0].code_lines, 176);
+        assert_eq!(report.contributors[0].git_id, "max@onebro.me");
+        assert_eq!(report.contributors[0].commit_count, 42);
+    }
+
+    #[test]
+    fn schema_drift_is_an_error() {
+        // e.g. the indexer starts writing `code_lines` as a string
+        let mut raw: Value = serde_json::from_str(SAMPLE).unwrap();
+        raw["hits"]["hits"][0]["_source"]["report"]["tech"][0]["code_lines"] = "176".into();
+        assert!(DevSearchResponse::from_es(raw).is_err());
+
+        let mut raw: Value = serde_json::from_str(SAMPLE).unwrap();
+        raw["hits"]["hits"][0]["_source"]
+            .as_object_mut()
+            .unwrap()
+            .remove("login");
+        assert!(DevSearchResponse::from_es(raw).is_err());
+    }
+}
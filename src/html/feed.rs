@@ -0,0 +1,215 @@
+//! Atom feeds of the latest dev reports for a search, e.g. `/feed.atom?q=rust tokio`, for recruiters to subscribe to.
+//! The feed has the same devs as the search at `/?rust tokio`, but the devs with the latest reports come first
+//! whether they are hireable or not. A feed without a search has the same devs as the home page.
+//! Filters work the same way as in the search, e.g. `/feed.atom?q=rust&location=new zealand`.
+
+use super::dev_doc::{Dev, DevSearchResponse, Tech};
+use super::html_data::{Feed, FeedEntry, HtmlData};
+use super::{filters, keyword, split_query_string, w3c_datetime, PAGE_PARAM};
+use crate::config::Config;
+use crate::elastic;
+use crate::error::StmError;
+use chrono::Utc;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+pub(crate) const FEED_PATH: &str = "/feed.atom";
+/// The query string param with the search, e.g. `q=rust tokio`. It is optional, `/feed.atom?rust tokio` works too.
+const SEARCH_PARAM: &str = "q";
+/// Max number of languages listed per dev
+const MAX_LANGS_PER_ENTRY: usize = 3;
+/// Feed readers poll every hour or so, which is longer than the TTL of search pages
+const FEED_TTL: u32 = 3600;
+
+/// Returns an Atom feed for the search in the query string. A search that would return a 404 page
+/// has no feed either.
+pub(crate) async fn atom(
+    config: &Config,
+    url_query: &str,
+    html_data: HtmlData,
+) -> Result<HtmlData, StmError> {
+    info!("Generating feed");
+
+    // `q=rust%20tokio&hireable=true&page=2` is the same search as `rust%20tokio&hireable=true` on the site
+    let (raw_search, query_params) = match feed_search(url_query) {
+        Some(v) => v,
+        None => {
+            warn!("Feed query is too long: {}", url_query);
+            return Ok(html_data);
+        }
+    };
+    let dev_filters = filters::from_params(&query_params)?;
+    // the same search as a page on the site for the links in the feed
+    let search_url = filters::search_url(&raw_search, &dev_filters, 1);
+
+    // the latest reports come first, unlike in the search results, where hireable devs come first
+    let (devs, title) = if raw_search.len() > 1 {
+        let resolved = super::resolve_search(
+            config,
+            &raw_search,
+            &dev_filters,
+            1,
+            elastic::search_latest_devs,
+        )
+        .await?;
+
+        // the same as the search page, which is a 404 without any valid terms
+        if resolved.terms.must.is_empty() {
+            warn!("No feed for {}", url_query);
+            return Ok(html_data);
+        }
+
+        let devs = match resolved.devs {
+            Some(v) => v,
+            None => {
+                let query = elastic::search_latest_devs(&resolved.terms, &dev_filters, 1);
+                elastic::search(&config.es, &config.dev_idx, Some(&query)).await?
+            }
+        };
+        let title = [
+            "StackMuncher: ",
+            &keyword::search_terms_str(&resolved.terms),
+            " developers",
+        ]
+        .concat();
+        (devs, title)
+    } else {
+        // the same devs as on the home page without the stats the home page also needs
+        let query = elastic::search_top_users(&dev_filters);
+        let devs = elastic::search(&config.es, &config.dev_idx, Some(&query)).await?;
+        (devs, "StackMuncher: latest hireable developers".to_owned())
+    };
+    let devs = DevSearchResponse::from_es(devs)?;
+
+    let entries = devs
+        .devs()
+        .filter_map(|dev| feed_entry(&config.site_url, dev))
+        .collect::<Vec<FeedEntry>>();
+    let last_modified = devs.devs().filter_map(|dev| dev.report_timestamp()).max();
+    info!("Feed entries: {}", entries.len());
+
+    Ok(HtmlData {
+        feed: Some(Feed {
+            title,
+            self_url: [
+                config.site_url.as_str(),
+                FEED_PATH,
+                search_url.trim_start_matches('/'),
+            ]
+            .concat(),
+            alternate_url: [config.site_url.as_str(), &search_url].concat(),
+            // Atom requires a date even if there are no entries
+            updated: w3c_datetime(last_modified.unwrap_or_else(Utc::now)),
+            entries,
+        }),
+        template_name: "feed.atom".to_owned(),
+        ttl: FEED_TTL,
+        http_resp_code: 200,
+        last_modified,
+        ..html_data
+    })
+}
+
+/// Splits the query string into the search and the filter params, same as for the search page. The search can be in `q=`
+/// or in the free-form part. Feeds always start with the latest devs, so the page number is dropped.
+/// Returns `None` if the query is over the same limits as for the search page.
+fn feed_search(url_query: &str) -> Option<(String, HashMap<String, String>)> {
+    if !super::query_within_limits(url_query) {
+        return None;
+    }
+
+    let (search, mut query_params) = split_query_string(url_query);
+    query_params.remove(PAGE_PARAM);
+    let raw_search = match query_params.remove(SEARCH_PARAM) {
+        Some(q) => [q.as_str(), " ", &search].concat().trim().to_owned(),
+        None => search,
+    };
+    if raw_search.len() > super::MAX_SEARCH_LEN {
+        return None;
+    }
+
+    Some((raw_search, query_params))
+}
+
+/// Returns a feed entry for the dev or `None` if the dev has no valid report date.
+fn feed_entry(site_url: &str, dev: &Dev) -> Option<FeedEntry> {
+    let updated = dev.report_timestamp()?;
+
+    // the same order as on the dev card
+    let mut techs = dev
+        .report
+        .as_ref()
+        .map(|v| v.tech.iter().collect::<Vec<&Tech>>())
+        .unwrap_or_default();
+    techs.sort_by_key(|tech| Reverse(tech.total_lines));
+    let langs = techs
+        .iter()
+        .take(MAX_LANGS_PER_ENTRY)
+        .map(|tech| tech.language.clone())
+        .collect::<Vec<String>>();
+
+    Some(FeedEntry {
+        login: dev.login.clone(),
+        name: dev.display_name().map(|v| v.to_owned()),
+        url: [site_url, "/", &urlencoding::encode(&dev.login)].concat(),
+        updated: w3c_datetime(updated),
+        langs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_in_q_and_free_form() {
+        let (search, params) = feed_search("q=rust%20tokio&hireable=true&page=2").unwrap();
+        assert_eq!(search, "rust tokio");
+        assert_eq!(params.len(), 1);
+        assert_eq!(params["hireable"], "true");
+
+        // the same search as on the site
+        let (search, params) = feed_search("rust%20tokio&page=2").unwrap();
+        assert_eq!(search, "rust tokio");
+        assert!(params.is_empty());
+
+        // both parts are combined
+        let (search, _) = feed_search("q=rust&tokio").unwrap();
+        assert_eq!(search, "rust tokio");
+
+        // encoded separators stay in the values
+        let (search, params) =
+            feed_search("q=c%23%20a%26b&location=Trinidad%20%26%20Tobago").unwrap();
+        assert_eq!(search, "c# a&b");
+        assert_eq!(params["location"], "Trinidad & Tobago");
+
+        let (search, params) = feed_search("").unwrap();
+        assert!(search.is_empty());
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn search_limits() {
+        let search = "r".repeat(crate::html::MAX_SEARCH_LEN);
+        assert!(feed_search(&["q=", &search].concat()).is_some());
+        // the search is too long only when combined
+        assert!(feed_search(&["q=", &search, "&r"].concat()).is_none());
+    }
+
+    #[tokio::test]
+    async fn feed_route() {
+        let config = Config::with_samples();
+        let html_data = atom(&config, "q=rust&page=3", HtmlData::new(String::new()))
+            .await
+            .unwrap();
+        assert_eq!(html_data.template_name, "feed.atom");
+
+        // links to the first page of the same search on the site
+        let feed = html_data.feed.unwrap();
+        assert_eq!(feed.alternate_url, "https://stackmuncher.com/?rust");
+        assert_eq!(feed.self_url, "https://stackmuncher.com/feed.atom?rust");
+        assert_eq!(feed.entries.len(), 1);
+        assert_eq!(feed.entries[0].url, "https://stackmuncher.com/rimutaka");
+    }
+}
@@ -0,0 +1,137 @@
+//! Dev search filters passed as query string params, e.g. `?rust&location=new zealand&hireable=true&page=2`.
+
+use super::html_data::SearchFilter;
+use super::PAGE_PARAM;
+use crate::elastic::DevFilters;
+use crate::error::StmError;
+use regex::Regex;
+use std::collections::HashMap;
+use urlencoding::encode;
+
+const LOCATION_PARAM: &str = "location";
+const HIREABLE_PARAM: &str = "hireable";
+const ACTIVE_SINCE_PARAM: &str = "active_since";
+const MIN_LOC_PARAM: &str = "min_loc";
+/// Longer locations are unlikely to match anything and would make search links too long.
+/// It is also the `maxlength` of the location input in `templates/includes/filters.html`.
+pub(crate) const MAX_LOCATION_LEN: usize = 100;
+/// Far more lines than any dev has written. Larger numbers may not fit into ES `long`.
+/// It is also the `max` of the min LOC input in `templates/includes/filters.html`.
+pub(crate) const MAX_MIN_LOC: usize = u32::MAX as usize;
+
+/// Extracts the filters from the query string params. Empty params are ignored.
+/// Returns an error if any of the values is invalid.
+pub(crate) fn from_params(params: &HashMap<String, String>) -> Result<DevFilters, StmError> {
+    // a helper to get a non-empty param value
+    let param = |name: &str| params.get(name).filter(|v| !v.is_empty());
+
+    let location = match param(LOCATION_PARAM) {
+        Some(v) if v.len() > MAX_LOCATION_LEN => {
+            return Err(StmError::Validation(["Location is too long: ", v].concat()));
+        }
+        v => v.map(|v| v.to_lowercase()),
+    };
+
+    let hireable = match param(HIREABLE_PARAM).map(|v| v.as_str()) {
+        None => None,
+        Some("true") => Some(true),
+        Some("false") => Some(false),
+        Some(v) => {
+            return Err(StmError::Validation(["Invalid hireable: ", v].concat()));
+        }
+    };
+
+    // ES accepts partial dates, e.g. 2021 or 2021-01
+    let active_since = match param(ACTIVE_SINCE_PARAM) {
+        None => None,
+        Some(v) => {
+            let rgx =
+                Regex::new(r#"^\d{4}(-\d{2}(-\d{2})?)?$"#).expect("Wrong active_since regex!");
+            if !rgx.is_match(v) {
+                return Err(StmError::Validation(["Invalid active_since: ", v].concat()));
+            }
+            Some(v.clone())
+        }
+    };
+
+    let min_loc = match param(MIN_LOC_PARAM) {
+        None => None,
+        Some(v) => match v.parse::<usize>() {
+            Ok(min_loc) if (1..=MAX_MIN_LOC).contains(&min_loc) => Some(min_loc),
+            _ => {
+                return Err(StmError::Validation(["Invalid min_loc: ", v].concat()));
+            }
+        },
+    };
+
+    Ok(DevFilters {
+        location,
+        hireable,
+        active_since,
+        min_loc,
+    })
+}
+
+/// Returns the filters as query string params in the same order every time for URL consistency.
+fn to_params(filters: &DevFilters) -> Vec<(&'static str, String)> {
+    let mut params: Vec<(&'static str, String)> = Vec::new();
+
+    if let Some(v) = &filters.location {
+        params.push((LOCATION_PARAM, v.clone()));
+    }
+    if let Some(v) = filters.hireable {
+        params.push((HIREABLE_PARAM, v.to_string()));
+    }
+    if let Some(v) = &filters.active_since {
+        params.push((ACTIVE_SINCE_PARAM, v.clone()));
+    }
+    if let Some(v) = filters.min_loc {
+        params.push((MIN_LOC_PARAM, v.to_string()));
+    }
+
+    params
+}
+
+/// Returns the list of filters for the templates with links to the same search without each of the filters.
+pub(crate) fn chips(raw_search: &str, filters: &DevFilters) -> Vec<SearchFilter> {
+    to_params(filters)
+        .into_iter()
+        .map(|(name, value)| {
+            let mut without = filters.clone();
+            match name {
+                LOCATION_PARAM => without.location = None,
+                HIREABLE_PARAM => without.hireable = None,
+                ACTIVE_SINCE_PARAM => without.active_since = None,
+                _ => without.min_loc = None,
+            }
+
+            SearchFilter {
+                name: name.to_owned(),
+                value,
+                remove_url: search_url(raw_search, &without, 1),
+            }
+        })
+        .collect()
+}
+
+/// Returns a relative URL for the page of the search with the filters. The first page has no page number for consistency
+/// with search URLs entered by users.
+pub(crate) fn search_url(raw_search: &str, filters: &DevFilters, page: usize) -> String {
+    let mut parts: Vec<String> = Vec::new();
+
+    if !raw_search.is_empty() {
+        parts.push(encode(raw_search));
+    }
+    for (name, value) in to_params(filters) {
+        parts.push([name, "=", &encode(&value)].concat());
+    }
+    if page > 1 {
+        parts.push([PAGE_PARAM, "=", &page.to_string()].concat());
+    }
+
+    if parts.is_empty() {
+        "/".to_owned()
+    } else {
+        ["/?", &parts.join("&")].concat()
+    }
+}
@@ -1,58 +1,27 @@
+use super::dev_doc::DevSearchResponse;
 use super::html_data::{HtmlData, RelatedKeywords};
 use crate::config::Config;
-use crate::elastic;
+use crate::elastic::{self, DevFilters};
+use crate::error::StmError;
 use regex::Regex;
-use serde::Deserialize;
-use serde_json::Value;
 use std::collections::HashMap;
 use tracing::{info, warn};
 
-#[derive(Deserialize, Debug)]
-struct EngListResp {
-    hits: EngListHits,
-}
-
-#[derive(Deserialize, Debug)]
-struct EngListHits {
-    hits: Vec<EngHit>,
-}
-
-#[derive(Deserialize, Debug)]
-struct EngHit {
-    #[serde(rename(deserialize = "_source"))]
-    source: Option<EngSource>,
-}
-
-#[derive(Deserialize, Debug)]
-struct EngSource {
-    report: Option<Report>,
-}
-
-#[derive(Deserialize, Debug)]
-struct Report {
-    tech: Option<Vec<Tech>>,
-}
-
-#[derive(Deserialize, Debug)]
-struct Tech {
-    refs_kw: Option<Vec<RelatedKeywords>>,
-    pkgs_kw: Option<Vec<RelatedKeywords>>,
-}
-
 /// Returns the default home page
-pub(crate) async fn html(config: &Config, html_data: HtmlData) -> Result<HtmlData, ()> {
+pub(crate) async fn html(
+    config: &Config,
+    filters: &DevFilters,
+    html_data: HtmlData,
+) -> Result<HtmlData, StmError> {
     info!("Generating html-home");
 
     // a query to grab a bunch of latest additions and updates to dev idx
-    let devs = elastic::search(
-        &config.es_url,
-        &config.dev_idx,
-        Some(elastic::SEARCH_TOP_USERS),
-    );
+    let query = elastic::search_top_users(filters);
+    let devs = elastic::search(&config.es, &config.dev_idx, Some(&query));
     // a query to get latest stats
     // returns Stats struct wrapped in _source
     let stats = elastic::get_doc_by_id(
-        &config.es_url,
+        &config.es,
         &config.stats_idx,
         "latest_stats.json",
         &config.no_sql_string_invalidation_regex,
@@ -60,7 +29,7 @@ pub(crate) async fn html(config: &Config, html_data: HtmlData) -> Result<HtmlDat
 
     // get all the data the page needs from ES in one go with async requests
     let (devs, stats) = futures::future::join(devs, stats).await;
-    let devs = devs?;
+    let devs = DevSearchResponse::from_es(devs?)?;
     let stats = stats?;
 
     // combine everything together for Tera
@@ -71,6 +40,12 @@ pub(crate) async fn html(config: &Config, html_data: HtmlData) -> Result<HtmlDat
         template_name: "home.html".to_owned(),
         ttl: 600,
         http_resp_code: 200,
+        // filtered lists are not indexed to avoid duplicate content
+        meta_robots: if filters.is_empty() {
+            None
+        } else {
+            Some("noindex".to_owned())
+        },
         ..html_data
     };
 
@@ -78,61 +53,33 @@ pub(crate) async fn html(config: &Config, html_data: HtmlData) -> Result<HtmlDat
 }
 
 /// Extracts ref_kw from all engineers and returns a unique list
-fn extract_keywords(engineer_list: &Value) -> Vec<RelatedKeywords> {
+fn extract_keywords(engineer_list: &DevSearchResponse) -> Vec<RelatedKeywords> {
     let mut collector: HashMap<String, usize> = HashMap::new();
     let rgx = Regex::new(r#"[^\-_0-9a-zA-Z]"#).expect("Wrong _kw regex!");
 
-    // the data we need is buried 10 levels deep - keep unwrapping until we are there
-    let e_list_resp = serde_json::from_value::<EngListResp>(engineer_list.clone())
-        .expect("Cannot deser Eng List");
-
-    for e_source in e_list_resp.hits.hits {
-        if e_source.source.is_none() {
-            // this should not happen
-            warn!("Empty _source on eng list");
-            continue;
-        }
-
-        let report = e_source.source.unwrap().report;
-        if report.is_none() {
-            warn!("Empty report on eng list");
-            // this should not happen
-            continue;
-        }
-
-        let tech = report.unwrap().tech;
-        if tech.is_none() {
-            // this may happen if the repos have no tech we track
-            continue;
-        }
-
-        for t in tech.unwrap() {
-            // code files like .cs and .rs have references (use ...)
-            if let Some(refs_kw) = t.refs_kw {
-                for kw in refs_kw {
-                    // do not add rubbish ones, but log them for reference
-                    if rgx.is_match(&kw.k) {
-                        warn!("Invalid keyword: {}", kw.k);
-                        continue;
-                    }
-                    // add the keyword to the list and increment its counter
-                    *collector.entry(kw.k).or_insert(kw.c) += kw.c;
-                }
+    for dev in engineer_list.devs() {
+        let report = match &dev.report {
+            Some(v) => v,
+            None => {
+                // this should not happen
+                warn!("Empty report on eng list");
+                continue;
             }
+        };
 
+        // the list may be empty if the repos have no tech we track
+        for t in &report.tech {
+            // code files like .cs and .rs have references (use ...)
             // project level files have packages like .csproj or Cargo.toml
             // it's unlikely to have both, pkgs and refs
-            if let Some(refs_kw) = t.pkgs_kw {
-                // these are the keywords we are after
-                for kw in refs_kw {
-                    // do not add rubbish ones, but log them for reference
-                    if rgx.is_match(&kw.k) {
-                        warn!("Invalid keyword: {}", kw.k);
-                        continue;
-                    }
-                    // add the keyword to the list of increment its counter
-                    *collector.entry(kw.k).or_insert(kw.c) += kw.c;
+            for kw in t.refs_kw.iter().chain(t.pkgs_kw.iter()) {
+                // do not add rubbish ones, but log them for reference
+                if rgx.is_match(&kw.k) {
+                    warn!("Invalid keyword: {}", kw.k);
+                    continue;
                 }
+                // add the keyword to the list and increment its counter
+                *collector.entry(kw.k.clone()).or_insert(kw.c as usize) += kw.c as usize;
             }
         }
     }
@@ -142,12 +89,12 @@ fn extract_keywords(engineer_list: &Value) -> Vec<RelatedKeywords> {
         .iter()
         .map(|(k, c)| RelatedKeywords {
             k: k.clone(),
-            c: c.clone(),
+            c: *c,
         })
         .collect();
 
     // sort by keyword, case-insensitive
-    ref_kws.sort_by(|a, b| a.k.to_lowercase().cmp(&b.k.to_lowercase()));
+    ref_kws.sort_by_key(|v| v.k.to_lowercase());
 
     info!("Dev keywords extracted");
 
@@ -1,4 +1,7 @@
+use crate::html::dev_doc::{DevSearchResponse, RepoSearchResponse};
+use crate::html::search_query::{SearchTerm, TermPrefix};
 use crate::html::stats::Stats;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -7,8 +10,11 @@ use serde_json::Value;
 pub(crate) struct HtmlData {
     /// System stats
     pub stats: Option<Value>,
-    /// Raw ES response with dev idx docs
-    pub devs: Option<Value>,
+    /// ES response with dev idx docs
+    pub devs: Option<DevSearchResponse>,
+    /// ES response with repo idx docs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<RepoSearchResponse>,
     /// List of related libraries, fully qualified  
     pub related: Option<Vec<RelatedKeywords>>,
     /// The raw search string as entered by the user
@@ -25,6 +31,9 @@ pub(crate) struct HtmlData {
     /// A normalized version of the user login for dev profile page title
     #[serde(skip_serializing_if = "Option::is_none")]
     pub login_str: Option<String>,
+    /// Repo name as in `/{owner}/{repo}` URL for repo page title. The owner goes into `login_str`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo_str: Option<String>,
     /// Name of the HTML template to use. Defaults to 404
     pub template_name: String,
     /// Time to live for the HTTP response
@@ -38,6 +47,156 @@ pub(crate) struct HtmlData {
     /// A container for job stats data populated for stats page only.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stats_jobs: Option<Stats>,
+    /// Inline SVG charts for the stats page
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub charts: Vec<SvgChart>,
+    /// A user-friendly error message for the error page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_msg: Option<String>,
+    /// Populated for paginated search results only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pagination: Option<Pagination>,
+    /// Filters from the query string, e.g. `location=new zealand`, in the same order as in search URLs
+    pub filters: Vec<SearchFilter>,
+    /// Absolute URLs for sitemaps, sitemap indexes and robots.txt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sitemap: Option<Vec<SitemapUrl>>,
+    /// Atom feed of the latest devs for a search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feed: Option<Feed>,
+    /// Several devs side by side for the compare page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comparison: Option<Comparison>,
+    /// When the data was last updated for `Last-Modified` header, if known
+    #[serde(skip)]
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl HtmlData {
+    /// Returns a blank structure for a 404 page with the search string as entered by the user.
+    pub fn new(raw_search: String) -> Self {
+        HtmlData {
+            raw_search,
+            related: None,
+            devs: None,
+            repo: None,
+            keywords: Vec::new(),
+            keywords_meta: Vec::new(),
+            langs: Vec::new(),
+            keywords_str: None,
+            stats: None,
+            template_name: "404.html".to_owned(),
+            ttl: 600,
+            http_resp_code: 404,
+            meta_robots: None,
+            login_str: None,
+            repo_str: None,
+            stats_jobs: None,
+            charts: Vec::new(),
+            error_msg: None,
+            pagination: None,
+            filters: Vec::new(),
+            sitemap: None,
+            feed: None,
+            comparison: None,
+            last_modified: None,
+        }
+    }
+}
+
+/// A `<url>` or `<sitemap>` entry of a sitemap or a sitemap index
+#[derive(Serialize)]
+pub(crate) struct SitemapUrl {
+    /// An absolute URL, e.g. `https://stackmuncher.com/rimutaka`
+    pub loc: String,
+    /// W3C datetime of the latest change, e.g. `2021-03-21T03:12:49Z`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lastmod: Option<String>,
+}
+
+/// An Atom feed with absolute URLs and RFC 3339 dates
+#[derive(Serialize)]
+pub(crate) struct Feed {
+    /// E.g. `rust tokio developers`
+    pub title: String,
+    /// The URL of the feed itself, which is also its ID
+    pub self_url: String,
+    /// The URL of the search page with the same devs
+    pub alternate_url: String,
+    /// The date of the latest report in the feed or the current time if the feed is empty
+    pub updated: String,
+    pub entries: Vec<FeedEntry>,
+}
+
+/// A dev report as an Atom feed entry
+#[derive(Serialize)]
+pub(crate) struct FeedEntry {
+    pub login: String,
+    /// The name from the GitHub profile, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The URL of the dev profile, which is also the ID of the entry
+    pub url: String,
+    /// The date of the latest report
+    pub updated: String,
+    /// Up to 3 languages with the most lines of code, e.g. `["Rust", "C#"]`
+    pub langs: Vec<String>,
+}
+
+/// Devs side by side. All per-dev lists are in the same order as `devs`, which is the order of logins in the URL.
+#[derive(Serialize)]
+pub(crate) struct Comparison {
+    pub devs: Vec<ComparedDev>,
+    /// Requested logins that are not in the dev idx
+    pub missing: Vec<String>,
+    /// Every language used by any of the devs, most lines of code first
+    pub langs: Vec<ComparedLang>,
+    /// Packages and refs used by all of the devs, most used first
+    pub shared_keywords: ComparedKeywords,
+}
+
+/// A summary of a single dev for the compare page
+#[derive(Serialize)]
+pub(crate) struct ComparedDev {
+    pub login: String,
+    /// The name from the GitHub profile, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub hireable: bool,
+    /// Number of projects in the report
+    pub projects: usize,
+    /// Lines of code in all languages
+    pub code_lines: u64,
+    /// The date of the first commit as in the report, e.g. `2016-04-04T16:56:38+12:00`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_init: Option<String>,
+    /// The date of the last commit as in the report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_head: Option<String>,
+    /// Packages and refs no other dev in the comparison uses, most used first
+    pub unique_keywords: ComparedKeywords,
+}
+
+/// A language with lines of code per dev, 0 if the dev doesn't use it
+#[derive(Serialize)]
+pub(crate) struct ComparedLang {
+    pub language: String,
+    pub code_lines: Vec<u64>,
+}
+
+/// A list of packages and refs that may be too long to show in full
+#[derive(Serialize)]
+pub(crate) struct ComparedKeywords {
+    /// Number of keywords before truncation
+    pub total: usize,
+    pub keywords: Vec<String>,
+}
+
+/// A chart rendered as an SVG document for embedding into HTML as-is
+#[derive(Serialize)]
+pub(crate) struct SvgChart {
+    pub title: String,
+    pub svg: String,
 }
 
 /// A view of the keyword from ElasticSearch
@@ -56,6 +215,49 @@ pub(crate) struct KeywordMetadata {
     /// True if the number of allowed search terms was exceeded. Needed to simplify the front end
     /// and keen the control of the number in one place.
     pub too_many: bool,
+    /// True if the term was excluded from the search with `-`, e.g. `-actix`
+    pub negated: bool,
+    /// 0-based index of the group of alternatives the term belongs to, e.g. `(tokio OR async-std)`.
+    /// It is `null` for terms that are required or excluded on their own.
+    pub or_group: Option<usize>,
+    /// `lang` or `pkg` if the user restricted the term with a prefix, e.g. `lang:go`
+    pub prefix: Option<String>,
+    /// Known terms similar to an unknown term, e.g. `tokio` for `tokoi`, best first
+    pub suggestions: Vec<SearchSuggestion>,
+    /// The term as entered by the user if it was auto-corrected into `search_term`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub corrected_from: Option<SearchSuggestion>,
+}
+
+impl KeywordMetadata {
+    /// Returns metadata for a term that was not checked against ES yet.
+    pub fn new(search_term: &SearchTerm) -> Self {
+        KeywordMetadata {
+            search_term: search_term.value.clone(),
+            es_keyword_count: 0,
+            es_package_count: 0,
+            es_language_count: 0,
+            unknown: false,
+            too_many: false,
+            negated: false,
+            or_group: None,
+            suggestions: Vec::new(),
+            corrected_from: None,
+            prefix: match search_term.prefix {
+                TermPrefix::Any => None,
+                TermPrefix::Lang => Some("lang".to_owned()),
+                TermPrefix::Pkg => Some("pkg".to_owned()),
+            },
+        }
+    }
+}
+
+/// An alternative search term with a link to the search where it replaces the original term
+#[derive(Serialize)]
+pub(crate) struct SearchSuggestion {
+    pub search_term: String,
+    /// A relative URL of the search with the term replaced
+    pub url: String,
 }
 
 /// List of related keywords extracted from ES
@@ -64,3 +266,32 @@ pub(crate) struct RelatedKeywords {
     pub k: String,
     pub c: usize,
 }
+
+/// Page navigation for search results
+#[derive(Serialize)]
+pub(crate) struct Pagination {
+    /// Total number of matching docs as reported by ES. It may be capped at 10,000.
+    pub total_hits: usize,
+    /// 1-based number of the current page
+    pub page: usize,
+    /// Number of pages that can be browsed, which may be less than the total number of hits requires
+    pub total_pages: usize,
+    /// True if there are more hits than can be browsed
+    pub truncated: bool,
+    /// A relative URL of the previous page, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_url: Option<String>,
+    /// A relative URL of the next page, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_url: Option<String>,
+}
+
+/// A search filter for rendering as a chip
+#[derive(Serialize)]
+pub(crate) struct SearchFilter {
+    /// Name of the query string param, e.g. `location`
+    pub name: String,
+    pub value: String,
+    /// A relative URL of the same search without this filter
+    pub remove_url: String,
+}
@@ -0,0 +1,95 @@
+use super::dev_doc::{Dev, Repo};
+use super::html_data::{
+    Comparison, HtmlData, KeywordMetadata, Pagination, RelatedKeywords, SearchFilter,
+};
+use crate::error::StmError;
+use crate::html::stats::Stats;
+use serde::Serialize;
+use serde_json::Value;
+
+/// The version of the JSON document. It must be incremented on any breaking change to `JsonDoc`
+/// or its members. Adding new optional members is not a breaking change.
+pub(crate) const JSON_DOC_VERSION: u32 = 1;
+
+/// A JSON representation of any page for API clients. It contains the same data as the HTML page,
+/// but without any presentation-only fields, e.g. template name or meta-tags.
+/// ```json
+/// {
+///   "version": 1,
+///   "page": "keyword",
+///   "status": 200,
+///   "search": "rust tokio",
+///   "keywords": ["tokio"],
+///   "langs": ["rust"],
+///   "devs": [{"login": "rimutaka", "report": {...}}],
+///   ...
+/// }
+/// ```
+#[derive(Serialize)]
+struct JsonDoc<'a> {
+    /// See `JSON_DOC_VERSION`
+    version: u32,
+    /// Type of the page, e.g. `dev`, `keyword`, `related`, `stats`, `home`, `repo`, `compare`, `404`, `429` or `error`
+    page: &'a str,
+    /// Same as the HTTP status of the response
+    status: u32,
+    /// The search part of the query string as entered by the user
+    search: &'a str,
+    /// Keywords used in the search
+    keywords: &'a Vec<String>,
+    /// Languages used in the search
+    langs: &'a Vec<String>,
+    /// All search terms with their counts from different fields in ES
+    keywords_meta: &'a Vec<KeywordMetadata>,
+    /// `_source` of every matching dev idx doc in the same order as returned by ES
+    #[serde(skip_serializing_if = "Option::is_none")]
+    devs: Option<Vec<&'a Dev>>,
+    /// `_source` of the matching repo idx doc
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repo: Option<&'a Repo>,
+    /// Related keywords with their counts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    related: Option<&'a Vec<RelatedKeywords>>,
+    /// Search result pages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pagination: Option<&'a Pagination>,
+    /// Filters applied to the search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filters: Option<&'a Vec<SearchFilter>>,
+    /// Overall system stats for the home page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<&'a Value>,
+    /// Devs side by side for the compare page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comparison: Option<&'a Comparison>,
+    /// Job stats for the stats page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats_jobs: Option<&'a Stats>,
+    /// A user-friendly error message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a String>,
+}
+
+/// Converts the page data into a versioned JSON document.
+pub(crate) fn to_json(html_data: &HtmlData) -> Result<String, StmError> {
+    let doc = JsonDoc {
+        version: JSON_DOC_VERSION,
+        page: html_data.template_name.trim_end_matches(".html"),
+        status: html_data.http_resp_code,
+        search: &html_data.raw_search,
+        keywords: &html_data.keywords,
+        langs: &html_data.langs,
+        keywords_meta: &html_data.keywords_meta,
+        devs: html_data.devs.as_ref().map(|v| v.devs().collect()),
+        repo: html_data.repo.as_ref().and_then(|v| v.docs().next()),
+        related: html_data.related.as_ref(),
+        pagination: html_data.pagination.as_ref(),
+        filters: Some(&html_data.filters).filter(|v| !v.is_empty()),
+        stats: html_data.stats.as_ref(),
+        comparison: html_data.comparison.as_ref(),
+        stats_jobs: html_data.stats_jobs.as_ref(),
+        error: html_data.error_msg.as_ref(),
+    };
+
+    Ok(serde_json::to_string(&doc)?)
+}
@@ -1,21 +1,38 @@
-use super::html_data::HtmlData;
+use super::dev_doc::DevSearchResponse;
+use super::html_data::{HtmlData, Pagination};
+use super::{filters, MAX_PAGE};
 use crate::config::Config;
-use crate::elastic;
+use crate::elastic::{self, DevFilters, DevSearchTerm, DevSearchTerms};
+use crate::error::StmError;
+use serde_json::Value;
 use tracing::info;
 
-/// Returns package names containing the keyword and engineers using them
+/// Returns package names containing the keyword and engineers using them.
+/// `devs` is the ES response for the same terms if it was fetched in the same batch as the term counts.
 pub(crate) async fn html(
     config: &Config,
-    keywords: Vec<String>,
-    langs: Vec<String>,
+    terms: DevSearchTerms,
+    filters: &DevFilters,
+    page: usize,
+    devs: Option<Value>,
     html_data: HtmlData,
-) -> Result<HtmlData, ()> {
+) -> Result<HtmlData, StmError> {
     info!("Generating html-keyword");
-    info!("KWs: {:?}", keywords);
-    info!("Lang: {:?}", langs);
+    info!("Terms: {:?}", terms);
+
+    // split the required terms into langs and keywords for the templates
+    let mut langs: Vec<String> = Vec::new();
+    let mut keywords: Vec<String> = Vec::new();
+    for term in terms.must.iter().flatten() {
+        match term {
+            DevSearchTerm::Lang(lang) => langs.push(lang.clone()),
+            DevSearchTerm::Keyword(kw) => keywords.push(kw.clone()),
+        }
+    }
 
     // return a blank response if no valid keywords were extracted from the search terms
-    if keywords.is_empty() && langs.is_empty() {
+    // a search with exclusions only is not allowed because it would match nearly everything
+    if terms.must.is_empty() {
         return Ok(HtmlData {
             devs: None,
             keywords,
@@ -29,31 +46,34 @@ pub(crate) async fn html(
         });
     }
 
-    // get the data from ES
-    let devs = elastic::matching_devs(
-        &config.es_url,
-        &config.dev_idx,
-        keywords.clone(),
-        langs.clone(),
-        &config.no_sql_string_invalidation_regex,
-    )
-    .await?;
+    // get the data from ES unless it was already fetched
+    let devs = match devs {
+        Some(v) => v,
+        None => elastic::matching_devs(&config.es, &config.dev_idx, &terms, filters, page).await?,
+    };
+    let devs = DevSearchResponse::from_es(devs)?;
+
+    let pagination = pagination(&devs, &html_data.raw_search, filters, page);
 
     // pre-build search terms as a string for simplified presentation
     // it should present them all as a list, but for now it uses a simple string
-    // languages come first
-    let mut combined_search_terms = langs.clone();
-    for kw in &keywords {
-        combined_search_terms.push(kw.clone());
-    }
-    let combined_search_terms = combined_search_terms.join(" + ");
+    let combined_search_terms = search_terms_str(&terms);
 
     // any page with more than one language or any number of keywords should not be indexed
     // in other words, only search results with just one language and nothing else are indexed
-    let meta_robots = if langs.len() > 1 || !keywords.is_empty() {
-        Some("noindex".to_owned())
+    // the pages after the first one are not indexed to avoid duplicate content
+    let meta_robots =
+        if langs.len() > 1 || !keywords.is_empty() || !terms.must_not.is_empty() || page > 1 {
+            Some("noindex".to_owned())
+        } else {
+            None
+        };
+
+    // a page past the last one has no results and should not exist
+    let http_resp_code = if page > pagination.total_pages.max(1) {
+        404
     } else {
-        None
+        200
     };
 
     // put everything together for Tera
@@ -64,10 +84,104 @@ pub(crate) async fn html(
         keywords_str: Some(combined_search_terms),
         template_name: "keyword.html".to_owned(),
         ttl: 600,
-        http_resp_code: 200,
+        http_resp_code,
         meta_robots,
+        pagination: Some(pagination),
         ..html_data
     };
 
     Ok(html_data)
 }
+
+/// Returns the required terms as a string for page titles, e.g. `rust + tokio or async-std`.
+/// Languages come first, alternatives are joined with `or`, exclusions are not shown.
+pub(crate) fn search_terms_str(terms: &DevSearchTerms) -> String {
+    let mut groups = terms.must.iter().collect::<Vec<&Vec<DevSearchTerm>>>();
+    groups.sort_by_key(|group| {
+        !group
+            .iter()
+            .all(|term| matches!(term, DevSearchTerm::Lang(_)))
+    });
+    groups
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .map(|term| match term {
+                    DevSearchTerm::Lang(v) | DevSearchTerm::Keyword(v) => v.as_str(),
+                })
+                .collect::<Vec<&str>>()
+                .join(" or ")
+        })
+        .collect::<Vec<String>>()
+        .join(" + ")
+}
+
+/// Calculates the number of pages from the total number of hits in ES response and
+/// builds links to the previous and next pages for the same search with the same filters.
+fn pagination(
+    devs: &DevSearchResponse,
+    raw_search: &str,
+    filters: &DevFilters,
+    page: usize,
+) -> Pagination {
+    // ES caps the total at 10,000 by default, which is about as much as can be browsed anyway
+    let total_hits = devs.hits.total.value;
+    let total_pages = total_hits
+        .div_ceil(elastic::MAX_DEVS_PER_SEARCH)
+        .min(MAX_PAGE);
+
+    Pagination {
+        total_hits,
+        page,
+        total_pages,
+        truncated: total_hits > total_pages * elastic::MAX_DEVS_PER_SEARCH,
+        prev_url: if page > 1 && page <= total_pages {
+            Some(filters::search_url(raw_search, filters, page - 1))
+        } else {
+            None
+        },
+        next_url: if page < total_pages {
+            Some(filters::search_url(raw_search, filters, page + 1))
+        } else {
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html::{query_within_limits, split_query_string, MAX_SEARCH_LEN, PAGE_PARAM};
+
+    #[test]
+    fn longest_next_url_routes() {
+        // the longest search with every filter set to its longest value
+        let raw_search = "r".repeat(MAX_SEARCH_LEN);
+        let dev_filters = DevFilters {
+            location: Some("z".repeat(filters::MAX_LOCATION_LEN)),
+            hireable: Some(false),
+            active_since: Some("2021-01-31".to_owned()),
+            min_loc: Some(filters::MAX_MIN_LOC),
+        };
+        let devs = DevSearchResponse::from_es(serde_json::json!({
+            "hits": {"total": {"value": elastic::MAX_RESULT_WINDOW}, "hits": []}
+        }))
+        .unwrap();
+
+        let next_url = pagination(&devs, &raw_search, &dev_filters, MAX_PAGE - 1)
+            .next_url
+            .unwrap();
+
+        // the router gets the query string as it is in the link
+        let url_query = next_url.trim_start_matches("/?");
+        assert!(query_within_limits(url_query), "{}", url_query);
+
+        let (search, params) = split_query_string(url_query);
+        assert_eq!(search, raw_search);
+        assert_eq!(params[PAGE_PARAM], MAX_PAGE.to_string());
+        let parsed = filters::from_params(&params).unwrap();
+        assert_eq!(parsed.location, dev_filters.location);
+        assert_eq!(parsed.min_loc, dev_filters.min_loc);
+    }
+}
@@ -1,68 +1,168 @@
 use crate::config::Config;
-use crate::elastic;
-use html_data::{HtmlData, KeywordMetadata};
-use regex::Regex;
-use tracing::{info, warn};
+use crate::elastic::{self, DevFilters};
+use crate::error::StmError;
+use chrono::{DateTime, SecondsFormat, Utc};
+use html_data::{HtmlData, KeywordMetadata, SearchSuggestion};
+use search_query::{SearchClause, SearchTerm, TermPrefix};
+use serde_json::Value;
+use std::collections::HashMap;
+use tracing::{error, info, warn};
+use urlencoding::decode;
 
+mod chart;
+mod compare;
 mod dev;
+pub(crate) mod dev_doc;
+mod feed;
+mod filters;
 mod home;
-mod html_data;
+pub(crate) mod html_data;
+pub(crate) mod json_doc;
 mod keyword;
 mod related;
+mod repo;
+mod search_query;
+mod sitemap;
 mod stats;
 
+pub(crate) use feed::FEED_PATH;
+
 const MAX_NUMBER_OF_VALID_SEARCH_TERMS: usize = 4;
 const MAX_NUMBER_OF_SEARCH_TERMS_TO_CHECK: usize = 6;
+/// Max number of "did you mean" suggestions per unknown search term
+const MAX_SUGGESTIONS_PER_TERM: usize = 3;
+/// A suggestion with this score or higher is used instead of the unknown term if it is the only one
+/// that strong, e.g. `tokoi` -> `tokio` scores 0.8. See ES term suggester docs for how the score is calculated.
+const STRONG_SUGGESTION_SCORE: f64 = 0.75;
+/// Popular languages that are searched for by their name, e.g. `rust` in `rust tokio`. Any other term without a prefix
+/// is assumed to be a keyword until it is checked in ES. A wrong guess costs another request to ES.
+const LIKELY_LANGS: [&str; 20] = [
+    "c",
+    "c#",
+    "c++",
+    "css",
+    "dart",
+    "elixir",
+    "go",
+    "haskell",
+    "html",
+    "java",
+    "javascript",
+    "kotlin",
+    "php",
+    "python",
+    "ruby",
+    "rust",
+    "scala",
+    "shell",
+    "swift",
+    "typescript",
+];
+/// The name of the query string param with the page number, e.g. `?rust&page=2`
+pub(crate) const PAGE_PARAM: &str = "page";
+/// The last page that can be fetched from ES with `from` + `size`
+const MAX_PAGE: usize = elastic::MAX_RESULT_WINDOW / elastic::MAX_DEVS_PER_SEARCH;
+/// Max length of the free-form search part of the decoded query string, e.g. `rust tokio`
+const MAX_SEARCH_LEN: usize = 100;
+/// Max length of the entire decoded query string. It fits the longest link the app generates:
+/// a search of `MAX_SEARCH_LEN` with all the filters and the last page number.
+const MAX_QUERY_LEN: usize = 300;
 
 /// Routes HTML requests to processing modules. Returns HTML response and TTL value in seconds.
+/// The path is decoded, but the query string is as it came in the request, see `split_query_string`.
 pub(crate) async fn html(
     config: &Config,
     url_path: String,
     url_query: String,
-) -> Result<HtmlData, ()> {
+) -> Result<HtmlData, StmError> {
     // prepare a common structure for feeding into Tera templates
-    let html_data = HtmlData {
-        raw_search: url_query.clone(),
-        related: None,
-        devs: None,
-        keywords: Vec::new(),
-        keywords_meta: Vec::new(),
-        langs: Vec::new(),
-        keywords_str: None,
-        stats: None,
-        template_name: "404.html".to_owned(),
-        ttl: 600,
-        http_resp_code: 404,
-        meta_robots: None,
-        login_str: None,
-        stats_jobs: None,
-    };
+    let html_data = HtmlData::new(decode_query(&url_query));
 
     // return 404 for requests that are too long or for some resource related to the static pages
-    if url_path.len() > 100 || url_query.len() > 100 {
+    if url_path.len() > 100 {
+        warn!("Invalid request: {} / {}", url_path, url_query);
+        return Ok(html_data);
+    }
+
+    // is it a comparison of several devs?
+    // it has its own limit for the list of logins, which can be longer than a search
+    if url_path.trim_end_matches("/") == compare::COMPARE_PATH {
+        // return compare page
+        return compare::html(config, &url_query, html_data).await;
+    }
+
+    if !query_within_limits(&url_query) {
         warn!("Invalid request: {} / {}", url_path, url_query);
         return Ok(html_data);
     }
-    if url_path.starts_with("/about/") || url_path.starts_with("/robots.txt") {
+    if url_path.starts_with("/about/") {
         warn!("Static resource request: {}", url_path);
         return Ok(html_data);
     }
 
+    // sitemaps and robots.txt for search engines
+    match url_path.trim_end_matches("/") {
+        sitemap::ROBOTS_TXT_PATH => return Ok(sitemap::robots_txt(config, html_data)),
+        sitemap::SITEMAP_INDEX_PATH => return sitemap::index_xml(config, html_data).await,
+        sitemap::SITEMAP_LANGS_PATH => return sitemap::langs_xml(config, html_data).await,
+        sitemap::SITEMAP_DEVS_PATH => {
+            // the login of the last dev in the previous sitemap, if any, e.g. `after=rimutaka`
+            let (_, query_params) = split_query_string(&url_query);
+            let after = query_params.get(sitemap::AFTER_PARAM).cloned();
+            if let Some(after) = &after {
+                if after.is_empty() || config.no_sql_string_invalidation_regex.is_match(after) {
+                    warn!("Invalid sitemap login: {}", after);
+                    return Ok(html_data);
+                }
+            }
+            return sitemap::devs_xml(config, after, html_data).await;
+        }
+        _ => {}
+    }
+
+    // Atom feeds of the latest devs for a search, e.g. `/feed.atom?q=rust tokio`
+    if url_path.trim_end_matches("/") == feed::FEED_PATH {
+        return feed::atom(config, &url_query, html_data).await;
+    }
+
     // is it a stats page?
     if url_path.trim_end_matches("/") == "/_stats" {
         // return stats page
-        return Ok(stats::html(config, html_data).await?);
+        return stats::html(config, html_data).await;
     }
 
     // is it a related keyword search?
     if url_path.trim_end_matches("/") == "/_related" {
         // return related keywords page
-        return Ok(related::html(config, url_query, html_data).await?);
+        return related::html(config, decode_query(&url_query), html_data).await;
     }
 
-    // check if there is a path - it can be the developer login
+    // check if there is a path - it can be the developer login or a repo
     // there shouldn't be any other paths at this stage
     if url_path.len() > 1 {
+        // a repo path has 2 parts: `/{owner}/{repo}`, e.g. /rimutaka/stm
+        let path_parts = url_path
+            .trim()
+            .trim_end_matches("/")
+            .trim_start_matches("/")
+            .split('/')
+            .collect::<Vec<&str>>();
+        if path_parts.len() == 2 {
+            // both parts must be valid values for ES, same as dev logins
+            if path_parts
+                .iter()
+                .any(|v| v.is_empty() || config.no_sql_string_invalidation_regex.is_match(v))
+            {
+                warn!("Invalid repo path: {}", url_path);
+                return Ok(html_data);
+            }
+
+            // return repo page
+            let owner = path_parts[0].to_string();
+            let repo_name = path_parts[1].to_string();
+            return repo::html(config, owner, repo_name, html_data).await;
+        }
+
         // it must be a dev login that matches the one on github, e.g. rimutaka
         let login = url_path
             .trim()
@@ -78,153 +178,748 @@ pub(crate) async fn html(
         }
 
         // return dev profile page
-        return Ok(dev::html(config, login, html_data).await?);
+        return dev::html(config, login, html_data).await;
     }
 
+    search(config, url_query, html_data).await
+}
+
+/// Runs a keyword search for the query string or returns the homepage if there is nothing to search for.
+/// E.g. `rust tokio&hireable=true&page=2`.
+async fn search(
+    config: &Config,
+    url_query: String,
+    html_data: HtmlData,
+) -> Result<HtmlData, StmError> {
+    // separate the search terms from named params, e.g. `rust tokio&page=2`
+    let (search_query, query_params) = split_query_string(&url_query);
+    let html_data = HtmlData {
+        raw_search: search_query.clone(),
+        ..html_data
+    };
+
+    // page numbers are 1-based and cannot go past the max number of results ES can return
+    let page = match query_params.get(PAGE_PARAM) {
+        None => 1,
+        Some(v) => match v.parse::<usize>() {
+            Ok(page) if (1..=MAX_PAGE).contains(&page) => page,
+            _ => {
+                warn!("Invalid page: {}", v);
+                return Ok(html_data);
+            }
+        },
+    };
+
+    // filters apply to the search results and the list of devs on the home page
+    let dev_filters = filters::from_params(&query_params)?;
+    let html_data = HtmlData {
+        filters: filters::chips(&search_query, &dev_filters),
+        ..html_data
+    };
+
     // is there something in the query string?
-    if url_query.len() > 1 {
-        // split the query into parts using a few common separators
-        let rgx = Regex::new(r#"[#\-\._0-9a-zA-Z]+"#).expect("Wrong search terms regex!");
-        let search_terms = rgx
-            .find_iter(&url_query)
-            .map(|v| v.as_str().to_owned())
-            .collect::<Vec<String>>();
-        info!("Terms: {:?}", search_terms);
-
-        // normalise and dedupe the search terms
-        let mut search_terms = search_terms
+    if search_query.len() > 1 {
+        let resolved = resolve_search(
+            config,
+            &search_query,
+            &dev_filters,
+            page,
+            elastic::search_devs,
+        )
+        .await?;
+
+        // update keyword metadata for the output
+        // they are in the same order as the search terms for URL consistency
+        let html_data = HtmlData {
+            keywords_meta: resolved.keywords_meta,
+            ..html_data
+        };
+
+        // run a keyword search
+        return keyword::html(
+            config,
+            resolved.terms,
+            &dev_filters,
+            page,
+            resolved.devs,
+            html_data,
+        )
+        .await;
+    }
+
+    // return the homepage if there is nothing else
+    home::html(config, &dev_filters, html_data).await
+}
+
+/// Search terms checked against ES with the dev search results if they were fetched in the same request
+struct ResolvedSearch {
+    terms: elastic::DevSearchTerms,
+    /// How every search term was understood, in the same order as in the search
+    keywords_meta: Vec<KeywordMetadata>,
+    devs: Option<Value>,
+}
+
+/// Parses the search, checks what every term is in ES and suggests corrections for unknown terms.
+/// `dev_search` builds the dev search for the resolved terms, e.g. `elastic::search_devs`. It goes into the same request
+/// as the checks if the terms can be predicted without checking them.
+async fn resolve_search(
+    config: &Config,
+    search_query: &str,
+    dev_filters: &DevFilters,
+    page: usize,
+    dev_search: fn(&elastic::DevSearchTerms, &DevFilters, usize) -> elastic::query::Search,
+) -> Result<ResolvedSearch, StmError> {
+    // parse the query into groups of terms, e.g. `rust (tokio OR async-std) -actix`
+    let clauses = search_query::parse(search_query)?;
+    info!("Clauses: {:?}", clauses);
+
+    // position of every term in the search as (clause_idx, term_idx)
+    let term_positions = clauses
+        .iter()
+        .enumerate()
+        .flat_map(|(clause_idx, clause)| {
+            (0..clause.terms.len()).map(move |term_idx| (clause_idx, term_idx))
+        })
+        .collect::<Vec<(usize, usize)>>();
+
+    // all terms within the limit are checked in a single ES request, the rest are ignored
+    let mut batch = elastic::MultiSearch::default();
+    let term_counts = term_positions
+        .iter()
+        .take(MAX_NUMBER_OF_SEARCH_TERMS_TO_CHECK)
+        .map(|(clause_idx, term_idx)| {
+            add_term_counts(&mut batch, config, &clauses[*clause_idx].terms[*term_idx])
+        })
+        .collect::<Vec<Vec<usize>>>();
+
+    // the dev search goes into the same request on the assumption that the checks will confirm the predicted terms
+    let predicted_terms = predict_dev_search_terms(&clauses);
+    let devs_response_idx = predicted_terms
+        .as_ref()
+        .map(|terms| batch.add(&config.dev_idx, dev_search(terms, dev_filters, page)));
+    let mut responses = batch.run(&config.es).await?;
+
+    // check every search term for what type of a term it is
+    let mut checked_terms = term_counts
+        .iter()
+        .zip(term_positions.iter())
+        .map(|(counts, (clause_idx, term_idx))| {
+            Ok(classify_search_term(
+                &clauses[*clause_idx].terms[*term_idx],
+                &doc_counts(&responses, counts)?,
+            ))
+        })
+        .collect::<Result<Vec<(KeywordMetadata, Option<elastic::DevSearchTerm>)>, StmError>>()?;
+
+    // unknown terms may be misspelled, unless they would be over the limit of valid terms anyway
+    let mut known_terms: usize = 0;
+    let mut unknown_terms: Vec<usize> = Vec::new();
+    for (term_pos, (_, dev_search_term)) in checked_terms.iter().enumerate() {
+        if dev_search_term.is_some() {
+            known_terms += 1;
+        } else if known_terms < MAX_NUMBER_OF_VALID_SEARCH_TERMS {
+            unknown_terms.push(term_pos);
+        }
+    }
+    let suggested_terms = suggest_search_terms(
+        config,
+        &clauses,
+        unknown_terms
             .iter()
-            .map(|v| v.to_lowercase())
-            .collect::<Vec<String>>();
-        search_terms.dedup();
-        let search_terms = search_terms;
-
-        // will contain values that matches language names
-        let mut langs: Vec<String> = Vec::new();
-        // will contain the list of keywords to search for
-        let mut keywords: Vec<String> = Vec::new();
-        // every search term submitted by the user with the meta of how it was understood
-        let mut keywords_meta: Vec<KeywordMetadata> = Vec::new();
-
-        // check every search term for what type of a term it is
-        for (search_term_idx, search_term) in search_terms.into_iter().enumerate() {
-            // searches with a tailing or leading . should be cleaned up
-            // it may be possible to have a lead/trail _, maybe
-            // I havn't seen a lead/trail - anywhere
-            let search_term = search_term.trim_matches('.').trim_matches('-').to_owned();
+            .map(|term_pos| {
+                let (clause_idx, term_idx) = term_positions[*term_pos];
+                let meta = std::mem::replace(
+                    &mut checked_terms[*term_pos].0,
+                    KeywordMetadata::new(&clauses[clause_idx].terms[term_idx]),
+                );
+                ((clause_idx, term_idx), meta)
+            })
+            .collect(),
+        dev_filters,
+    )
+    .await?;
+    for (term_pos, suggested_term) in unknown_terms.into_iter().zip(suggested_terms) {
+        checked_terms[term_pos] = suggested_term;
+    }
+
+    // will contain the terms for the ES query grouped the same way as in the search
+    let mut terms = elastic::DevSearchTerms::default();
+    // every search term submitted by the user with the meta of how it was understood
+    let mut keywords_meta: Vec<KeywordMetadata> = Vec::new();
+    // the checked terms in the same order as in the search
+    let mut checked_terms = checked_terms.into_iter();
+    // a counter for limiting the number of search terms
+    let mut valid_terms: usize = 0;
+    // the next index for a group of alternatives
+    let mut or_group_idx: usize = 0;
+
+    for clause in &clauses {
+        // only groups of alternatives get an index, negated groups are just a list of exclusions
+        let or_group = if clause.terms.len() > 1 && !clause.negated {
+            or_group_idx += 1;
+            Some(or_group_idx - 1)
+        } else {
+            None
+        };
+
+        let mut group: Vec<elastic::DevSearchTerm> = Vec::new();
 
+        for search_term in &clause.terms {
             // limit the list of valid search terms to 4
-            if search_term_idx >= MAX_NUMBER_OF_SEARCH_TERMS_TO_CHECK
-                || keywords.len() + langs.len() >= MAX_NUMBER_OF_VALID_SEARCH_TERMS
-            {
-                // this term got no results and will be ignored
-                keywords_meta.push(KeywordMetadata {
-                    search_term: search_term,
-                    es_keyword_count: 0,
-                    es_package_count: 0,
-                    es_language_count: 0,
-                    unknown: false,
-                    too_many: true,
-                });
+            let (meta, dev_search_term) = match checked_terms.next() {
+                Some(v) if valid_terms < MAX_NUMBER_OF_VALID_SEARCH_TERMS => v,
+                // this term was not checked or will be ignored
+                _ => (
+                    KeywordMetadata {
+                        too_many: true,
+                        ..KeywordMetadata::new(search_term)
+                    },
+                    None,
+                ),
+            };
 
-                continue;
+            if let Some(dev_search_term) = dev_search_term {
+                valid_terms += 1;
+                group.push(dev_search_term);
             }
 
-            // searching for a keyword is different from searching for a fully qualified package name
-            // e.g. xml vs System.XML vs SomeVendor.XML
-            let (fields, can_be_lang) = if search_term.contains(".") {
-                // this is a fully qualified name and cannot be a language
-                (
-                    vec!["report.tech.refs.k.keyword", "report.tech.pkgs.k.keyword"],
-                    false,
-                )
-            } else {
-                // this is a keyword, which may be all there is, but it will be in _kw field anyway
-                // this can also be a language
-                (
-                    vec![
-                        "report.tech.language.keyword",
-                        "report.tech.refs_kw.k.keyword",
-                        "report.tech.pkgs_kw.k.keyword",
-                    ],
-                    true,
-                )
-            };
+            keywords_meta.push(KeywordMetadata {
+                negated: clause.negated,
+                or_group,
+                ..meta
+            });
+        }
 
-            // get the doc counts for the term
-            let counts = elastic::matching_doc_counts(
-                &config.es_url,
-                &config.dev_idx,
-                fields,
-                &search_term,
-                &config.no_sql_string_invalidation_regex,
-            )
-            .await?;
-            info!("search_term {}: {:?}", search_term, counts);
-
-            if can_be_lang {
-                // there are 3 search results if it can be a language
-
-                // store the metadata for this search term
-                keywords_meta.push(KeywordMetadata {
-                    search_term: search_term.clone(),
-                    es_keyword_count: counts[1] + counts[2],
-                    es_package_count: 0,
-                    es_language_count: counts[0],
-                    unknown: (counts[0] + counts[1] + counts[2]) == 0,
-                    too_many: false,
-                });
-
-                // extract useful terms to be used in the search
-                // this may be a language
-                if counts[0] > 0 {
-                    langs.push(search_term);
-                } else if counts[1] > 0 || counts[2] > 0 {
-                    // add it to the list of keywords if there is still room
-                    keywords.push(search_term);
-                }
-            } else if counts[0] > 0 || counts[1] > 0 {
-                // only 2 results if it looks like a package
-
-                // store the metadata for this search term
-                keywords_meta.push(KeywordMetadata {
-                    search_term: search_term.clone(),
-                    es_keyword_count: counts[0],
-                    es_package_count: counts[1],
-                    es_language_count: 0,
-                    unknown: (counts[0] + counts[1]) == 0,
-                    too_many: false,
-                });
-
-                // .-notation, so can't be a language, but can be a keyword
-                // add it to the list of keywords if there is still room
-                keywords.push(search_term);
+        // unknown terms are dropped, which may leave the group empty
+        if group.is_empty() {
+            continue;
+        }
+        if clause.negated {
+            terms.must_not.append(&mut group);
+        } else {
+            terms.must.push(group);
+        }
+    }
+
+    // the dev search results can only be used if the terms were the same as predicted
+    let devs = match devs_response_idx {
+        Some(idx) if predicted_terms.as_ref() == Some(&terms) => Some(responses[idx].take()),
+        _ => None,
+    };
+
+    Ok(ResolvedSearch {
+        terms,
+        keywords_meta,
+        devs,
+    })
+}
+
+/// Returns true if the decoded query string and its search part are not too long to process.
+/// Links to other pages of the same search, e.g. `rust%20tokio&hireable=true&page=2`, must always pass.
+fn query_within_limits(url_query: &str) -> bool {
+    decode_query(url_query).len() <= MAX_QUERY_LEN
+        && split_query_string(url_query).0.len() <= MAX_SEARCH_LEN
+}
+
+/// Percent-decodes the query string or a part of it. An invalid encoding is decoded into an empty string.
+fn decode_query(url_query: &str) -> String {
+    decode(url_query).unwrap_or_default()
+}
+
+/// Splits the raw query string into the free-form search part and `name=value` params and decodes every name and value
+/// separately, so that an encoded `&` or `=` stays inside the value. E.g. `rust%20tokio&location=Trinidad%20%26%20Tobago&page=2`
+/// returns `rust tokio` and `{location: Trinidad & Tobago, page: 2}`. Anything that doesn't look like a param is
+/// considered a part of the search.
+fn split_query_string(url_query: &str) -> (String, HashMap<String, String>) {
+    let mut search: Vec<String> = Vec::new();
+    let mut params: HashMap<String, String> = HashMap::new();
+
+    for part in url_query.split('&') {
+        let param = part
+            .split_once('=')
+            .map(|(name, value)| (decode_query(name), value))
+            .filter(|(name, _)| {
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+            });
+        match param {
+            Some((name, value)) => {
+                params.insert(name, decode_query(value).trim().to_owned());
+            }
+            None => search.push(decode_query(part)),
+        }
+    }
+
+    (search.join(" ").trim().to_owned(), params)
+}
+
+/// Adds the doc counts for every field the term can be in to the batch.
+/// Returns the positions of the responses for `classify_search_term`.
+fn add_term_counts(
+    batch: &mut elastic::MultiSearch,
+    config: &Config,
+    search_term: &SearchTerm,
+) -> Vec<usize> {
+    search_term_fields(search_term)
+        .into_iter()
+        .map(|field| batch.add_doc_count(&config.dev_idx, field, &search_term.value))
+        .collect()
+}
+
+/// Returns the doc counts from the responses at the positions returned by `add_term_counts`.
+fn doc_counts(responses: &[Value], counts: &[usize]) -> Result<Vec<usize>, StmError> {
+    counts
+        .iter()
+        .map(|v| elastic::hits_total(&responses[*v]))
+        .collect()
+}
+
+/// Checks what type of a term it is by the number of matching docs in different fields of dev idx.
+/// The counts must be in the same order as the fields from `search_term_fields`.
+/// Returns the metadata for the term and the term for the ES query if there were any matches.
+fn classify_search_term(
+    search_term: &SearchTerm,
+    counts: &[usize],
+) -> (KeywordMetadata, Option<elastic::DevSearchTerm>) {
+    let value = &search_term.value;
+    let meta = KeywordMetadata::new(search_term);
+    info!("search_term {}: {:?}", value, counts);
+
+    let (meta, dev_search_term) = match search_term.prefix {
+        TermPrefix::Lang => (
+            KeywordMetadata {
+                es_language_count: counts[0],
+                ..meta
+            },
+            if counts[0] > 0 {
+                Some(elastic::DevSearchTerm::Lang(value.clone()))
+            } else {
+                None
+            },
+        ),
+        // .-notation, so can't be a language, but can be a keyword
+        _ if value.contains(".") => (
+            KeywordMetadata {
+                es_keyword_count: counts[0],
+                es_package_count: counts[1],
+                ..meta
+            },
+            if counts[0] > 0 || counts[1] > 0 {
+                Some(elastic::DevSearchTerm::Keyword(value.clone()))
+            } else {
+                None
+            },
+        ),
+        TermPrefix::Pkg => (
+            KeywordMetadata {
+                es_keyword_count: counts[0] + counts[1],
+                ..meta
+            },
+            if counts[0] > 0 || counts[1] > 0 {
+                Some(elastic::DevSearchTerm::Keyword(value.clone()))
             } else {
-                // this term got no results and will be ignored
-                keywords_meta.push(KeywordMetadata {
-                    search_term: search_term,
-                    es_keyword_count: 0,
-                    es_package_count: 0,
-                    es_language_count: 0,
-                    unknown: true,
-                    too_many: false,
-                });
+                None
+            },
+        ),
+        // there are 3 search results if it can be a language
+        TermPrefix::Any => (
+            KeywordMetadata {
+                es_keyword_count: counts[1] + counts[2],
+                es_language_count: counts[0],
+                ..meta
+            },
+            // this may be a language
+            if counts[0] > 0 {
+                Some(elastic::DevSearchTerm::Lang(value.clone()))
+            } else if counts[1] > 0 || counts[2] > 0 {
+                Some(elastic::DevSearchTerm::Keyword(value.clone()))
+            } else {
+                None
+            },
+        ),
+    };
+
+    // a term with no results will be ignored
+    (
+        KeywordMetadata {
+            unknown: dev_search_term.is_none(),
+            ..meta
+        },
+        dev_search_term,
+    )
+}
+
+/// Returns the likely terms for the dev search before checking them in ES, assuming all the terms exist in ES.
+/// The type of a term is known if it has a prefix or a `.`, e.g. `lang:rust pkg:tokio` or `System.Xml`.
+/// Any other term is a language if it is one of `LIKELY_LANGS` or a keyword otherwise, e.g. `rust tokio`.
+/// Returns `None` if there is nothing to search for.
+fn predict_dev_search_terms(clauses: &[SearchClause]) -> Option<elastic::DevSearchTerms> {
+    let mut terms = elastic::DevSearchTerms::default();
+    let mut valid_terms: usize = 0;
+
+    for clause in clauses {
+        let mut group: Vec<elastic::DevSearchTerm> = Vec::new();
+
+        // the same limit as for the checked terms
+        for search_term in clause.terms.iter() {
+            if valid_terms >= MAX_NUMBER_OF_VALID_SEARCH_TERMS {
+                break;
             }
+            let value = search_term.value.clone();
+            group.push(match search_term.prefix {
+                TermPrefix::Lang => elastic::DevSearchTerm::Lang(value),
+                TermPrefix::Pkg => elastic::DevSearchTerm::Keyword(value),
+                TermPrefix::Any if value.contains(".") => elastic::DevSearchTerm::Keyword(value),
+                TermPrefix::Any if is_likely_lang(&value) => elastic::DevSearchTerm::Lang(value),
+                TermPrefix::Any => elastic::DevSearchTerm::Keyword(value),
+            });
+            valid_terms += 1;
         }
 
-        // update keyword metadata for the output
-        // they should be sorted in the same order as the search terms, which were
-        // sorted earlier
-        // the sort order has to be enforced for URL consistency
-        let html_data = HtmlData {
-            keywords_meta,
-            ..html_data
+        if group.is_empty() {
+            continue;
+        }
+        if clause.negated {
+            terms.must_not.append(&mut group);
+        } else {
+            terms.must.push(group);
+        }
+    }
+
+    // a search with exclusions only is not run at all
+    if terms.must.is_empty() {
+        return None;
+    }
+
+    Some(terms)
+}
+
+/// Returns true if the term is one of `LIKELY_LANGS`.
+fn is_likely_lang(value: &str) -> bool {
+    LIKELY_LANGS.iter().any(|v| v.eq_ignore_ascii_case(value))
+}
+
+/// Returns the list of dev idx fields the term should be looked up in.
+fn search_term_fields(search_term: &SearchTerm) -> Vec<&'static str> {
+    // searching for a keyword is different from searching for a fully qualified package name
+    // e.g. xml vs System.XML vs SomeVendor.XML
+    // the prefix, if any, narrows it down to languages or keywords
+    match search_term.prefix {
+        TermPrefix::Lang => vec!["report.tech.language.keyword"],
+        // this is a fully qualified name and cannot be a language
+        _ if search_term.value.contains(".") => {
+            vec!["report.tech.refs.k.keyword", "report.tech.pkgs.k.keyword"]
+        }
+        TermPrefix::Pkg => vec![
+            "report.tech.refs_kw.k.keyword",
+            "report.tech.pkgs_kw.k.keyword",
+        ],
+        // this is a keyword, which may be all there is, but it will be in _kw field anyway
+        // this can also be a language
+        TermPrefix::Any => vec![
+            "report.tech.language.keyword",
+            "report.tech.refs_kw.k.keyword",
+            "report.tech.pkgs_kw.k.keyword",
+        ],
+    }
+}
+
+/// Looks up known terms similar to the unknown search terms, e.g. `tokio` for `tokoi`, in a single ES request.
+/// A term is replaced with the suggestion if there is exactly one strong suggestion and the term was not quoted.
+/// All replacements are checked in another single request.
+/// Returns the metadata with the suggestions and the term for the ES query if it was replaced for every term in the same order.
+async fn suggest_search_terms(
+    config: &Config,
+    clauses: &[SearchClause],
+    unknown_terms: Vec<((usize, usize), KeywordMetadata)>,
+    filters: &DevFilters,
+) -> Result<Vec<(KeywordMetadata, Option<elastic::DevSearchTerm>)>, StmError> {
+    let mut batch = elastic::MultiSearch::default();
+    for ((clause_idx, term_idx), _) in &unknown_terms {
+        let search_term = &clauses[*clause_idx].terms[*term_idx];
+        batch.add_similar_terms(
+            &config.dev_idx,
+            &search_term_fields(search_term),
+            &search_term.value,
+            MAX_SUGGESTIONS_PER_TERM,
+        );
+    }
+    let responses = batch.run(&config.es).await?;
+
+    // replace the terms only if there is no doubt about them
+    let mut corrections = elastic::MultiSearch::default();
+    // the suggestions and the corrected term with the positions of its doc counts for every unknown term
+    let mut suggested_terms = Vec::new();
+    for (((clause_idx, term_idx), _), response) in unknown_terms.iter().zip(responses.iter()) {
+        let search_term = &clauses[*clause_idx].terms[*term_idx];
+        let suggestions =
+            elastic::similar_terms(response, &search_term.value, MAX_SUGGESTIONS_PER_TERM)?;
+        info!("Suggestions for {}: {:?}", search_term.value, suggestions);
+
+        let strong = suggestions
+            .iter()
+            .filter(|v| v.score >= STRONG_SUGGESTION_SCORE)
+            .collect::<Vec<&elastic::TermSuggestion>>();
+        let correction = if !search_term.exact && strong.len() == 1 {
+            let corrected = SearchTerm {
+                value: strong[0].text.clone(),
+                ..search_term.clone()
+            };
+            let counts = add_term_counts(&mut corrections, config, &corrected);
+            Some((corrected, counts))
+        } else {
+            None
         };
+        suggested_terms.push((suggestions, correction));
+    }
+    let correction_responses = corrections.run(&config.es).await?;
 
-        // run a keyword search
-        return Ok(keyword::html(config, keywords, langs, html_data).await?);
+    let mut results: Vec<(KeywordMetadata, Option<elastic::DevSearchTerm>)> = Vec::new();
+    for (((clause_idx, term_idx), meta), (suggestions, correction)) in
+        unknown_terms.into_iter().zip(suggested_terms)
+    {
+        let search_term = &clauses[clause_idx].terms[term_idx];
+
+        // a link to the same search with the term replaced or `None` if the search would get too long to follow
+        let suggestion_url = |value: &str, exact: bool| {
+            let term = SearchTerm {
+                value: value.to_owned(),
+                exact,
+                ..search_term.clone()
+            };
+            let search = search_query::replace_term(clauses, clause_idx, term_idx, term);
+            if search.len() > MAX_SEARCH_LEN {
+                return None;
+            }
+            Some(filters::search_url(&search, filters, 1))
+        };
+
+        // the correction is used only if it is a known term
+        if let Some((corrected, counts)) = correction {
+            let counts = doc_counts(&correction_responses, &counts)?;
+            if let (corrected_meta, Some(dev_search_term)) =
+                classify_search_term(&corrected, &counts)
+            {
+                info!("Corrected {} to {}", search_term.value, corrected.value);
+                results.push((
+                    KeywordMetadata {
+                        // quoting the original term stops it from being corrected again
+                        corrected_from: suggestion_url(&search_term.value, true).map(|url| {
+                            SearchSuggestion {
+                                search_term: search_term.value.clone(),
+                                url,
+                            }
+                        }),
+                        ..corrected_meta
+                    },
+                    Some(dev_search_term),
+                ));
+                continue;
+            }
+        }
+
+        results.push((
+            KeywordMetadata {
+                suggestions: suggestions
+                    .iter()
+                    .filter_map(|v| {
+                        Some(SearchSuggestion {
+                            search_term: v.text.clone(),
+                            url: suggestion_url(&v.text, false)?,
+                        })
+                    })
+                    .collect(),
+                ..meta
+            },
+            None,
+        ));
     }
 
-    // return the homepage if there is nothing else
-    return Ok(home::html(config, html_data).await?);
+    Ok(results)
+}
+
+/// Parses an ES timestamp, e.g. `2021-03-21T03:12:49.943327166+00:00`. Returns `None` if it's not a valid RFC 3339 date.
+pub(crate) fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(v) => Some(v.with_timezone(&Utc)),
+        Err(e) => {
+            warn!("Invalid timestamp {}: {}", timestamp, e);
+            None
+        }
+    }
+}
+
+/// Formats the date as W3C datetime for sitemaps and feeds, e.g. `2021-03-21T03:12:49Z`. It is also valid RFC 3339.
+pub(crate) fn w3c_datetime(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Returns a page for a client that sent too many requests. It must not be cached by CloudFront
+/// because the limit is per client.
+pub(crate) fn too_many_requests_html(url_query: String) -> HtmlData {
+    HtmlData {
+        error_msg: Some("Too many requests".to_owned()),
+        template_name: "429.html".to_owned(),
+        ttl: 0,
+        http_resp_code: 429,
+        meta_robots: Some("noindex".to_owned()),
+        ..HtmlData::new(decode_query(&url_query))
+    }
+}
+
+/// Returns data for the error page. The details of the error are logged, but only a generic message is shown to the user.
+pub(crate) fn error_html(err: &StmError, url_query: String) -> HtmlData {
+    error!("Request failed with {}", err);
+
+    HtmlData {
+        error_msg: Some(err.user_message().to_owned()),
+        template_name: "error.html".to_owned(),
+        ttl: err.ttl(),
+        http_resp_code: err.http_resp_code(),
+        meta_robots: Some("noindex".to_owned()),
+        ..HtmlData::new(decode_query(&url_query))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_separators_stay_in_values() {
+        // as sent by the filters form
+        let (search, params) =
+            split_query_string("rust%20a%3Db&location=Trinidad%20%26%20Tobago&min_loc=100");
+        assert_eq!(search, "rust a=b");
+        assert_eq!(params["location"], "Trinidad & Tobago");
+        assert_eq!(params["min_loc"], "100");
+        assert_eq!(params.len(), 2);
+
+        let filters = filters::from_params(&params).unwrap();
+        assert_eq!(filters.location.as_deref(), Some("trinidad & tobago"));
+
+        // and back into a link that splits the same way
+        let url = filters::search_url(&search, &filters, 2);
+        let (search, params) = split_query_string(url.trim_start_matches("/?"));
+        assert_eq!(search, "rust a=b");
+        assert_eq!(params["location"], "trinidad & tobago");
+        assert_eq!(params[PAGE_PARAM], "2");
+    }
+
+    #[test]
+    fn filter_limits() {
+        let filters = |query: &str| filters::from_params(&split_query_string(query).1);
+
+        assert_eq!(
+            filters("min_loc=4294967295").unwrap().min_loc,
+            Some(filters::MAX_MIN_LOC)
+        );
+        // it would overflow ES long
+        assert!(filters("min_loc=18446744073709551615").is_err());
+        assert!(filters("min_loc=0").is_err());
+
+        let location = "z".repeat(filters::MAX_LOCATION_LEN);
+        assert!(filters(&["location=", &location].concat()).is_ok());
+        assert!(filters(&["location=", &location, "z"].concat()).is_err());
+    }
+
+    #[test]
+    fn predicted_terms() {
+        use elastic::DevSearchTerm::{Keyword, Lang};
+
+        let terms =
+            predict_dev_search_terms(&search_query::parse("Rust tokio -go").unwrap()).unwrap();
+        assert_eq!(
+            terms.must,
+            vec![
+                vec![Lang("rust".to_owned())],
+                vec![Keyword("tokio".to_owned())]
+            ]
+        );
+        assert_eq!(terms.must_not, vec![Lang("go".to_owned())]);
+    }
+
+    #[tokio::test]
+    async fn dev_route() {
+        let config = Config::with_samples();
+
+        let html_data = html(&config, "/rimutaka".to_owned(), String::new())
+            .await
+            .unwrap();
+        assert_eq!(html_data.template_name, "dev.html");
+        assert_eq!(html_data.http_resp_code, 200);
+        assert_eq!(html_data.login_str.as_deref(), Some("rimutaka"));
+        assert_eq!(html_data.devs.unwrap().hits.total.value, 1);
+
+        // the template shows a not found message
+        let html_data = html(&config, "/nobody".to_owned(), String::new())
+            .await
+            .unwrap();
+        assert_eq!(html_data.template_name, "dev.html");
+        assert_eq!(html_data.devs.unwrap().hits.total.value, 0);
+    }
+
+    #[tokio::test]
+    async fn keyword_route() {
+        let config = Config::with_samples();
+
+        let html_data = html(&config, "/".to_owned(), "rust".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(html_data.template_name, "keyword.html");
+        assert_eq!(html_data.http_resp_code, 200);
+        assert_eq!(html_data.keywords_str.as_deref(), Some("rust"));
+        assert_eq!(html_data.devs.unwrap().hits.total.value, 1);
+
+        let html_data = html(&config, "/".to_owned(), "rust%20-rust".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(html_data.http_resp_code, 200);
+        assert_eq!(html_data.devs.unwrap().hits.total.value, 0);
+
+        // `markdown` is predicted as a keyword, but it is a language in the index
+        let html_data = html(&config, "/".to_owned(), "rust%20markdown".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(html_data.http_resp_code, 200);
+        assert_eq!(html_data.devs.unwrap().hits.total.value, 1);
+
+        // not in the index at all
+        let html_data = html(&config, "/".to_owned(), "cobol".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(html_data.http_resp_code, 404);
+        assert!(html_data.devs.is_none());
+    }
+
+    #[tokio::test]
+    async fn home_route() {
+        let config = Config::with_samples();
+
+        let html_data = html(&config, "/".to_owned(), String::new()).await.unwrap();
+        assert_eq!(html_data.template_name, "home.html");
+        assert_eq!(html_data.http_resp_code, 200);
+        assert!(html_data.stats.is_some());
+        assert!(!html_data.related.unwrap().is_empty());
+        assert_eq!(html_data.devs.unwrap().hits.total.value, 1);
+        assert!(html_data.meta_robots.is_none());
+
+        let html_data = html(&config, "/".to_owned(), "location=atlantis".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(html_data.template_name, "home.html");
+        assert_eq!(html_data.devs.unwrap().hits.total.value, 0);
+        assert_eq!(html_data.meta_robots.as_deref(), Some("noindex"));
+    }
+
+    #[test]
+    fn params_and_search() {
+        let (search, params) = split_query_string("rust&tokio&page=2&C%23=x&=y");
+        assert_eq!(search, "rust tokio C#=x =y");
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[PAGE_PARAM], "2");
+        assert!(query_within_limits("rust&tokio&page=2"));
+        // the limits are for the decoded query
+        assert!(query_within_limits(&"%20".repeat(MAX_QUERY_LEN)));
+        assert!(!query_within_limits(&"r".repeat(MAX_SEARCH_LEN + 1)));
+    }
 }
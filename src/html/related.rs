@@ -1,6 +1,7 @@
 use super::html_data::{HtmlData, RelatedKeywords};
 use crate::config::Config;
 use crate::elastic;
+use crate::error::StmError;
 use regex::Regex;
 use tracing::{error, warn};
 
@@ -8,7 +9,7 @@ pub(crate) async fn html(
     config: &Config,
     keyword: String,
     html_data: HtmlData,
-) -> Result<HtmlData, ()> {
+) -> Result<HtmlData, StmError> {
     // is it a valid format for related keywords search?
     let keyword = keyword.trim().to_string();
 
@@ -30,13 +31,16 @@ pub(crate) async fn html(
     }
 
     // get the data from ES
-    let related = match elastic::related_keywords(&config.es_url, &config.dev_idx, &keyword).await {
-        Err(_) => {
+    let related = match elastic::related_keywords(&config.es, &config.dev_idx, &keyword).await {
+        Err(StmError::Validation(_)) => {
             // the UI shouldn't send any invalid keywords through, but the user or the bot may still try to submit
             // all sorts of values for search. Those should result in a 404 page.
             error!("Keyword search failed for {}", keyword);
             return Ok(html_data);
         }
+        Err(e) => {
+            return Err(e);
+        }
         Ok(v) => v,
     };
 
@@ -0,0 +1,40 @@
+use super::dev_doc::RepoSearchResponse;
+use super::html_data::HtmlData;
+use crate::config::Config;
+use crate::elastic;
+use crate::error::StmError;
+use tracing::info;
+
+/// Returns the repo tech breakdown. Expects a valid owner login and repo name.
+pub(crate) async fn html(
+    config: &Config,
+    owner: String,
+    repo_name: String,
+    html_data: HtmlData,
+) -> Result<HtmlData, StmError> {
+    info!("Generating html-repo");
+    let query = elastic::search_repo_by_name(&owner, &repo_name);
+    let repo = RepoSearchResponse::from_es(
+        elastic::search(&config.es, &config.repo_idx, Some(&query)).await?,
+    )?;
+
+    // the page is still rendered for a missing repo, but it should not be cached for long or indexed
+    let found = !repo.hits.hits.is_empty();
+
+    let html_data = HtmlData {
+        repo: Some(repo),
+        template_name: "repo.html".to_owned(),
+        ttl: if found { 3600 } else { 600 },
+        http_resp_code: if found { 200 } else { 404 },
+        meta_robots: if found {
+            None
+        } else {
+            Some("noindex".to_owned())
+        },
+        login_str: Some(owner),
+        repo_str: Some(repo_name),
+        ..html_data
+    };
+
+    Ok(html_data)
+}
@@ -0,0 +1,606 @@
+//! A parser for the search string entered by the user, e.g. `rust (tokio OR async-std) -actix`.
+//!
+//! Supported syntax:
+//! * terms separated by spaces, commas or a single `+` are all required, e.g. `rust tokio` or `rust+tokio`
+//! * `OR` or `|` between terms makes them alternatives, e.g. `tokio OR async-std`
+//! * terms in brackets are alternatives, e.g. `(tokio async-std)` is the same as `tokio OR async-std`,
+//!   and so are terms in nested brackets, e.g. `((tokio async-std) smol)`
+//! * `-` in front of a term or brackets excludes it, e.g. `-actix` or `-(actix rocket)`, but it cannot be used
+//!   inside brackets, e.g. `(tokio -actix)`, because an exclusion cannot be one of the alternatives
+//! * `"` quotes a phrase that may contain spaces, e.g. `"visual basic"`, and is never auto-corrected
+//! * `lang:` and `pkg:` prefixes restrict a term to languages or keywords/packages, e.g. `lang:go pkg:"go"`
+
+use crate::error::StmError;
+
+/// What the user wants the term to be matched against.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum TermPrefix {
+    /// No prefix - it can be anything
+    Any,
+    /// `lang:` - a language only
+    Lang,
+    /// `pkg:` - a keyword or a package only
+    Pkg,
+}
+
+/// A single search term as entered by the user.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct SearchTerm {
+    /// Normalized value, lower case with no prefix or quotes
+    pub value: String,
+    pub prefix: TermPrefix,
+    /// True if the term was in quotes, which means it should not be auto-corrected
+    pub exact: bool,
+}
+
+impl std::fmt::Display for SearchTerm {
+    /// Outputs the term in the same format it can be parsed from, e.g. `lang:"visual basic"`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.prefix {
+            TermPrefix::Any => {}
+            TermPrefix::Lang => f.write_str(LANG_PREFIX)?,
+            TermPrefix::Pkg => f.write_str(PKG_PREFIX)?,
+        }
+        if self.exact || self.value.contains(' ') {
+            write!(f, "\"{}\"", self.value)
+        } else {
+            f.write_str(&self.value)
+        }
+    }
+}
+
+/// A group of terms where at least one of them must match, or, if `negated`, none of them can match.
+/// A single required term is a group of one.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct SearchClause {
+    pub terms: Vec<SearchTerm>,
+    pub negated: bool,
+}
+
+#[derive(PartialEq, Debug)]
+enum Token {
+    LParen,
+    RParen,
+    Or,
+    Not,
+    Term(SearchTerm),
+}
+
+const LANG_PREFIX: &str = "lang:";
+const PKG_PREFIX: &str = "pkg:";
+const NOT_IN_BRACKETS_MSG: &str =
+    "Exclusions cannot be inside brackets. Search for tokio -actix instead of (tokio -actix).";
+
+/// Parses the search string into a list of clauses that must all match.
+/// Anything that cannot be understood is ignored, same as unsupported characters.
+/// Duplicate terms are removed. Returns an error with a message for the user if there is `-` inside brackets.
+pub(crate) fn parse(raw_search: &str) -> Result<Vec<SearchClause>, StmError> {
+    let tokens = tokenize(raw_search);
+    let mut clauses: Vec<SearchClause> = Vec::new();
+    let mut seen: Vec<(SearchTerm, bool)> = Vec::new();
+
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        // a leading - applies to the term or the group that follows it
+        let (negated, token) = if token == Token::Not {
+            match tokens.next() {
+                Some(v) => (true, v),
+                None => break,
+            }
+        } else {
+            (false, token)
+        };
+
+        let mut terms: Vec<SearchTerm> = Vec::new();
+        match token {
+            Token::LParen => {
+                // everything inside the brackets is an alternative, including nested brackets,
+                // e.g. `((a b) c)` is `a OR b OR c`, and the group ends at the matching bracket
+                let mut depth: usize = 1;
+                for token in tokens.by_ref() {
+                    match token {
+                        Token::LParen => depth += 1,
+                        Token::RParen => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        Token::Term(term) => terms.push(term),
+                        // `(tokio -actix)` means `tokio OR NOT actix`, which is not what the user wants
+                        Token::Not => return Err(StmError::InvalidSearch(NOT_IN_BRACKETS_MSG)),
+                        Token::Or => {}
+                    }
+                }
+            }
+            Token::Term(term) => {
+                terms.push(term);
+                // collect `a OR b OR c` into the same group
+                while tokens.peek() == Some(&Token::Or) {
+                    tokens.next();
+                    match tokens.peek() {
+                        Some(Token::Term(_)) => {
+                            if let Some(Token::Term(term)) = tokens.next() {
+                                terms.push(term);
+                            }
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            // stray operators and brackets are ignored
+            _ => continue,
+        }
+
+        // remove duplicates across all clauses
+        // quoted and unquoted versions of the same term are duplicates
+        let same = |a: &SearchTerm, b: &SearchTerm| a.value == b.value && a.prefix == b.prefix;
+        let mut unique: Vec<SearchTerm> = Vec::new();
+        for term in terms {
+            if seen.iter().any(|(t, n)| same(t, &term) && *n == negated)
+                || unique.iter().any(|t| same(t, &term))
+            {
+                continue;
+            }
+            seen.push((term.clone(), negated));
+            unique.push(term);
+        }
+
+        if !unique.is_empty() {
+            clauses.push(SearchClause {
+                terms: unique,
+                negated,
+            });
+        }
+    }
+
+    Ok(clauses)
+}
+
+/// Converts the clauses back into a search string, e.g. `rust (tokio OR async-std) -actix`.
+pub(crate) fn to_search_string(clauses: &[SearchClause]) -> String {
+    clauses
+        .iter()
+        .map(|clause| {
+            let terms = clause
+                .terms
+                .iter()
+                .map(|term| term.to_string())
+                .collect::<Vec<String>>();
+            let terms = if terms.len() > 1 {
+                ["(", &terms.join(" OR "), ")"].concat()
+            } else {
+                terms.concat()
+            };
+            if clause.negated {
+                ["-", &terms].concat()
+            } else {
+                terms
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Returns the search string for the same clauses with one of the terms replaced,
+/// e.g. `rust tokio` for `rust tokoi`.
+pub(crate) fn replace_term(
+    clauses: &[SearchClause],
+    clause_idx: usize,
+    term_idx: usize,
+    term: SearchTerm,
+) -> String {
+    let mut clauses = clauses.to_vec();
+    clauses[clause_idx].terms[term_idx] = term;
+    to_search_string(&clauses)
+}
+
+/// Characters allowed in unquoted terms. `:` is only meaningful as part of a prefix.
+fn is_term_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "#@/-._:".contains(c)
+}
+
+/// Splits the search string into tokens.
+fn tokenize(raw_search: &str) -> Vec<Token> {
+    let chars = raw_search.chars().collect::<Vec<char>>();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '|' => {
+                tokens.push(Token::Or);
+                i += 1;
+            }
+            '"' => {
+                let (phrase, next) = read_phrase(&chars, i + 1);
+                push_term(&mut tokens, &phrase, TermPrefix::Any, true);
+                i = next;
+            }
+            // - is an operator only in front of something it can apply to, otherwise it's just a separator
+            '-' if i + 1 < chars.len()
+                && (is_term_char(chars[i + 1]) || "(\"".contains(chars[i + 1])) =>
+            {
+                tokens.push(Token::Not);
+                i += 1;
+            }
+            c if is_term_char(c) => {
+                let start = i;
+                while i < chars.len() && is_term_char(chars[i]) {
+                    i += 1;
+                }
+                // a trailing ++ is part of the term, e.g. c++, but a single + is a separator, e.g. rust+tokio
+                if i + 1 < chars.len() && chars[i] == '+' && chars[i + 1] == '+' {
+                    i += 2;
+                }
+                let word = chars[start..i].iter().collect::<String>();
+
+                if word == "OR" {
+                    tokens.push(Token::Or);
+                    continue;
+                }
+
+                // check for lang: and pkg: prefixes, which may be followed by a quoted phrase
+                let lower = word.to_lowercase();
+                let (prefix, value) = if let Some(v) = lower.strip_prefix(LANG_PREFIX) {
+                    (TermPrefix::Lang, v.to_owned())
+                } else if let Some(v) = lower.strip_prefix(PKG_PREFIX) {
+                    (TermPrefix::Pkg, v.to_owned())
+                } else {
+                    (TermPrefix::Any, lower)
+                };
+
+                if prefix != TermPrefix::Any
+                    && value.is_empty()
+                    && i < chars.len()
+                    && chars[i] == '"'
+                {
+                    let (phrase, next) = read_phrase(&chars, i + 1);
+                    push_term(&mut tokens, &phrase, prefix, true);
+                    i = next;
+                } else {
+                    push_term(&mut tokens, &value, prefix, false);
+                }
+            }
+            // spaces, commas, single + and anything else are separators
+            _ => {
+                i += 1;
+            }
+        }
+    }
+
+    tokens
+}
+
+/// Reads a quoted phrase starting after the opening quote up to the closing quote or the end of the string.
+/// Returns the phrase and the index of the char after the closing quote.
+fn read_phrase(chars: &[char], start: usize) -> (String, usize) {
+    let mut end = start;
+    while end < chars.len() && chars[end] != '"' {
+        end += 1;
+    }
+
+    (chars[start..end].iter().collect::<String>(), end + 1)
+}
+
+/// Normalizes the term and adds it to the list of tokens, unless it is empty.
+fn push_term(tokens: &mut Vec<Token>, value: &str, prefix: TermPrefix, phrase: bool) {
+    // searches with a tailing or leading . should be cleaned up
+    // it may be possible to have a lead/trail _, maybe
+    // I havn't seen a lead/trail - anywhere
+    let value = if phrase {
+        value.split_whitespace().collect::<Vec<&str>>().join(" ")
+    } else {
+        value.trim_matches('.').trim_matches('-').to_owned()
+    };
+
+    if value.is_empty() {
+        return;
+    }
+
+    tokens.push(Token::Term(SearchTerm {
+        value: value.to_lowercase(),
+        prefix,
+        exact: phrase,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a search that is expected to be valid.
+    fn parse(raw_search: &str) -> Vec<SearchClause> {
+        super::parse(raw_search).unwrap()
+    }
+
+    fn term(value: &str) -> SearchTerm {
+        SearchTerm {
+            value: value.to_owned(),
+            prefix: TermPrefix::Any,
+            exact: false,
+        }
+    }
+
+    fn clause(terms: Vec<SearchTerm>, negated: bool) -> SearchClause {
+        SearchClause { terms, negated }
+    }
+
+    #[test]
+    fn symbols_in_terms() {
+        assert_eq!(
+            parse("C++ c# .net node.js"),
+            vec![
+                clause(vec![term("c++")], false),
+                clause(vec![term("c#")], false),
+                clause(vec![term("net")], false),
+                clause(vec![term("node.js")], false),
+            ]
+        );
+        // a single + is a separator, a double + is only part of the term at its end
+        assert_eq!(
+            parse("rust+tokio c++17"),
+            vec![
+                clause(vec![term("rust")], false),
+                clause(vec![term("tokio")], false),
+                clause(vec![term("c++")], false),
+                clause(vec![term("17")], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(
+            parse("rust -actix async-std"),
+            vec![
+                clause(vec![term("rust")], false),
+                clause(vec![term("actix")], true),
+                clause(vec![term("async-std")], false),
+            ]
+        );
+        // a - that doesn't touch a term is a separator
+        assert_eq!(
+            parse("rust - tokio-"),
+            vec![
+                clause(vec![term("rust")], false),
+                clause(vec![term("tokio")], false),
+            ]
+        );
+        assert_eq!(
+            parse("rust -(actix rocket)"),
+            vec![
+                clause(vec![term("rust")], false),
+                clause(vec![term("actix"), term("rocket")], true),
+            ]
+        );
+        assert_eq!(
+            parse(r#"-"visual basic""#),
+            vec![clause(
+                vec![SearchTerm {
+                    exact: true,
+                    ..term("visual basic")
+                }],
+                true
+            )]
+        );
+    }
+
+    #[test]
+    fn alternatives() {
+        let expected = vec![clause(
+            vec![term("tokio"), term("async-std"), term("smol")],
+            false,
+        )];
+        assert_eq!(parse("tokio OR async-std | smol"), expected);
+        assert_eq!(parse("(tokio, async-std smol)"), expected);
+        // `or` in lower case is a term
+        assert_eq!(
+            parse("tokio or smol"),
+            vec![
+                clause(vec![term("tokio")], false),
+                clause(vec![term("or")], false),
+                clause(vec![term("smol")], false),
+            ]
+        );
+        // a dangling OR is ignored
+        assert_eq!(parse("OR rust OR"), vec![clause(vec![term("rust")], false)]);
+    }
+
+    #[test]
+    fn nested_brackets() {
+        // everything up to the matching bracket is one group of alternatives
+        assert_eq!(
+            parse("((tokio async-std) smol) rust"),
+            vec![
+                clause(vec![term("tokio"), term("async-std"), term("smol")], false),
+                clause(vec![term("rust")], false),
+            ]
+        );
+        assert_eq!(
+            parse("-(actix (rocket warp)) rust"),
+            vec![
+                clause(vec![term("actix"), term("rocket"), term("warp")], true),
+                clause(vec![term("rust")], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn negation_in_brackets() {
+        for search in &[
+            "(tokio -actix)",
+            "rust (tokio OR -actix)",
+            "-(actix -rocket)",
+            r#"(tokio -"async std")"#,
+            "((tokio) -(actix))",
+        ] {
+            match super::parse(search) {
+                Err(StmError::InvalidSearch(msg)) => assert_eq!(msg, NOT_IN_BRACKETS_MSG),
+                v => panic!("{}: {:?}", search, v),
+            }
+        }
+        // a - inside a term is not an exclusion
+        assert_eq!(
+            parse("(tokio async-std)"),
+            vec![clause(vec![term("tokio"), term("async-std")], false)]
+        );
+    }
+
+    #[test]
+    fn quoted_phrases() {
+        let phrase = SearchTerm {
+            exact: true,
+            ..term("visual basic")
+        };
+        assert_eq!(
+            parse(r#""Visual   Basic" rust"#),
+            vec![
+                clause(vec![phrase.clone()], false),
+                clause(vec![term("rust")], false),
+            ]
+        );
+        // quotes stop auto-correction of single words too
+        assert_eq!(
+            parse(r#""tokoi""#),
+            vec![clause(
+                vec![SearchTerm {
+                    exact: true,
+                    ..term("tokoi")
+                }],
+                false
+            )]
+        );
+        assert_eq!(
+            to_search_string(&parse(r#""visual basic""#)),
+            r#""visual basic""#
+        );
+        assert_eq!(parse(r#""" " ""#), vec![]);
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(
+            parse(r#"lang:Go pkg:tokio LANG:"visual basic" pkg:"#),
+            vec![
+                clause(
+                    vec![SearchTerm {
+                        prefix: TermPrefix::Lang,
+                        ..term("go")
+                    }],
+                    false
+                ),
+                clause(
+                    vec![SearchTerm {
+                        prefix: TermPrefix::Pkg,
+                        ..term("tokio")
+                    }],
+                    false
+                ),
+                clause(
+                    vec![SearchTerm {
+                        prefix: TermPrefix::Lang,
+                        exact: true,
+                        ..term("visual basic")
+                    }],
+                    false
+                ),
+            ]
+        );
+        // the same value with different prefixes is not a duplicate
+        assert_eq!(parse("go lang:go pkg:go").len(), 3);
+        assert_eq!(
+            to_search_string(&parse(r#"lang:go (pkg:"go" rust) -actix"#)),
+            r#"lang:go (pkg:"go" OR rust) -actix"#
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        assert_eq!(
+            parse(r#"rust Rust "rust" (tokio rust tokio) -rust -rust"#),
+            vec![
+                clause(vec![term("rust")], false),
+                clause(vec![term("tokio")], false),
+                clause(vec![term("rust")], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn unbalanced_brackets_and_quotes() {
+        assert_eq!(
+            parse("(tokio async-std"),
+            vec![clause(vec![term("tokio"), term("async-std")], false)]
+        );
+        assert_eq!(
+            parse("rust) ((tokio)"),
+            vec![
+                clause(vec![term("rust")], false),
+                clause(vec![term("tokio")], false),
+            ]
+        );
+        assert_eq!(
+            parse(r#"rust "visual basic"#),
+            vec![
+                clause(vec![term("rust")], false),
+                clause(
+                    vec![SearchTerm {
+                        exact: true,
+                        ..term("visual basic")
+                    }],
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn term_cap() {
+        // the parser keeps every term, the router checks only the first few
+        let clauses = parse("lang:a lang:b lang:c pkg:d pkg:e pkg:f");
+        assert_eq!(clauses.len(), 6);
+
+        let terms = crate::html::predict_dev_search_terms(&clauses).unwrap();
+        assert_eq!(
+            terms.must.len(),
+            crate::html::MAX_NUMBER_OF_VALID_SEARCH_TERMS
+        );
+        assert!(terms.must_not.is_empty());
+    }
+
+    #[test]
+    fn negations_or_punctuation_only() {
+        let clauses = parse("-actix -(rocket warp)");
+        assert_eq!(
+            clauses,
+            vec![
+                clause(vec![term("actix")], true),
+                clause(vec![term("rocket"), term("warp")], true),
+            ]
+        );
+        // there is nothing to search for
+        assert!(crate::html::predict_dev_search_terms(&parse("-lang:go")).is_none());
+
+        for search in &[
+            "",
+            "   ",
+            "!!! ,,, ()",
+            "- -- ---",
+            "| OR |",
+            r#"""#,
+            "+ ++ ...",
+        ] {
+            assert_eq!(parse(search), vec![], "{}", search);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Sitemaps and robots.txt for search engines. Only pages that are indexed as per `meta_robots` are listed:
+//! * `/sitemap.xml` - an index of all other sitemaps
+//! * `/sitemap-langs.xml` - a search page per language, e.g. `/?rust`
+//! * `/sitemap-devs.xml?after={login}` - dev profiles sorted by login, up to 10,000 per sitemap
+//! * `/robots.txt` - points at the sitemap index and disallows pages that are never indexed
+
+use super::dev_doc::DevSearchResponse;
+use super::html_data::{HtmlData, SitemapUrl};
+use super::w3c_datetime;
+use crate::config::Config;
+use crate::elastic;
+use crate::error::StmError;
+use chrono::{DateTime, Utc};
+use tracing::{info, warn};
+use urlencoding::encode;
+
+pub(crate) const SITEMAP_INDEX_PATH: &str = "/sitemap.xml";
+pub(crate) const SITEMAP_LANGS_PATH: &str = "/sitemap-langs.xml";
+pub(crate) const SITEMAP_DEVS_PATH: &str = "/sitemap-devs.xml";
+pub(crate) const ROBOTS_TXT_PATH: &str = "/robots.txt";
+/// The query string param with the login of the last dev in the previous devs sitemap, e.g. `?after=rimutaka`
+pub(crate) const AFTER_PARAM: &str = "after";
+/// Sitemaps are regenerated once a day, which is about as often as crawlers check them
+const SITEMAP_TTL: u32 = 86400;
+/// The sitemap index stops walking the dev index after this many sitemaps, which is 1M devs
+const MAX_DEV_SITEMAPS: usize = 100;
+
+/// Returns the sitemap index with the languages sitemap and as many devs sitemaps as needed to list all devs.
+/// The dev index is walked with `search_after` to find where every devs sitemap starts.
+pub(crate) async fn index_xml(config: &Config, html_data: HtmlData) -> Result<HtmlData, StmError> {
+    let mut sitemaps = vec![SitemapUrl {
+        loc: [config.site_url.as_str(), SITEMAP_LANGS_PATH].concat(),
+        lastmod: None,
+    }];
+    let mut last_modified: Option<DateTime<Utc>> = None;
+
+    let mut after: Option<String> = None;
+    for _ in 0..MAX_DEV_SITEMAPS {
+        let devs = DevSearchResponse::dev_logins_page(config, after.as_deref()).await?;
+        if devs.hits.hits.is_empty() {
+            break;
+        }
+
+        // the sitemap is as fresh as the latest report in it
+        let sitemap_modified = devs.devs().filter_map(|dev| dev.report_timestamp()).max();
+        sitemaps.push(SitemapUrl {
+            loc: devs_sitemap_url(&config.site_url, after.as_deref()),
+            lastmod: sitemap_modified.map(w3c_datetime),
+        });
+        last_modified = last_modified.max(sitemap_modified);
+
+        after = match devs.next_page_after() {
+            Some(v) => Some(v.to_owned()),
+            None => break,
+        };
+    }
+    info!("Sitemaps: {}", sitemaps.len());
+
+    Ok(HtmlData {
+        sitemap: Some(sitemaps),
+        template_name: "sitemap_index.xml".to_owned(),
+        ttl: SITEMAP_TTL,
+        http_resp_code: 200,
+        last_modified,
+        ..html_data
+    })
+}
+
+/// Returns a sitemap with dev profiles that come after the login, if any, with the date of their latest report.
+/// A sitemap past the last dev is a 404.
+pub(crate) async fn devs_xml(
+    config: &Config,
+    after: Option<String>,
+    html_data: HtmlData,
+) -> Result<HtmlData, StmError> {
+    let devs = DevSearchResponse::dev_logins_page(config, after.as_deref()).await?;
+    if devs.hits.hits.is_empty() && after.is_some() {
+        warn!("Empty devs sitemap after {:?}", after);
+        return Ok(html_data);
+    }
+
+    let urls = devs
+        .devs()
+        .map(|dev| SitemapUrl {
+            loc: [config.site_url.as_str(), "/", &encode(&dev.login)].concat(),
+            lastmod: dev.report_timestamp().map(w3c_datetime),
+        })
+        .collect::<Vec<SitemapUrl>>();
+
+    Ok(HtmlData {
+        sitemap: Some(urls),
+        template_name: "sitemap.xml".to_owned(),
+        ttl: SITEMAP_TTL,
+        http_resp_code: 200,
+        last_modified: devs.devs().filter_map(|dev| dev.report_timestamp()).max(),
+        ..html_data
+    })
+}
+
+/// Returns a sitemap with a search page for every language found in dev reports, e.g. `/?rust`.
+/// Single-language searches are the only searches that are indexed.
+pub(crate) async fn langs_xml(config: &Config, html_data: HtmlData) -> Result<HtmlData, StmError> {
+    let urls = elastic::dev_langs(&config.es, &config.dev_idx)
+        .await?
+        .iter()
+        .map(|lang| SitemapUrl {
+            loc: [config.site_url.as_str(), "/?", &encode(lang)].concat(),
+            lastmod: None,
+        })
+        .collect::<Vec<SitemapUrl>>();
+
+    Ok(HtmlData {
+        sitemap: Some(urls),
+        template_name: "sitemap.xml".to_owned(),
+        ttl: SITEMAP_TTL,
+        http_resp_code: 200,
+        ..html_data
+    })
+}
+
+/// Returns robots.txt with the URL of the sitemap index. The disallowed paths are in the template.
+pub(crate) fn robots_txt(config: &Config, html_data: HtmlData) -> HtmlData {
+    HtmlData {
+        sitemap: Some(vec![SitemapUrl {
+            loc: [config.site_url.as_str(), SITEMAP_INDEX_PATH].concat(),
+            lastmod: None,
+        }]),
+        template_name: "robots.txt".to_owned(),
+        ttl: SITEMAP_TTL,
+        http_resp_code: 200,
+        ..html_data
+    }
+}
+
+/// Returns the absolute URL of the devs sitemap that starts after the login, if any.
+fn devs_sitemap_url(site_url: &str, after: Option<&str>) -> String {
+    match after {
+        Some(login) => [
+            site_url,
+            SITEMAP_DEVS_PATH,
+            "?",
+            AFTER_PARAM,
+            "=",
+            &encode(login),
+        ]
+        .concat(),
+        None => [site_url, SITEMAP_DEVS_PATH].concat(),
+    }
+}
@@ -1,6 +1,9 @@
-use super::html_data::HtmlData;
+use super::chart::{self, ChartType, Series};
+use super::html_data::{HtmlData, SvgChart};
 use crate::config::Config;
 use crate::elastic;
+use crate::error::StmError;
+use chrono::{DateTime, Utc};
 use futures::future::join_all;
 use serde::Serialize;
 use serde_json::Value;
@@ -14,18 +17,18 @@ pub(crate) struct Stats {
     stm_stats_report_fail_counts: Value,
 }
 
-pub(crate) async fn html(config: &Config, html_data: HtmlData) -> Result<HtmlData, ()> {
+pub(crate) async fn html(config: &Config, html_data: HtmlData) -> Result<HtmlData, StmError> {
     // get the data from ES
     let stm_stats_dev_job_counts =
-        elastic::get_stm_stats(&config.es_url, "stm_stats_dev_job_counts", 60);
+        elastic::get_stm_stats(&config.es, "stm_stats_dev_job_counts", 60);
     let stm_stats_repo_job_counts =
-        elastic::get_stm_stats(&config.es_url, "stm_stats_repo_job_counts", 60);
+        elastic::get_stm_stats(&config.es, "stm_stats_repo_job_counts", 60);
     let stm_stats_report_success_counts =
-        elastic::get_stm_stats(&config.es_url, "stm_stats_report_success_counts", 12);
+        elastic::get_stm_stats(&config.es, "stm_stats_report_success_counts", 12);
     let stm_stats_report_generation_time_avg =
-        elastic::get_stm_stats(&config.es_url, "stm_stats_report_generation_time_avg", 12);
+        elastic::get_stm_stats(&config.es, "stm_stats_report_generation_time_avg", 12);
     let stm_stats_report_fail_counts =
-        elastic::get_stm_stats(&config.es_url, "stm_stats_report_fail_counts", 12);
+        elastic::get_stm_stats(&config.es, "stm_stats_report_fail_counts", 12);
 
     // run the queries concurrently
     let jobs = vec![
@@ -39,33 +42,109 @@ pub(crate) async fn html(config: &Config, html_data: HtmlData) -> Result<HtmlDat
     response.reverse();
 
     // put everything together into a structure
-    // expect has to be repeated twice because it is Option<Result<Value>> after pop()
-    let stats_jobs = Stats {
-        stm_stats_dev_job_counts: response
-            .pop()
-            .expect("stm_stats_dev_job_counts ES query failed")
-            .expect("stm_stats_dev_job_counts ES query failed"),
-        stm_stats_repo_job_counts: response
-            .pop()
-            .expect("stm_stats_repo_job_counts ES query failed")
-            .expect("stm_stats_repo_job_counts ES query failed"),
-        stm_stats_report_success_counts: response
-            .pop()
-            .expect("stm_stats_report_success_counts ES query failed")
-            .expect("stm_stats_report_success_counts ES query failed"),
-        stm_stats_report_generation_time_avg: response
+    // pop() returns Option<Result<Value>>, which is unwrapped here because the number of jobs is fixed
+    let mut next_response = || {
+        response
             .pop()
-            .expect("stm_stats_report_generation_time_avg ES query failed")
-            .expect("stm_stats_report_generation_time_avg ES query failed"),
-        stm_stats_report_fail_counts: response
-            .pop()
-            .expect("stm_stats_report_fail_counts ES query failed")
-            .expect("stm_stats_report_fail_counts ES query failed"),
+            .expect("Mismatched number of stats queries and responses")
+    };
+    let stats_jobs = Stats {
+        stm_stats_dev_job_counts: next_response()?,
+        stm_stats_repo_job_counts: next_response()?,
+        stm_stats_report_success_counts: next_response()?,
+        stm_stats_report_generation_time_avg: next_response()?,
+        stm_stats_report_fail_counts: next_response()?,
     };
 
+    // the page changes when a new entry is added to any of the stats indices
+    let last_modified = [
+        latest_entry_date(
+            "stm_stats_dev_job_counts",
+            &stats_jobs.stm_stats_dev_job_counts,
+        ),
+        latest_entry_date(
+            "stm_stats_repo_job_counts",
+            &stats_jobs.stm_stats_repo_job_counts,
+        ),
+        latest_entry_date(
+            "stm_stats_report_success_counts",
+            &stats_jobs.stm_stats_report_success_counts,
+        ),
+        latest_entry_date(
+            "stm_stats_report_generation_time_avg",
+            &stats_jobs.stm_stats_report_generation_time_avg,
+        ),
+        latest_entry_date(
+            "stm_stats_report_fail_counts",
+            &stats_jobs.stm_stats_report_fail_counts,
+        ),
+    ]
+    .iter()
+    .flatten()
+    .max()
+    .cloned();
+
+    // trends at a glance, the tables have the details
+    let charts = vec![
+        stats_chart(
+            "DEV queue",
+            ChartType::Line,
+            "",
+            "stm_stats_dev_job_counts",
+            &stats_jobs.stm_stats_dev_job_counts,
+            &[
+                ("devs_total", "Total"),
+                ("w_report", "With report"),
+                ("no_report", "No report"),
+                ("in_fl", "In flight"),
+            ],
+        ),
+        stats_chart(
+            "REPO queue",
+            ChartType::Line,
+            "",
+            "stm_stats_repo_job_counts",
+            &stats_jobs.stm_stats_repo_job_counts,
+            &[
+                ("repos_total", "Total"),
+                ("w_report", "With report"),
+                ("no_report", "No report"),
+                ("in_fl", "In flight"),
+            ],
+        ),
+        stats_chart(
+            "Report success vs failure, last 1hr",
+            ChartType::Bar,
+            "",
+            "stm_stats_report_success_counts",
+            &stats_jobs.stm_stats_report_success_counts,
+            &[
+                ("r1hr", "OK repo"),
+                ("rf1hr", "Failed repo"),
+                ("d1hr", "OK dev"),
+                ("df1hr", "Failed dev"),
+            ],
+        ),
+        stats_chart(
+            "Report generation time, last 1hr",
+            ChartType::Line,
+            " sec",
+            "stm_stats_report_generation_time_avg",
+            &stats_jobs.stm_stats_report_generation_time_avg,
+            &[
+                ("r_r1hr", "Repo report"),
+                ("r_s2r1hr", "Repo sync to report"),
+                ("d_r1hr", "Dev report"),
+                ("d_s2r1hr", "Dev sync to report"),
+            ],
+        ),
+    ];
+
     // put everything together for Tera
     let html_data = HtmlData {
         stats_jobs: Some(stats_jobs),
+        charts,
+        last_modified,
         template_name: "stats.html".to_owned(),
         ttl: 600,
         http_resp_code: 200,
@@ -74,3 +153,51 @@ pub(crate) async fn html(config: &Config, html_data: HtmlData) -> Result<HtmlDat
 
     Ok(html_data)
 }
+
+/// Returns the date of the latest entry in the response from `get_stm_stats`, if any.
+/// The date is in the `iso` field under the index name, e.g. `stm_stats_dev_job_counts.iso`.
+fn latest_entry_date(idx: &str, es_response: &Value) -> Option<DateTime<Utc>> {
+    es_response["hits"]["hits"]
+        .as_array()?
+        .iter()
+        .filter_map(|hit| hit["_source"][idx]["iso"].as_str())
+        .filter_map(super::parse_timestamp)
+        .max()
+}
+
+/// Returns a chart with a series per field of the stats entries in the response from `get_stm_stats`.
+/// The fields come with their names for the legend, e.g. `("in_fl", "In flight")`. Entries without
+/// a numeric value are skipped, e.g. there is no average time if no reports were generated.
+fn stats_chart(
+    title: &str,
+    chart_type: ChartType,
+    unit: &str,
+    idx: &str,
+    es_response: &Value,
+    fields: &[(&str, &str)],
+) -> SvgChart {
+    let entries = es_response["hits"]["hits"]
+        .as_array()
+        .map(|hits| {
+            hits.iter()
+                .map(|hit| &hit["_source"][idx])
+                .collect::<Vec<&Value>>()
+        })
+        .unwrap_or_default();
+
+    let series = fields
+        .iter()
+        .map(|(field, name)| Series {
+            name: name.to_string(),
+            points: entries
+                .iter()
+                .filter_map(|entry| Some((entry["ts"].as_i64()?, entry[*field].as_f64()?)))
+                .collect(),
+        })
+        .collect::<Vec<Series>>();
+
+    SvgChart {
+        title: title.to_owned(),
+        svg: chart::svg(chart_type, title, unit, &series),
+    }
+}
@@ -1,10 +1,18 @@
+use handler::SharedState;
 #[cfg(not(debug_assertions))]
 use lambda_runtime::handler_fn;
+use std::sync::Arc;
 
+mod cache;
 mod config;
 mod elastic;
+mod error;
 mod handler;
 mod html;
+mod prerender;
+mod rate_limit;
+mod security;
+mod server;
 
 pub(crate) type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
@@ -19,18 +27,36 @@ async fn main() -> Result<(), Error> {
     let tsub = tsub.without_time();
     tsub.init();
 
+    // the config, templates and ES connections are shared by all requests for the lifetime of the process
+    let state = Arc::new(SharedState::new()?);
+
+    // run as a standalone HTTP server if launched with `serve` command, e.g. `stm-html serve --listen 0.0.0.0:8080`
+    if let Some(addr) = server::listen_addr_from_args() {
+        return server::run(addr, state).await;
+    }
+
+    // write pages to a folder and exit if launched with `prerender` command, e.g. `stm-html prerender --out ./site`
+    if let Some(args) = prerender::args_from_cli() {
+        return prerender::run(args, state).await;
+    }
+
     #[cfg(debug_assertions)]
-    return proxy::run().await;
+    return proxy::run(state).await;
 
     // call the actual handler of the request
     #[cfg(not(debug_assertions))]
-    return lambda_runtime::run(handler_fn(handler::my_handler)).await;
+    return lambda_runtime::run(handler_fn(move |event, ctx| {
+        let state = state.clone();
+        async move { handler::my_handler(event, ctx, &state).await }
+    }))
+    .await;
 }
 
 /// This module is only used for local debugging via SQS and should
 /// not be deployed to Lambda.
 #[cfg(debug_assertions)]
 mod proxy {
+    use crate::handler::SharedState;
     use lambda_runtime::Context;
     use rusoto_core::region::Region;
     use rusoto_sqs::{
@@ -38,6 +64,7 @@ mod proxy {
     };
     use serde::Deserialize;
     use serde_json::Value;
+    use std::sync::Arc;
     use tracing::info;
 
     pub(crate) type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
@@ -52,14 +79,14 @@ mod proxy {
         pub ctx: Context,
     }
 
-    pub(crate) async fn run() -> Result<(), Error> {
+    pub(crate) async fn run(state: Arc<SharedState>) -> Result<(), Error> {
         #[cfg(debug_assertions)]
         loop {
             // get event and context details from the queue
             let (payload, receipt_handle) = get_input().await?;
             info!("New msg");
             // invoke the handler
-            let response = crate::handler::my_handler(payload.event, payload.ctx).await?;
+            let response = crate::handler::my_handler(payload.event, payload.ctx, &state).await?;
 
             // send back the response and delete the message from the queue
             send_output(response, receipt_handle).await?;
@@ -77,10 +104,12 @@ mod proxy {
                 .receive_message(ReceiveMessageRequest {
                     max_number_of_messages: Some(1),
                     queue_url: std::env::var(REQUEST_QUEUE_URL_ENV)
-                        .expect(&format!(
-                            "Missing {} env var with the SQS request queue URL",
-                            REQUEST_QUEUE_URL_ENV
-                        ))
+                        .unwrap_or_else(|_| {
+                            panic!(
+                                "Missing {} env var with the SQS request queue URL",
+                                REQUEST_QUEUE_URL_ENV
+                            )
+                        })
                         .trim()
                         .to_string(),
                     wait_time_seconds: Some(20),
@@ -95,7 +124,7 @@ mod proxy {
 
             // an empty list returns when the queue wait time expires
             let msgs = resp.messages.expect("Failed to get list of messages");
-            if msgs.len() == 0 {
+            if msgs.is_empty() {
                 continue;
             }
 
@@ -123,10 +152,12 @@ mod proxy {
             .send_message(SendMessageRequest {
                 message_body: response.to_string(),
                 queue_url: std::env::var(RESPONSE_QUEUE_URL_ENV)
-                    .expect(&format!(
-                        "Missing {} env var with the SQS response queue URL",
-                        RESPONSE_QUEUE_URL_ENV
-                    ))
+                    .unwrap_or_else(|_| {
+                        panic!(
+                            "Missing {} env var with the SQS response queue URL",
+                            RESPONSE_QUEUE_URL_ENV
+                        )
+                    })
                     .trim()
                     .to_string(),
                 ..Default::default()
@@ -137,10 +168,12 @@ mod proxy {
         client
             .delete_message(DeleteMessageRequest {
                 queue_url: std::env::var(REQUEST_QUEUE_URL_ENV)
-                    .expect(&format!(
-                        "Missing {} env var with the SQS request queue URL",
-                        REQUEST_QUEUE_URL_ENV
-                    ))
+                    .unwrap_or_else(|_| {
+                        panic!(
+                            "Missing {} env var with the SQS request queue URL",
+                            REQUEST_QUEUE_URL_ENV
+                        )
+                    })
                     .trim()
                     .to_string(),
                 receipt_handle,
@@ -0,0 +1,332 @@
+//! Renders pages into a folder for static hosting, e.g. `stm-html prerender --out ./site`, so that the most visited
+//! pages don't have to be rendered on demand. Every page goes through the same `html::html` and templates as in Lambda:
+//! * `/` -> `index.html`
+//! * `/_stats` -> `_stats/index.html`
+//! * `/{login}` -> `{login}/index.html` for every dev in the dev index
+//! * `/?{lang}` -> `_lang/{lang}/index.html` for every language, URL-encoded, e.g. `_lang/C%23/index.html`.
+//!   Static hosting ignores query strings, so the CDN has to rewrite `/?C%23` into `/_lang/C%23/`.
+//!
+//! `manifest.json` in the same folder lists every page with its file, the SHA-256 of the file and the timestamp of
+//! the dev report. `--incremental` re-renders only devs with a different timestamp than in the previous manifest.
+//! The home, stats and language pages change with every new report and are always re-rendered. Pages of devs that are
+//! no longer in the index are deleted in both modes.
+//!
+//! Static pages cannot have a CSP nonce per response and a nonce shared by all pages would be public, so the pages are
+//! rendered without a nonce. The manifest has the SHA-256 of the inline scripts of every page and the security headers
+//! with a CSP that allows the scripts of all pages by their hashes for the CDN to send with every page.
+
+use crate::config::Config;
+use crate::handler::{self, SharedState};
+use crate::html::dev_doc::DevSearchResponse;
+use crate::{elastic, html, security, Error};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, warn};
+use urlencoding::encode;
+
+/// The CLI command to pre-render pages, e.g. `stm-html prerender --out ./site --incremental`
+const PRERENDER_CMD: &str = "prerender";
+/// The CLI param with the output folder
+const OUT_PARAM: &str = "--out";
+/// The CLI flag to re-render only the devs that changed since the last run
+const INCREMENTAL_PARAM: &str = "--incremental";
+/// The name of the manifest file in the output folder
+const MANIFEST_FILE: &str = "manifest.json";
+/// Incremented on breaking changes to the manifest structure. A manifest with a different version is ignored.
+const MANIFEST_VERSION: u32 = 2;
+/// The folder with language pages inside the output folder
+const LANG_DIR: &str = "_lang";
+/// Max number of pages rendered at the same time, which is also the max number of concurrent ES requests
+const MAX_CONCURRENT_PAGES: usize = 8;
+
+/// Parsed `prerender` command params
+pub(crate) struct PrerenderArgs {
+    /// The output folder, e.g. `./site`
+    pub out: PathBuf,
+    /// Re-render only the devs that changed since the last run
+    pub incremental: bool,
+}
+
+/// Returns the params if the app was launched with `prerender` command or `None` otherwise.
+/// Panics if `--out` is missing or there are unknown params.
+pub(crate) fn args_from_cli() -> Option<PrerenderArgs> {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+
+    // the command must come first, e.g. `stm-html prerender --out ./site`
+    if args.first().map(|v| v.as_str()) != Some(PRERENDER_CMD) {
+        return None;
+    }
+
+    // the folder may be given as `--out dir` or `--out=dir`
+    let mut out: Option<String> = None;
+    let mut incremental = false;
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == OUT_PARAM {
+            out = Some(
+                args.next()
+                    .unwrap_or_else(|| panic!("Missing value for {}", OUT_PARAM)),
+            );
+        } else if let Some(v) = arg.strip_prefix(&[OUT_PARAM, "="].concat()) {
+            out = Some(v.to_owned());
+        } else if arg == INCREMENTAL_PARAM {
+            incremental = true;
+        } else {
+            panic!("Unknown param: {}", arg);
+        }
+    }
+
+    Some(PrerenderArgs {
+        out: PathBuf::from(out.unwrap_or_else(|| panic!("Missing {} param", OUT_PARAM))),
+        incremental,
+    })
+}
+
+/// The list of pre-rendered pages from the last run
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// The headers the CDN should send with every page, including CSP with the hashes of inline scripts of all pages
+    headers: BTreeMap<String, String>,
+    /// Pages by URL path, e.g. `/rimutaka` or `/?rust`
+    pages: BTreeMap<String, ManifestPage>,
+}
+
+/// A single page in the manifest
+#[derive(Serialize, Deserialize, Clone)]
+struct ManifestPage {
+    /// The file relative to the output folder, e.g. `rimutaka/index.html`
+    file: String,
+    /// SHA-256 of the file contents
+    hash: String,
+    /// `report.timestamp` of the dev for dev pages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<String>,
+    /// CSP sources of the inline scripts in the page, e.g. `'sha256-...'`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    script_hashes: Vec<String>,
+}
+
+/// A page to be rendered
+struct Page {
+    /// The key in the manifest
+    manifest_key: String,
+    /// URL path, decoded, and query string, encoded, as they would come in a request
+    url_path: String,
+    url_query: String,
+    /// The file relative to the output folder
+    file: String,
+    /// `report.timestamp` of the dev for dev pages
+    timestamp: Option<String>,
+}
+
+/// Renders all pages into the output folder and writes the manifest. Stops on the first ES or template error
+/// without updating the manifest, so that the next run starts from the same point.
+pub(crate) async fn run(args: PrerenderArgs, state: Arc<SharedState>) -> Result<(), Error> {
+    let started = Instant::now();
+    let config = &state.config;
+    std::fs::create_dir_all(&args.out)?;
+
+    // the previous manifest is needed for deleting the pages of removed devs even if all pages are re-rendered
+    let previous = load_manifest(&args.out);
+
+    let mut pages = vec![
+        Page::new("/", "", "index.html".to_owned(), None),
+        Page::new("/_stats", "", "_stats/index.html".to_owned(), None),
+    ];
+    for lang in elastic::dev_langs(&config.es, &config.dev_idx).await? {
+        // languages become folder names, same as logins
+        if !is_safe_dir_name(&lang) {
+            warn!("Invalid language: {}", lang);
+            continue;
+        }
+        let file = [LANG_DIR, "/", &encode(&lang), "/index.html"].concat();
+        pages.push(Page {
+            manifest_key: ["/?", &lang].concat(),
+            ..Page::new("/", &encode(&lang), file, None)
+        });
+    }
+
+    // unchanged devs keep their pages from the previous run in incremental mode
+    let mut manifest_pages: BTreeMap<String, ManifestPage> = BTreeMap::new();
+    for (login, timestamp) in dev_logins(config).await? {
+        // logins become folder names, so they must be safe, e.g. no `..` or `/`
+        if !is_safe_dir_name(&login) || config.no_sql_string_invalidation_regex.is_match(&login) {
+            warn!("Invalid dev login: {}", login);
+            continue;
+        }
+        let url_path = ["/", &login].concat();
+
+        if args.incremental {
+            let unchanged = previous
+                .as_ref()
+                .and_then(|v| v.pages.get(&url_path))
+                .filter(|v| v.timestamp.is_some() && v.timestamp == timestamp)
+                .filter(|v| args.out.join(&v.file).exists());
+            if let Some(unchanged) = unchanged {
+                manifest_pages.insert(url_path, unchanged.clone());
+                continue;
+            }
+        }
+
+        let file = [login.as_str(), "/index.html"].concat();
+        pages.push(Page::new(&url_path, "", file, timestamp));
+    }
+    let unchanged = manifest_pages.len();
+    info!("Pages to render: {}, unchanged: {}", pages.len(), unchanged);
+
+    // render several pages at a time because most of the time is spent waiting for ES
+    let mut rendered = stream::iter(
+        pages
+            .into_iter()
+            .map(|page| render_page(&state, &args.out, page)),
+    )
+    .buffer_unordered(MAX_CONCURRENT_PAGES);
+    while let Some(page) = rendered.next().await {
+        if let Some((manifest_key, manifest_page)) = page? {
+            manifest_pages.insert(manifest_key, manifest_page);
+        }
+    }
+
+    // pages from the last run that are gone now, e.g. deleted devs or 404s
+    if let Some(previous) = &previous {
+        for (manifest_key, page) in &previous.pages {
+            if !manifest_pages.contains_key(manifest_key) {
+                info!("Deleting {}", page.file);
+                let path = args.out.join(&page.file);
+                if let Err(e) = std::fs::remove_file(&path) {
+                    warn!("Cannot delete {}: {}", page.file, e);
+                }
+                // the folder of the page is removed if it's empty, e.g. `rimutaka/`
+                if let Some(dir) = path.parent().filter(|v| *v != args.out) {
+                    let _ = std::fs::remove_dir(dir);
+                }
+            }
+        }
+    }
+
+    // the scripts may differ between pages rendered by different versions of the templates in incremental mode
+    let script_hashes = manifest_pages
+        .values()
+        .flat_map(|page| page.script_hashes.iter().cloned())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect::<Vec<String>>();
+    let mut headers: HashMap<String, String> = HashMap::new();
+    security::add_static_headers(&mut headers, &config.security_headers, &script_hashes);
+    let manifest = Manifest {
+        version: MANIFEST_VERSION,
+        headers: headers.into_iter().collect(),
+        pages: manifest_pages,
+    };
+    std::fs::write(
+        args.out.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+
+    info!(
+        "Pre-rendered {} pages, {} unchanged, in {}s",
+        manifest.pages.len() - unchanged,
+        unchanged,
+        started.elapsed().as_secs()
+    );
+
+    Ok(())
+}
+
+impl Page {
+    /// A page with the URL path as the manifest key.
+    fn new(url_path: &str, url_query: &str, file: String, timestamp: Option<String>) -> Self {
+        Self {
+            manifest_key: url_path.to_owned(),
+            url_path: url_path.to_owned(),
+            url_query: url_query.to_owned(),
+            file,
+            timestamp,
+        }
+    }
+}
+
+/// Renders the page and writes it into the output folder. Returns the manifest entry or `None` if the page is not a 200,
+/// e.g. a language with no devs. ES and template errors are returned as errors.
+async fn render_page(
+    state: &SharedState,
+    out: &Path,
+    page: Page,
+) -> Result<Option<(String, ManifestPage)>, Error> {
+    let html_data = html::html(&state.config, page.url_path, page.url_query).await?;
+    if html_data.http_resp_code != 200 {
+        warn!(
+            "Skipping {} with status {}",
+            page.manifest_key, html_data.http_resp_code
+        );
+        return Ok(None);
+    }
+
+    // the inline scripts are allowed by their hashes instead of a nonce
+    let body = handler::render(&state.tera, &html_data, "")?;
+    let path = out.join(&page.file);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, &body)?;
+    info!("Saved {}", page.file);
+
+    Ok(Some((
+        page.manifest_key,
+        ManifestPage {
+            file: page.file,
+            hash: format!("{:x}", Sha256::digest(body.as_bytes())),
+            timestamp: page.timestamp,
+            script_hashes: security::script_hashes(&body),
+        },
+    )))
+}
+
+/// Returns all logins from the dev index with the timestamps of their reports. The index is walked by login with `search_after`.
+async fn dev_logins(config: &Config) -> Result<Vec<(String, Option<String>)>, Error> {
+    let mut logins: Vec<(String, Option<String>)> = Vec::new();
+    let mut after: Option<String> = None;
+
+    loop {
+        let devs = DevSearchResponse::dev_logins_page(config, after.as_deref()).await?;
+        for dev in devs.devs() {
+            let timestamp = dev.report.as_ref().and_then(|v| v.timestamp.clone());
+            logins.push((dev.login.clone(), timestamp));
+        }
+
+        after = match devs.next_page_after() {
+            Some(v) => Some(v.to_owned()),
+            None => break,
+        };
+    }
+    info!("Devs in the index: {}", logins.len());
+
+    Ok(logins)
+}
+
+/// Returns true if the name can be used as a folder name inside the output folder, e.g. no `..` or `/`.
+fn is_safe_dir_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\'])
+}
+
+/// Returns the manifest from the last run, if any. A missing or invalid manifest is the same as no previous run.
+fn load_manifest(out: &Path) -> Option<Manifest> {
+    let contents = std::fs::read_to_string(out.join(MANIFEST_FILE)).ok()?;
+    match serde_json::from_str::<Manifest>(&contents) {
+        Ok(v) if v.version == MANIFEST_VERSION => Some(v),
+        Ok(v) => {
+            warn!("Ignoring manifest version {}", v.version);
+            None
+        }
+        Err(e) => {
+            warn!("Ignoring invalid manifest: {}", e);
+            None
+        }
+    }
+}
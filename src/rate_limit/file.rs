@@ -0,0 +1,82 @@
+//! Keeps every bucket in a JSON file named after its key in a local folder. It is a stand-in for a shared store,
+//! e.g. for testing the limiter with several server processes on the same machine.
+//!
+//! Reading and writing a bucket is not atomic between processes, so a client may get a few extra requests
+//! if it hits several processes at the same time. Files of idle buckets are not deleted.
+
+use super::{Bucket, RateLimitStore};
+use crate::error::StmError;
+use futures::future::BoxFuture;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Buckets in JSON files, e.g. `/tmp/stm-rate-limit/search_127.0.0.1.json`
+pub(crate) struct FileStore {
+    dir: PathBuf,
+    /// Requests within the same process take tokens one at a time
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    /// Creates the folder if it doesn't exist.
+    pub fn new(dir: &str) -> Result<Self, StmError> {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            return Err(StmError::Config(format!(
+                "Cannot create rate limiter folder {}: {}",
+                dir, e
+            )));
+        }
+
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn take_sync(&self, key: &str, per_minute: u32, now: u64) -> Result<Option<u64>, StmError> {
+        let _lock = self.lock.lock().expect("Poisoned rate limiter lock");
+
+        // IPv6 addresses have `:`, which is not a good idea in file names
+        let file_name = key
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        let path = self.dir.join([&file_name, ".json"].concat());
+
+        // a missing or corrupt file is the same as a full bucket
+        let mut bucket = match std::fs::read_to_string(&path) {
+            Ok(v) => {
+                serde_json::from_str::<Bucket>(&v).unwrap_or_else(|_| Bucket::new(per_minute, now))
+            }
+            Err(_) => Bucket::new(per_minute, now),
+        };
+        let retry_after = bucket.take(per_minute, now);
+
+        if let Err(e) = std::fs::write(&path, serde_json::to_string(&bucket)?) {
+            return Err(StmError::Config(format!(
+                "Cannot save rate limiter bucket {}: {}",
+                path.to_string_lossy(),
+                e
+            )));
+        }
+
+        Ok(retry_after)
+    }
+}
+
+impl RateLimitStore for FileStore {
+    fn take(
+        &self,
+        key: String,
+        per_minute: u32,
+        now: u64,
+    ) -> BoxFuture<'_, Result<Option<u64>, StmError>> {
+        Box::pin(async move { self.take_sync(&key, per_minute, now) })
+    }
+}
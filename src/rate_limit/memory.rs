@@ -0,0 +1,82 @@
+//! Keeps the buckets in the process memory. Every Lambda instance or server process has its own buckets,
+//! so the actual limit is higher if the same client is served by several instances.
+
+use super::{Bucket, RateLimitStore};
+use crate::error::StmError;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Max number of buckets. Idle buckets are dropped to make room for new ones.
+const MAX_BUCKETS: usize = 10_000;
+
+/// In-process buckets shared by all requests
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take(
+        &self,
+        key: String,
+        per_minute: u32,
+        now: u64,
+    ) -> BoxFuture<'_, Result<Option<u64>, StmError>> {
+        Box::pin(async move { Ok(self.take_sync(key, per_minute, now)) })
+    }
+}
+
+impl MemoryStore {
+    /// The lock is never held across an await, so it is a sync mutex.
+    fn take_sync(&self, key: String, per_minute: u32, now: u64) -> Option<u64> {
+        let mut buckets = self.buckets.lock().expect("Poisoned rate limiter lock");
+
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            buckets.retain(|_, bucket| !bucket.is_idle(now));
+            // too many active clients to keep track of, which is more likely a DDoS than a scraper
+            if buckets.len() >= MAX_BUCKETS {
+                warn!("Rate limiter is full, {} is not limited", key);
+                return None;
+            }
+        }
+
+        buckets
+            .entry(key)
+            .or_insert_with(|| Bucket::new(per_minute, now))
+            .take(per_minute, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::REFILL_PERIOD_MS;
+    use super::*;
+
+    #[test]
+    fn full_store() {
+        let store = MemoryStore::default();
+        for i in 0..MAX_BUCKETS {
+            assert_eq!(store.take_sync(i.to_string(), 1, 0), None);
+        }
+
+        // known clients are still limited
+        assert_eq!(store.take_sync("0".to_owned(), 1, 1000), Some(59));
+
+        // new clients are let through without a bucket while all buckets are active
+        assert_eq!(store.take_sync("new".to_owned(), 1, 1000), None);
+        assert_eq!(store.take_sync("new".to_owned(), 1, 1000), None);
+        assert!(!store.buckets.lock().unwrap().contains_key("new"));
+
+        // idle buckets are dropped to make room
+        assert_eq!(store.take_sync("new".to_owned(), 1, REFILL_PERIOD_MS), None);
+        assert_eq!(
+            store.take_sync("new".to_owned(), 1, REFILL_PERIOD_MS),
+            Some(60)
+        );
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(buckets.contains_key("0"));
+    }
+}
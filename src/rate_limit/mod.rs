@@ -0,0 +1,324 @@
+//! A token-bucket rate limiter that stops a single client, e.g. a scraper walking dev profiles, from using up
+//! the few Lambda instances allowed by the reserved concurrency.
+//!
+//! Every client gets a separate bucket per route group with the capacity of one minute worth of requests.
+//! Tokens are added back at the same rate, so a client can make a burst of requests and then continue at the
+//! steady rate. A bucket that was not used for a minute is full again and can be forgotten.
+//!
+//! Clients are identified by the source IP, or by `x-forwarded-for` only if `STM_HTML_RATE_LIMIT_TRUST_XFF` is set
+//! behind a proxy that clients cannot bypass. Crawlers from the allow-list are identified by their user agent and are never throttled.
+//! User agents can be faked, so the allow-list should only have crawlers we want to let through anyway.
+//!
+//! The buckets are kept in a store picked by `STM_HTML_RATE_LIMIT_STORE` value:
+//! * `memory` - in-process, see `memory` module
+//! * `file:///path/to/dir` - a local stand-in for a shared store, see `file` module
+
+mod file;
+mod memory;
+
+use crate::config::RateLimitConfig;
+use crate::error::StmError;
+use crate::html;
+use file::FileStore;
+use futures::future::BoxFuture;
+use memory::MemoryStore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// `STM_HTML_RATE_LIMIT_STORE` value for the in-memory store
+pub(crate) const MEMORY_STORE: &str = "memory";
+/// `STM_HTML_RATE_LIMIT_STORE` values starting with this scheme are stored in files, e.g. `file:///tmp/stm-rate-limit`
+pub(crate) const FILE_STORE_SCHEME: &str = "file://";
+/// An empty bucket gets its capacity back in this many ms
+const REFILL_PERIOD_MS: u64 = 60_000;
+
+/// Routes with separate budgets. Searches are more expensive for ES than profiles, which are fetched by ID.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum RateLimitRoute {
+    /// The home page, keyword search, related keywords and stats
+    Search,
+    /// Dev profiles and repos
+    Profile,
+}
+
+impl RateLimitRoute {
+    /// Returns the route group for the URL path, e.g. `/rimutaka` is `Profile` and `/_related` is `Search`.
+    /// Feeds run the same search as search pages.
+    pub fn for_path(url_path: &str) -> Self {
+        if url_path.trim_end_matches('/').is_empty()
+            || url_path.starts_with("/_")
+            || url_path.trim_end_matches('/') == html::FEED_PATH
+        {
+            RateLimitRoute::Search
+        } else {
+            RateLimitRoute::Profile
+        }
+    }
+
+    /// Returns the name of the route group for the logs and bucket keys.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitRoute::Search => "search",
+            RateLimitRoute::Profile => "profile",
+        }
+    }
+
+    /// Returns the max number of requests per minute for this route group from the config.
+    fn per_minute(&self, config: &RateLimitConfig) -> u32 {
+        match self {
+            RateLimitRoute::Search => config.search_per_minute,
+            RateLimitRoute::Profile => config.profile_per_minute,
+        }
+    }
+}
+
+/// Tokens left for a client with the time they were counted at
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Bucket {
+    tokens: f64,
+    /// Unix time in ms
+    updated: u64,
+}
+
+impl Bucket {
+    /// Returns a full bucket.
+    fn new(per_minute: u32, now: u64) -> Self {
+        Self {
+            tokens: per_minute as f64,
+            updated: now,
+        }
+    }
+
+    /// Adds tokens for the time since the last update and takes one token if there is any.
+    /// Returns `None` if the request is allowed or the number of seconds until the next token otherwise.
+    fn take(&mut self, per_minute: u32, now: u64) -> Option<u64> {
+        let capacity = per_minute as f64;
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        self.tokens = (self.tokens + elapsed * capacity / REFILL_PERIOD_MS as f64).min(capacity);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+
+        let wait_ms = (1.0 - self.tokens) * REFILL_PERIOD_MS as f64 / capacity;
+        Some((wait_ms / 1000.0).ceil() as u64)
+    }
+
+    /// Returns true if the bucket would be full by now, which is the same as not having it at all.
+    fn is_idle(&self, now: u64) -> bool {
+        now.saturating_sub(self.updated) >= REFILL_PERIOD_MS
+    }
+}
+
+/// Keeps the buckets for all clients. Implementations must take the token and save the bucket as a single step
+/// as far as they can, because the same client may send several requests at once.
+pub(crate) trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket for the key, creating a full bucket if there is none.
+    /// Returns `None` if the request is allowed or the number of seconds until the next token otherwise.
+    fn take(
+        &self,
+        key: String,
+        per_minute: u32,
+        now: u64,
+    ) -> BoxFuture<'_, Result<Option<u64>, StmError>>;
+}
+
+/// Returns the store for `STM_HTML_RATE_LIMIT_STORE` value. It should be created once per process and shared by all requests.
+pub(crate) fn store_for_url(url: &str) -> Result<Box<dyn RateLimitStore>, StmError> {
+    if url == MEMORY_STORE {
+        Ok(Box::new(MemoryStore::default()))
+    } else if let Some(dir) = url.strip_prefix(FILE_STORE_SCHEME) {
+        Ok(Box::new(FileStore::new(dir)?))
+    } else {
+        Err(StmError::Config(format!(
+            "Invalid rate limiter store: {}. Expected memory or file:///path/to/dir",
+            url
+        )))
+    }
+}
+
+/// Checks if the client has any requests left for the route. Returns `None` if the request is allowed or
+/// the number of seconds the client should wait before trying again. Store errors are logged and the request is let through.
+pub(crate) async fn check(
+    config: &RateLimitConfig,
+    url_path: &str,
+    headers: &HashMap<String, String>,
+    source_ip: &str,
+) -> Option<u64> {
+    let route = RateLimitRoute::for_path(url_path);
+    let per_minute = route.per_minute(config);
+    if per_minute == 0 {
+        return None;
+    }
+
+    // known crawlers are let through to keep the site indexed
+    let user_agent = headers
+        .get("user-agent")
+        .map(|v| v.to_lowercase())
+        .unwrap_or_default();
+    if config
+        .allowed_bots
+        .iter()
+        .any(|bot| user_agent.contains(bot))
+    {
+        info!("Allowed bot: {}", user_agent);
+        return None;
+    }
+
+    let client_ip = match client_ip(headers, source_ip, config.trust_forwarded_for) {
+        Some(v) => v,
+        None => {
+            warn!("Unknown client IP");
+            return None;
+        }
+    };
+
+    let key = [route.as_str(), ":", &client_ip].concat();
+    match config.store.take(key, per_minute, now_ms()).await {
+        Ok(Some(retry_after)) => {
+            warn!(
+                "Throttled {} for {} route, retry in {}s",
+                client_ip,
+                route.as_str(),
+                retry_after
+            );
+            Some(retry_after)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            error!("Rate limiter failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns the client IP from `x-forwarded-for` header if it can be trusted or the source IP otherwise.
+/// The leftmost XFF entries can be sent by the client, so the rightmost entry that is not the source IP is used,
+/// which is the one added by CloudFront.
+fn client_ip(
+    headers: &HashMap<String, String>,
+    source_ip: &str,
+    trust_forwarded_for: bool,
+) -> Option<String> {
+    if trust_forwarded_for {
+        let forwarded_for = headers
+            .get("x-forwarded-for")
+            .map(|v| v.as_str())
+            .unwrap_or_default();
+        let client_ip = forwarded_for
+            .rsplit(',')
+            .map(|v| v.trim())
+            .find(|v| !v.is_empty() && *v != source_ip);
+        if let Some(v) = client_ip {
+            return Some(v.to_owned());
+        }
+    }
+
+    if source_ip.is_empty() {
+        None
+    } else {
+        Some(source_ip.to_owned())
+    }
+}
+
+/// Returns the current Unix time in ms for comparing across processes.
+fn now_ms() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(v) => v.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refill() {
+        // 1 token per second
+        let mut bucket = Bucket::new(60, 0);
+        for _ in 0..60 {
+            assert_eq!(bucket.take(60, 0), None);
+        }
+        assert_eq!(bucket.take(60, 0), Some(1));
+
+        // half a token is not enough, but the wait is rounded up to a whole second
+        assert_eq!(bucket.take(60, 500), Some(1));
+        assert_eq!(bucket.take(60, 1500), None);
+        assert_eq!(bucket.take(60, 1500), Some(1));
+
+        // an idle bucket is never more than full
+        assert!(!bucket.is_idle(1500 + REFILL_PERIOD_MS - 1));
+        assert!(bucket.is_idle(1500 + REFILL_PERIOD_MS));
+        let now = 10 * REFILL_PERIOD_MS;
+        for _ in 0..60 {
+            assert_eq!(bucket.take(60, now), None);
+        }
+        assert_eq!(bucket.take(60, now), Some(1));
+
+        // 1 token per 30 seconds
+        let mut bucket = Bucket::new(2, 0);
+        assert_eq!(bucket.take(2, 0), None);
+        assert_eq!(bucket.take(2, 0), None);
+        assert_eq!(bucket.take(2, 0), Some(30));
+        assert_eq!(bucket.take(2, 15_000), Some(15));
+        assert_eq!(bucket.take(2, 30_000), None);
+    }
+
+    #[test]
+    fn client_ip_from_xff() {
+        let headers = |xff: &str| {
+            vec![("x-forwarded-for".to_owned(), xff.to_owned())]
+                .into_iter()
+                .collect::<HashMap<String, String>>()
+        };
+
+        // the client can put anything into XFF, so it is ignored unless it is trusted
+        assert_eq!(
+            client_ip(&headers("6.6.6.6"), "9.9.9.9", false).as_deref(),
+            Some("9.9.9.9")
+        );
+
+        // the rightmost entry that is not the source IP was added by the proxy
+        let xff = headers("6.6.6.6, 1.1.1.1, 9.9.9.9");
+        assert_eq!(client_ip(&xff, "9.9.9.9", true).as_deref(), Some("1.1.1.1"));
+        let xff = headers("6.6.6.6, 1.1.1.1");
+        assert_eq!(client_ip(&xff, "9.9.9.9", true).as_deref(), Some("1.1.1.1"));
+        let xff = headers("1.1.1.1, ,");
+        assert_eq!(client_ip(&xff, "9.9.9.9", true).as_deref(), Some("1.1.1.1"));
+
+        // falls back to the source IP
+        let xff = headers(" 9.9.9.9 ");
+        assert_eq!(client_ip(&xff, "9.9.9.9", true).as_deref(), Some("9.9.9.9"));
+        assert_eq!(
+            client_ip(&HashMap::new(), "9.9.9.9", true).as_deref(),
+            Some("9.9.9.9")
+        );
+        assert_eq!(client_ip(&HashMap::new(), "", true), None);
+    }
+
+    #[test]
+    fn route_groups() {
+        assert_eq!(RateLimitRoute::for_path("/"), RateLimitRoute::Search);
+        assert_eq!(
+            RateLimitRoute::for_path("/_related"),
+            RateLimitRoute::Search
+        );
+        assert_eq!(
+            RateLimitRoute::for_path("/feed.atom"),
+            RateLimitRoute::Search
+        );
+        assert_eq!(
+            RateLimitRoute::for_path("/rimutaka/"),
+            RateLimitRoute::Profile
+        );
+        assert_eq!(
+            RateLimitRoute::for_path("/rimutaka/stm"),
+            RateLimitRoute::Profile
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Security headers added to every response. Profile pages render user-controlled GitHub fields, e.g. `company` and `blog`,
+//! so CSP only allows scripts with a nonce generated for every response. All inline event handlers live in
+//! `templates/includes/head.html` under that nonce.
+//!
+//! Styles are allowed inline because templates use `style` attributes, which cannot have a nonce.
+//!
+//! Pre-rendered pages are served as-is by a CDN, so a nonce would be the same for every response and public.
+//! They are rendered without a nonce and CSP allows their inline scripts by the SHA-256 of the script instead.
+
+use crate::config::SecurityHeadersConfig;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// CSP header names for the enforced and report-only modes
+pub(crate) const CSP_HEADER: &str = "Content-Security-Policy";
+pub(crate) const CSP_REPORT_ONLY_HEADER: &str = "Content-Security-Policy-Report-Only";
+
+/// Returns a random base64 value for `nonce` attribute of inline scripts and CSP `script-src`.
+/// It is URL-safe so that Tera doesn't escape `/` in the attribute, which would change the body the ETag is based on.
+pub(crate) fn new_nonce() -> String {
+    base64::encode_config(
+        rand::thread_rng().gen::<[u8; 16]>(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Adds the security headers to the response headers. The nonce is only needed for HTML pages with inline scripts.
+/// Any other response gets a CSP that doesn't allow any scripts.
+pub(crate) fn add_headers(
+    headers: &mut HashMap<String, String>,
+    config: &SecurityHeadersConfig,
+    csp_nonce: Option<&str>,
+) {
+    let script_src = match csp_nonce {
+        Some(v) => ["script-src 'nonce-", v, "'"].concat(),
+        None => "script-src 'none'".to_owned(),
+    };
+    add_headers_with_script_src(headers, config, script_src);
+}
+
+/// Adds the security headers for pre-rendered pages that allow inline scripts with the hashes from `script_hashes`.
+pub(crate) fn add_static_headers(
+    headers: &mut HashMap<String, String>,
+    config: &SecurityHeadersConfig,
+    script_hashes: &[String],
+) {
+    let script_src = if script_hashes.is_empty() {
+        "script-src 'none'".to_owned()
+    } else {
+        ["script-src ", &script_hashes.join(" ")].concat()
+    };
+    add_headers_with_script_src(headers, config, script_src);
+}
+
+/// Returns CSP sources for every inline script in the HTML, e.g. `'sha256-...'`, in the order of the scripts.
+pub(crate) fn script_hashes(html: &str) -> Vec<String> {
+    let mut hashes: Vec<String> = Vec::new();
+
+    let mut rest = html;
+    while let Some(start) = rest.find("<script") {
+        rest = &rest[start..];
+        let (body_start, body_end) = match (rest.find('>'), rest.find("</script>")) {
+            (Some(tag_end), Some(end)) if tag_end < end => (tag_end + 1, end),
+            _ => break,
+        };
+        // the hash is of the exact text between the tags, including the whitespace
+        hashes.push(
+            [
+                "'sha256-",
+                &base64::encode(Sha256::digest(&rest.as_bytes()[body_start..body_end])),
+                "'",
+            ]
+            .concat(),
+        );
+        rest = &rest[body_end..];
+    }
+
+    hashes
+}
+
+/// Adds all the security headers with CSP `script-src` directive for the type of the response.
+fn add_headers_with_script_src(
+    headers: &mut HashMap<String, String>,
+    config: &SecurityHeadersConfig,
+    script_src: String,
+) {
+    let csp_header = if config.csp_report_only {
+        CSP_REPORT_ONLY_HEADER
+    } else {
+        CSP_HEADER
+    };
+    headers.insert(csp_header.to_owned(), csp(config, script_src));
+
+    if config.hsts_max_age > 0 {
+        headers.insert(
+            "Strict-Transport-Security".to_owned(),
+            [
+                "max-age=",
+                &config.hsts_max_age.to_string(),
+                "; includeSubDomains",
+            ]
+            .concat(),
+        );
+    }
+    headers.insert("X-Content-Type-Options".to_owned(), "nosniff".to_owned());
+    headers.insert(
+        "Referrer-Policy".to_owned(),
+        "strict-origin-when-cross-origin".to_owned(),
+    );
+    headers.insert(
+        "Permissions-Policy".to_owned(),
+        "camera=(), microphone=(), geolocation=(), payment=(), interest-cohort=()".to_owned(),
+    );
+    // old browsers ignore CSP frame-ancestors
+    headers.insert("X-Frame-Options".to_owned(), "DENY".to_owned());
+}
+
+/// Builds the CSP value for the templates. External sources must be added here if the templates start using them.
+fn csp(config: &SecurityHeadersConfig, script_src: String) -> String {
+    let mut directives = vec![
+        "default-src 'self'".to_owned(),
+        script_src,
+        "style-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net".to_owned(),
+        "img-src 'self' data: https://assets.stackmuncher.com".to_owned(),
+        "object-src 'none'".to_owned(),
+        "base-uri 'none'".to_owned(),
+        "form-action 'self'".to_owned(),
+        "frame-ancestors 'none'".to_owned(),
+    ];
+    if let Some(report_uri) = &config.csp_report_uri {
+        directives.push(["report-uri ", report_uri].concat());
+    }
+
+    directives.join("; ")
+}
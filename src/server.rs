@@ -0,0 +1,159 @@
+use crate::handler::{
+    self, ApiGatewayRequest, ApiGatewayResponse, HttpContext, RequestContext, SharedState,
+};
+use crate::Error;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// The CLI command to run the app as a standalone HTTP server, e.g. `stm-html serve --listen 0.0.0.0:8080`
+const SERVE_CMD: &str = "serve";
+/// The CLI param with the address:port to listen on
+const LISTEN_PARAM: &str = "--listen";
+/// Used if `--listen` param was omitted
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8080";
+
+/// Returns the address to listen on if the app was launched with `serve` command or `None` otherwise.
+/// Panics if the address is not a valid `IP:port` value.
+pub(crate) fn listen_addr_from_args() -> Option<SocketAddr> {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+
+    // the command must come first, e.g. `stm-html serve --listen 0.0.0.0:8080`
+    if args.first().map(|v| v.as_str()) != Some(SERVE_CMD) {
+        return None;
+    }
+
+    // the address may be given as `--listen addr` or `--listen=addr`
+    let mut listen = DEFAULT_LISTEN_ADDR.to_owned();
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == LISTEN_PARAM {
+            listen = args
+                .next()
+                .unwrap_or_else(|| panic!("Missing value for {}", LISTEN_PARAM));
+        } else if let Some(v) = arg.strip_prefix(&[LISTEN_PARAM, "="].concat()) {
+            listen = v.to_owned();
+        } else {
+            panic!("Unknown param: {}", arg);
+        }
+    }
+
+    Some(
+        listen
+            .parse::<SocketAddr>()
+            .unwrap_or_else(|_| panic!("Invalid {} value: {}", LISTEN_PARAM, listen)),
+    )
+}
+
+/// Runs a standalone HTTP server that feeds all requests through the same handler as Lambda.
+/// It never returns unless the server fails.
+pub(crate) async fn run(addr: SocketAddr, state: Arc<SharedState>) -> Result<(), Error> {
+    // every connection and request gets a reference to the same state
+    // the remote address is the source IP for the rate limiter, same as in APIGW requests
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                serve_request(req, remote_addr, state.clone())
+            }))
+        }
+    });
+
+    info!("Listening on http://{}", addr);
+    Server::bind(&addr).serve(make_svc).await?;
+
+    Ok(())
+}
+
+/// Converts an HTTP request into APIGW format, passes it to the handler and converts
+/// the handler response back into HTTP. Handler errors are returned as 500.
+async fn serve_request(
+    req: Request<Body>,
+    remote_addr: SocketAddr,
+    state: Arc<SharedState>,
+) -> Result<Response<Body>, Infallible> {
+    let api_request = to_api_gateway_request(&req, remote_addr);
+    info!("New request: {}", req.uri());
+
+    let resp = match handler::handle_request(api_request, &state).await {
+        Ok(v) => from_api_gateway_response(v),
+        Err(e) => {
+            error!("Request failed with {}", e);
+            let mut resp = Response::new(Body::from("Internal Server Error"));
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            resp
+        }
+    };
+
+    Ok(resp)
+}
+
+/// Builds a request in the same format as APIGW would send to Lambda.
+/// Header names are lower-case and repeated headers are joined with `,`, same as in APIGW v2.
+fn to_api_gateway_request(req: &Request<Body>, remote_addr: SocketAddr) -> ApiGatewayRequest {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in req.headers() {
+        let value = match value.to_str() {
+            Ok(v) => v.to_owned(),
+            Err(_) => continue,
+        };
+        headers
+            .entry(name.as_str().to_lowercase())
+            .and_modify(|v| {
+                v.push(',');
+                v.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    ApiGatewayRequest {
+        raw_path: req.uri().path().to_owned(),
+        raw_query_string: req.uri().query().unwrap_or_default().to_owned(),
+        headers,
+        request_context: RequestContext {
+            http: HttpContext {
+                method: req.method().as_str().to_owned(),
+                source_ip: remote_addr.ip().to_string(),
+            },
+        },
+    }
+}
+
+/// Converts the handler response into an HTTP response. Base64-encoded bodies, e.g. compressed pages,
+/// are decoded the same way as APIGW does it.
+fn from_api_gateway_response(api_response: ApiGatewayResponse) -> Response<Body> {
+    let mut resp = Response::builder().status(api_response.status_code as u16);
+    for (name, value) in api_response.headers {
+        resp = resp.header(name.as_str(), value.as_str());
+    }
+
+    let body = if api_response.is_base64_encoded {
+        match base64::decode(&api_response.body) {
+            Ok(v) => Body::from(v),
+            Err(e) => {
+                error!("Invalid base64 body: {}", e);
+                let mut resp = Response::new(Body::from("Internal Server Error"));
+                *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                return resp;
+            }
+        }
+    } else {
+        Body::from(api_response.body)
+    };
+
+    match resp.body(body) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid response: {}", e);
+            let mut resp = Response::new(Body::from("Internal Server Error"));
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            resp
+        }
+    }
+}